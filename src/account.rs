@@ -0,0 +1,310 @@
+/*
+This file contains the account page and the routes for the two-factor authentication.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::path::Path;
+use rocket::form::Form;
+use rocket::time::Duration;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::http::{Cookie, CookieJar};
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{ACCESS_DENIED, LOGIN_FAILED, LOGIN_LOCKED, Auth, audit, html, totp, tokens, throttle, check_csrf, start_session, unix_time, user_settings};
+use crate::users::{get_user, update_user};
+
+// Time to enter the second factor after a successful password check (in seconds)
+const PENDING_LOGIN_TIMEOUT: u64 = 300;
+
+#[derive(FromForm)]
+pub struct TotpCode {
+    code: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct Settings {
+    language: String,
+    theme: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+// Remember a user whose password was verified, but who still needs to pass the second factor
+pub fn start_second_factor(cookies: &CookieJar<'_>, username: &str) {
+    let mut cookie = Cookie::new("pending_login", format!("{};{}", unix_time(), username));
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_max_age(Duration::seconds(PENDING_LOGIN_TIMEOUT as i64));
+    cookies.add_private(cookie);
+}
+
+fn pending_user(cookies: &CookieJar<'_>) -> Option<String> {
+    let cookie = cookies.get_private("pending_login")?;
+    let (timestamp, username) = cookie.value().split_once(';')?;
+    if unix_time().saturating_sub(timestamp.parse().ok()?) > PENDING_LOGIN_TIMEOUT { return None }
+    Some(username.to_owned())
+}
+
+// Check an authentication code or consume one of the recovery codes
+fn verify_second_factor(username: &str, code: &str) -> bool {
+    let Some(user) = get_user(username) else { return false };
+    if !user.totp_secret.is_empty() && totp::verify_code(username, &user.totp_secret, code) { return true }
+    if !user.recovery_codes.contains(&totp::hash_recovery_code(code)) { return false }
+    // The code is removed while the users file is locked, so it cannot be used by two parallel logins
+    let mut consumed = false;
+    update_user(username, |user| consumed = totp::consume_recovery_code(&mut user.recovery_codes, code));
+    consumed
+}
+
+#[post("/totp", data = "<data>")]
+pub fn totp_login(cookies: &CookieJar<'_>, ip: Option<IpAddr>, data: Option<Form<TotpCode>>) -> Either<Redirect, RawHtml<String>> {
+    let (Some(username), Some(data)) = (pending_user(cookies), data) else {
+        return Either::Right(RawHtml(LOGIN_FAILED.to_owned()))
+    };
+    if throttle::is_locked(ip, &username) {
+        audit::record(Some(&username), ip, "login_totp", Path::new(""), "locked");
+        return Either::Right(RawHtml(LOGIN_LOCKED.to_owned()))
+    }
+    if verify_second_factor(&username, &data.code) {
+        cookies.remove_private("pending_login");
+        throttle::register_success(&username);
+        audit::record(Some(&username), ip, "login_totp", Path::new(""), "success");
+        Either::Left(start_session(cookies, &username))
+    } else {
+        throttle::register_failure(ip, &username);
+        audit::record(Some(&username), ip, "login_totp", Path::new(""), "failure");
+        Either::Right(RawHtml(LOGIN_FAILED.to_owned()))
+    }
+}
+
+// An active second factor can only be set up again after its current code has been entered
+fn renew_confirmed(cookies: &CookieJar<'_>) -> bool {
+    cookies.get_private("totp_renew")
+        .and_then(|cookie| cookie.value().parse::<u64>().ok())
+        .is_some_and(|timestamp| unix_time().saturating_sub(timestamp) <= PENDING_LOGIN_TIMEOUT)
+}
+
+// The setup is available to logged-in users and to pending logins that are required to set up the second factor
+fn setup_user(auth: &Auth<'_>) -> Option<(String, bool)> {
+    if let Some(user) = auth.account() {
+        return if user.totp_secret.is_empty() || renew_confirmed(auth.cookies) { Some((user.name, false)) } else { None }
+    }
+    let username = pending_user(auth.cookies)?;
+    let user = get_user(&username)?;
+    if user.totp_required && user.totp_secret.is_empty() { Some((username, true)) } else { None }
+}
+
+#[get("/totp/setup")]
+pub fn totp_setup(auth: Auth<'_>) -> RawHtml<String> {
+    let Some((username, _)) = setup_user(&auth) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let secret = totp::generate_secret();
+    let uri = totp::otpauth_uri(&username, &secret);
+    let mut cookie = Cookie::new("totp_setup", secret);
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_max_age(Duration::seconds(PENDING_LOGIN_TIMEOUT as i64));
+    auth.cookies.add_private(cookie);
+
+    // Pending logins are not logged in yet, so the settings are taken from the user that sets up the second factor
+    let settings = user_settings(&username);
+    let mut texts = ("Set up two-factor authentication",
+                     "Scan the QR code with an authenticator app or enter the following URI manually, then confirm with the current code:",
+                     "Confirm", "authentication code");
+    if settings.language == "de" {
+        texts = ("Zwei-Faktor-Authentifizierung einrichten",
+                 "Den QR-Code mit einer Authenticator-App scannen oder die folgende URI manuell eingeben und mit dem aktuellen Code bestätigen:",
+                 "Bestätigen", "Authentifizierungscode");
+    }
+    let content = format!(
+        "{}<div style=\"display:inline-block; background-color:#FFFFFF; padding:8px\">{}</div>{}<br>{}",
+        html::paragraph(texts.1), totp::qr_code_svg(&uri), html::paragraph(&html::escape(&uri)),
+        html::form("/totp/setup", "post", &format!(
+            "{}{}<br>{}", html::hidden("csrf_token", &auth.csrf_token()), html::input("code", "text", texts.3), html::submit(texts.2)
+        ))
+    );
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[post("/totp/setup", data = "<data>")]
+pub fn totp_confirm(auth: Auth<'_>, data: Option<Form<TotpCode>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "totp_setup", Path::new(""))?;
+    let cookies = auth.cookies;
+    let Some((username, pending)) = setup_user(&auth) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    let settings = user_settings(&username);
+    let mut texts = ("Two-factor authentication", "The code is invalid, please try again.", "Retry",
+                     "The two-factor authentication is active. Please store the following recovery codes in a safe place, \
+                     each of them can be used once instead of an authentication code:", "Continue");
+    if settings.language == "de" {
+        texts = ("Zwei-Faktor-Authentifizierung", "Der Code ist ungültig, bitte erneut versuchen.", "Wiederholen",
+                 "Die Zwei-Faktor-Authentifizierung ist aktiv. Die folgenden Wiederherstellungscodes sollten sicher aufbewahrt \
+                 werden, jeder von ihnen kann einmalig anstelle eines Authentifizierungscodes verwendet werden:", "Weiter");
+    }
+    let secret = cookies.get_private("totp_setup").map(|cookie| cookie.value().to_owned());
+    let (Some(secret), Some(data)) = (secret, data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    if !totp::verify_code(&username, &secret, &data.code) {
+        return Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.1), html::link("/totp/setup", texts.2)))))
+    }
+    let (codes, hashes) = totp::generate_recovery_codes();
+    update_user(&username, |user| {
+        user.totp_secret = secret;
+        user.recovery_codes = hashes;
+    });
+    cookies.remove_private("totp_setup");
+    cookies.remove_private("totp_renew");
+    // Complete a pending login, since both factors have been verified now
+    if pending {
+        cookies.remove_private("pending_login");
+        throttle::register_success(&username);
+        let _ = start_session(cookies, &username);
+    }
+    let content = format!(
+        "{}<pre style=\"font-size:16px\">{}</pre>{}",
+        html::paragraph(texts.3), codes.join("\n"), html::link(&format!("/files/{}", username), texts.4)
+    );
+    Ok(RawHtml(html::page(&settings, texts.0, &content)))
+}
+
+#[post("/totp/renew", data = "<data>")]
+pub fn totp_renew(auth: Auth<'_>, data: Option<Form<TotpCode>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "totp_renew", Path::new(""))?;
+    let (Some(user), Some(data)) = (auth.account(), data) else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    if user.totp_secret.is_empty() || !verify_second_factor(&user.name, &data.code) {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    }
+    let mut cookie = Cookie::new("totp_renew", unix_time().to_string());
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_max_age(Duration::seconds(PENDING_LOGIN_TIMEOUT as i64));
+    auth.cookies.add_private(cookie);
+    Ok(Either::Left(Redirect::to(uri!(totp_setup))))
+}
+
+#[post("/totp/disable", data = "<data>")]
+pub fn totp_disable(auth: Auth<'_>, data: Option<Form<TotpCode>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "totp_disable", Path::new(""))?;
+    let (Some(username), Some(data)) = (auth.user(), data) else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    match get_user(&username) {
+        Some(user) if !user.totp_required && verify_second_factor(&username, &data.code) => {
+            update_user(&username, |user| {
+                user.totp_secret = String::new();
+                user.recovery_codes = Vec::new();
+            });
+            Ok(Either::Left(Redirect::to(uri!(account))))
+        }
+        _ => Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    }
+}
+
+#[post("/account/settings", data = "<data>")]
+pub fn account_settings(auth: Auth<'_>, data: Option<Form<Settings>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "account_settings", Path::new(""))?;
+    let (Some(username), Some(data)) = (auth.user(), data) else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    if !["", "en", "de"].contains(&data.language.as_str()) || !["", "dark", "light"].contains(&data.theme.as_str()) {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    }
+    update_user(&username, |user| {
+        user.language = data.language.to_owned();
+        user.theme = data.theme.to_owned();
+    });
+    Ok(Either::Left(Redirect::to(uri!(account))))
+}
+
+#[get("/account")]
+pub fn account(auth: Auth<'_>) -> RawHtml<String> {
+    let Some(user) = auth.account() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = user_settings(&user.name);
+    let mut texts = ("Account of", "Two-factor authentication", "is active", "is not set up", "(required)",
+                     "Set up", "Set up again", "Disable", "authentication code", "remaining recovery codes", "Back");
+    let csrf_token = auth.csrf_token();
+    let mut settings_texts = ("Settings", "Server default", "English", "German", "Dark", "Light", "Save", "Role", "Quota", "unlimited");
+    if settings.language == "de" {
+        texts = ("Konto von", "Zwei-Faktor-Authentifizierung", "ist aktiv", "ist nicht eingerichtet", "(erforderlich)",
+                 "Einrichten", "Neu einrichten", "Deaktivieren", "Authentifizierungscode", "verbleibende Wiederherstellungscodes", "Zurück");
+        settings_texts = ("Einstellungen", "Servervorgabe", "Englisch", "Deutsch", "Dunkel", "Hell", "Speichern", "Rolle", "Kontingent", "unbegrenzt");
+    }
+    let quota = if user.quota == 0 { settings_texts.9.to_owned() } else { format!("{} MiB", user.quota) };
+    let mut content = html::paragraph(&format!("{}: {} &ensp; | &ensp; {}: {}", settings_texts.7, user.role.name(), settings_texts.8, quota));
+    if user.totp_secret.is_empty() {
+        content.push_str(&html::paragraph(&format!("{} {} {}", texts.1, texts.3, if user.totp_required { texts.4 } else { "" })));
+        content.push_str(&html::link("/totp/setup", texts.5));
+    } else {
+        content.push_str(&html::paragraph(&format!(
+            "{} {} {} ({} {})", texts.1, texts.2, if user.totp_required { texts.4 } else { "" }, user.recovery_codes.len(), texts.9
+        )));
+        // Setting up again and disabling both need the current code, so an open session alone cannot replace the second factor
+        let code = format!("{}{}<br>", html::hidden("csrf_token", &csrf_token), html::input("code", "text", texts.8));
+        content.push_str(&html::form("/totp/renew", "post", &format!("{}{}", code, html::submit(texts.6))));
+        if !user.totp_required {
+            content.push_str(&html::form("/totp/disable", "post", &format!("{}{}", code, html::submit(texts.7))));
+        }
+    }
+    content.push_str(&tokens::token_section(&user.name, &settings.language, &csrf_token));
+    let fields = format!(
+        "{}{}<br>{}<br>{}",
+        html::hidden("csrf_token", &csrf_token), html::select("language", &[("", settings_texts.1), ("en", settings_texts.2), ("de", settings_texts.3)], &user.language),
+        html::select("theme", &[("", settings_texts.1), ("dark", settings_texts.4), ("light", settings_texts.5)], &user.theme),
+        html::submit(settings_texts.6)
+    );
+    content.push_str(&format!("<br>{}{}", html::paragraph(&format!("<b>{}</b>", settings_texts.0)), html::form("/account/settings", "post", &fields)));
+    content.push_str(&format!("<br>{}", html::link(&format!("/files/{}", user.name), texts.10)));
+    RawHtml(html::page(&settings, &format!("{} {}", texts.0, user.name), &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use crate::users::Role;
+    use crate::test_support::{CSRF_TOKEN, setup, login, test_user};
+
+    #[test]
+    fn active_second_factor_needs_current_code() {
+        test_user("totp_user", Role::User);
+        let (codes, hashes) = totp::generate_recovery_codes();
+        update_user("totp_user", |user| {
+            user.totp_secret = totp::generate_secret();
+            user.recovery_codes = hashes;
+        });
+        setup();
+        let client = Client::tracked(rocket::build().mount("/", routes![totp_setup, totp_renew, totp_disable])).expect("Cannot start test client");
+        let post = |uri: &'static str, code: &str| login(client.post(uri), "totp_user").header(ContentType::Form)
+            .body(format!("code={}&csrf_token={}", code, CSRF_TOKEN)).dispatch();
+
+        // An open session alone can neither replace nor remove the secret
+        assert_eq!(login(client.get("/totp/setup"), "totp_user").dispatch().into_string(), Some(ACCESS_DENIED.to_owned()));
+        assert_eq!(post("/totp/renew", "000000").into_string(), Some(ACCESS_DENIED.to_owned()));
+        assert_eq!(post("/totp/disable", "000000").into_string(), Some(ACCESS_DENIED.to_owned()));
+        assert!(!get_user("totp_user").expect("User is missing").totp_secret.is_empty());
+
+        assert_eq!(post("/totp/renew", &codes[0]).status(), Status::SeeOther);
+        let page = login(client.get("/totp/setup"), "totp_user").dispatch().into_string().unwrap_or_default();
+        assert!(page.contains("otpauth://totp/"));
+        assert_eq!(post("/totp/disable", &codes[1]).status(), Status::SeeOther);
+        assert!(get_user("totp_user").expect("User is missing").totp_secret.is_empty());
+    }
+}
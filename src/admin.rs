@@ -0,0 +1,256 @@
+/*
+This file contains the administration area for the user and storage management.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+use rocket::form::Form;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{STORAGE, ACCESS_DENIED, Auth, Settings, audit, html, session, check_csrf, directory_size};
+use crate::users::{Role, User, get_users, get_user, update_user, add_user, remove_user, hash_password, is_external_hash};
+
+// Number of recent administrative actions shown in the administration area
+const LOG_ENTRIES: usize = 20;
+
+#[derive(FromForm)]
+pub struct NewUser {
+    name: String,
+    password: String,
+    role: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct UserPassword {
+    name: String,
+    password: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct UserState {
+    name: String,
+    enabled: bool,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct UserDeletion {
+    name: String,
+    confirm_name: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+fn admin_user(auth: &Auth<'_>) -> Option<User> {
+    auth.account().filter(|user| user.role == Role::Admin)
+}
+
+// Every action in the administration area is recorded in the audit log, the affected user is the path of the entry
+pub fn log_action(auth: &Auth<'_>, action: &str, target: &str) {
+    audit::record(auth.actor().as_deref(), auth.ip, &format!("admin_{}", action), Path::new(target), "success");
+}
+
+fn result_page(settings: &Settings, message: &str, success: bool) -> RawHtml<String> {
+    let mut texts = ("Administration", "Back");
+    if settings.language == "de" {
+        texts = ("Administration", "Zurück");
+    }
+    let message = if success { html::paragraph(message) } else { html::error(message) };
+    RawHtml(html::page(settings, texts.0, &format!("{}{}", message, html::link(&uri!(admin).to_string(), texts.1))))
+}
+
+#[get("/admin")]
+pub fn admin(auth: Auth<'_>) -> RawHtml<String> {
+    let Some(admin) = admin_user(&auth) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = auth.settings();
+    let mut texts = ("Administration", "Users", "enabled", "disabled", "used", "unlimited", "created", "Browse files",
+                     "Disable", "Enable", "new password", "Reset password", "repeat the name to confirm", "Delete",
+                     "Create user", "name", "password", "Recent actions", "Back", "Audit log");
+    if settings.language == "de" {
+        texts = ("Administration", "Nutzer", "aktiv", "deaktiviert", "belegt", "unbegrenzt", "erstellt", "Dateien anzeigen",
+                 "Deaktivieren", "Aktivieren", "neues Passwort", "Passwort zurücksetzen", "Name zur Bestätigung wiederholen", "Löschen",
+                 "Nutzer erstellen", "Name", "Passwort", "Letzte Aktionen", "Zurück", "Audit-Log");
+    }
+    let csrf_token = html::hidden("csrf_token", &auth.csrf_token());
+    let mut content = html::paragraph(&format!("<b>{}</b>", texts.1));
+    for user in get_users() {
+        let used = directory_size(&STORAGE.join(&user.name)) as f64 / 1024.0 / 1024.0;
+        let quota = if user.quota == 0 { texts.5.to_owned() } else { format!("{} MiB", user.quota) };
+        content.push_str(&html::paragraph(&format!(
+            "<b>{}</b> &ensp; {} &ensp; {} &ensp; {:.1} MiB {} / {} &ensp; {} {}",
+            html::escape(&user.name), user.role.name(), if user.enabled { texts.2 } else { texts.3 },
+            used, texts.4, quota, texts.6, html::date(user.created)
+        )));
+        content.push_str(&html::link(&format!("/files/{}", user.name), texts.7));
+        // Administrators cannot lock themselves out
+        if user.name != admin.name {
+            let state = format!("{}{}{}{}", html::hidden("name", &user.name), html::hidden("enabled", if user.enabled { "false" } else { "true" }),
+                                csrf_token, html::submit(if user.enabled { texts.8 } else { texts.9 }));
+            content.push_str(&html::form("/admin/users/state", "post", &state));
+        }
+        // The passwords of external users are managed by their login provider
+        if !is_external_hash(&user.hash) {
+            let password = format!("{}{}{}<br>{}", html::hidden("name", &user.name), csrf_token, html::input("password", "password", texts.10), html::submit(texts.11));
+            content.push_str(&html::form("/admin/users/password", "post", &password));
+        }
+        if user.name != admin.name {
+            let deletion = format!("{}{}{}<br>{}", html::hidden("name", &user.name), csrf_token, html::input("confirm_name", "text", texts.12), html::submit(texts.13));
+            content.push_str(&html::form("/admin/users/delete", "post", &deletion));
+        }
+        content.push_str("<br><br>");
+    }
+    let roles = [Role::User, Role::ReadOnly, Role::Admin].map(|role| (role.name(), role.name()));
+    let fields = format!(
+        "{}{}<br>{}<br>{}<br>{}",
+        csrf_token, html::input("name", "text", texts.15), html::input("password", "password", texts.16),
+        html::select("role", &roles, Role::User.name()), html::submit(texts.14)
+    );
+    content.push_str(&format!("{}{}", html::paragraph(&format!("<b>{}</b>", texts.14)), html::form("/admin/users", "post", &fields)));
+    // Show the latest administrative actions of the audit log
+    let entries = audit::admin_actions(LOG_ENTRIES);
+    content.push_str(&format!(
+        "<br>{}<pre style=\"font-size:12px; text-align:left; white-space:pre-wrap\">{}</pre>",
+        html::paragraph(&format!("<b>{}</b>", texts.17)), html::escape(&entries.join("\n"))
+    ));
+    content.push_str(&html::link(&uri!(audit_log(_, _, _, _)).to_string(), texts.19));
+    content.push_str(&html::link(&format!("/files/{}", admin.name), texts.18));
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[get("/admin/audit?<user>&<action>&<path>&<result>")]
+pub fn audit_log(auth: Auth<'_>, user: Option<&str>, action: Option<&str>, path: Option<&str>, result: Option<&str>) -> RawHtml<String> {
+    if admin_user(&auth).is_none() { return RawHtml(auth.page(&ACCESS_DENIED)) }
+    let settings = auth.settings();
+    let mut texts = ("Administration", "Back");
+    if settings.language == "de" {
+        texts = ("Administration", "Zurück");
+    }
+    let [user, action, path, result] = [user, action, path, result].map(|filter| filter.unwrap_or_default().trim());
+    let content = audit::viewer(&settings.language, user, action, path, result);
+    RawHtml(html::page(&settings, texts.0, &format!("{}{}", content, html::link(&uri!(admin).to_string(), texts.1))))
+}
+
+#[post("/admin/users", data = "<data>")]
+pub fn create_user(auth: Auth<'_>, data: Option<Form<NewUser>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "create_user", Path::new(""))?;
+    let (Some(_admin), Some(data)) = (admin_user(&auth), data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    let settings = auth.settings();
+    let mut texts = ("The user has been created.", "The user name is invalid, reserved or already taken, or the password is empty.");
+    if settings.language == "de" {
+        texts = ("Der Nutzer wurde erstellt.", "Der Nutzername ist ungültig, reserviert oder bereits vergeben oder das Passwort ist leer.");
+    }
+    let Some(role) = Role::from_name(&data.role) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    if data.password.is_empty() { return Ok(result_page(&settings, texts.1, false)) }
+    match add_user(&data.name, &data.password, role) {
+        Ok(()) => {
+            log_action(&auth, &format!("create_{}", role.name().replace('-', "_")), &data.name);
+            Ok(result_page(&settings, texts.0, true))
+        }
+        Err(_) => Ok(result_page(&settings, texts.1, false))
+    }
+}
+
+#[post("/admin/users/password", data = "<data>")]
+pub fn reset_password(auth: Auth<'_>, data: Option<Form<UserPassword>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "reset_password", Path::new(""))?;
+    let (Some(_admin), Some(data)) = (admin_user(&auth), data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    let settings = auth.settings();
+    let mut texts = ("The password has been changed and all sessions of the user have been ended.", "The password must not be empty.",
+                     "The password of this user is managed by the external login provider.");
+    if settings.language == "de" {
+        texts = ("Das Passwort wurde geändert und alle Sitzungen des Nutzers wurden beendet.", "Das Passwort darf nicht leer sein.",
+                 "Das Passwort dieses Nutzers wird vom externen Anmeldedienst verwaltet.");
+    }
+    let Some(user) = get_user(&data.name) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    // A password hash would replace the marker and allow a local login that bypasses the external provider
+    if is_external_hash(&user.hash) { return Ok(result_page(&settings, texts.2, false)) }
+    if data.password.is_empty() { return Ok(result_page(&settings, texts.1, false)) }
+    let hash_value = hash_password(&data.password);
+    update_user(&data.name, |user| user.hash = hash_value);
+    session::remove_user_sessions(&data.name);
+    log_action(&auth, "reset_password", &data.name);
+    Ok(result_page(&settings, texts.0, true))
+}
+
+#[post("/admin/users/state", data = "<data>")]
+pub fn set_user_state(auth: Auth<'_>, data: Option<Form<UserState>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "set_user_state", Path::new(""))?;
+    let (Some(admin), Some(data)) = (admin_user(&auth), data) else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    if data.name == admin.name || get_user(&data.name).is_none() { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) }
+    update_user(&data.name, |user| user.enabled = data.enabled);
+    if !data.enabled { session::remove_user_sessions(&data.name); }
+    log_action(&auth, if data.enabled { "enable" } else { "disable" }, &data.name);
+    Ok(Either::Left(Redirect::to(uri!(admin))))
+}
+
+#[post("/admin/users/delete", data = "<data>")]
+pub fn delete_user(auth: Auth<'_>, data: Option<Form<UserDeletion>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "delete_user", Path::new(""))?;
+    let (Some(admin), Some(data)) = (admin_user(&auth), data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    let settings = auth.settings();
+    let mut texts = ("The user and all of its files have been deleted.", "The confirmation does not match the user name.");
+    if settings.language == "de" {
+        texts = ("Der Nutzer und alle seine Dateien wurden gelöscht.", "Die Bestätigung stimmt nicht mit dem Nutzernamen überein.");
+    }
+    if data.name == admin.name || get_user(&data.name).is_none() { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) }
+    if data.confirm_name != data.name { return Ok(result_page(&settings, texts.1, false)) }
+    remove_user(&data.name);
+    log_action(&auth, "delete", &data.name);
+    Ok(result_page(&settings, texts.0, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Status};
+    use crate::users::{provision_user, verify_password};
+    use crate::test_support::{CSRF_TOKEN, client, login, test_user};
+
+    #[test]
+    fn external_passwords_are_not_reset() {
+        test_user("reset_admin", Role::Admin);
+        test_user("reset_local", Role::User);
+        provision_user("reset_external", "!ldap");
+        let client = client(routes![reset_password]);
+        let reset = |name: &str| login(client.post("/admin/users/password"), "reset_admin").header(ContentType::Form)
+            .body(format!("name={}&password=changed&csrf_token={}", name, CSRF_TOKEN)).dispatch().into_string().unwrap_or_default();
+
+        assert!(reset("reset_external").contains("The password of this user is managed by the external login provider."));
+        assert_eq!(get_user("reset_external").expect("User is missing").hash, "!ldap");
+        assert!(reset("reset_local").contains("The password has been changed"));
+        assert!(verify_password(&get_user("reset_local").expect("User is missing").hash, "reset_local", "changed"));
+    }
+
+    #[test]
+    fn actions_are_recorded_in_the_audit_log() {
+        test_user("audit_admin", Role::Admin);
+        test_user("audit_target", Role::User);
+        let client = client(routes![set_user_state]);
+        let response = login(client.post("/admin/users/state"), "audit_admin").header(ContentType::Form)
+            .body(format!("name=audit_target&enabled=false&csrf_token={}", CSRF_TOKEN)).dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert!(!get_user("audit_target").expect("User is missing").enabled);
+        assert!(audit::admin_actions(usize::MAX).iter().any(|line| line.contains("admin=\"audit_admin\" action=admin_disable target=\"audit_target\"")));
+    }
+}
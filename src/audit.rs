@@ -0,0 +1,177 @@
+/*
+This file contains the audit log that records every login and file operation as JSON lines.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::io::Write;
+use std::fs::{OpenOptions, metadata, read_to_string, remove_file, rename};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use rocket::Either;
+use rocket::response::content::RawHtml;
+use crate::{CONFIG, ACCESS_DENIED, READ_ONLY, NO_FILE, NO_DIRECTORY, IS_FILE, IS_DIRECTORY, UPLOAD_ERROR, UNPACK_ERROR, MOVE_ERROR,
+            QUOTA_EXCEEDED, SHARE_INVALID, LOGIN_FAILED, LOGIN_LOCKED, Auth, html};
+
+const AUDIT_LOG: &str = "audit.log";
+
+// Number of entries shown in the administration area
+const VIEWER_ENTRIES: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    time: String,
+    user: Option<String>,
+    ip: Option<String>,
+    action: String,
+    path: String,
+    result: String,
+}
+
+lazy_static! {
+    // Serialize all write access to the audit log and its rotation
+    static ref AUDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn rotated_name(index: u64) -> String {
+    format!("{}.{}", AUDIT_LOG, index)
+}
+
+// Rename audit.log to audit.log.1 (and so on) once it reaches the maximum size, the oldest file is deleted
+fn rotate() {
+    let size = metadata(AUDIT_LOG).map(|metadata| metadata.len()).unwrap_or(0);
+    if size < CONFIG.audit_log_max_size.saturating_mul(1024 * 1024) { return }
+    if CONFIG.audit_log_files == 0 {
+        remove_file(AUDIT_LOG).expect("Cannot delete audit log");
+        return
+    }
+    let _ = remove_file(rotated_name(CONFIG.audit_log_files));
+    for index in (1..CONFIG.audit_log_files).rev() {
+        let _ = rename(rotated_name(index), rotated_name(index + 1));
+    }
+    rename(AUDIT_LOG, rotated_name(1)).expect("Cannot rotate audit log");
+}
+
+pub fn record(user: Option<&str>, ip: Option<IpAddr>, action: &str, path: &Path, result: &str) {
+    let entry = Entry {
+        time: OffsetDateTime::now_utc().format(&Rfc3339).expect("Cannot format timestamp"),
+        user: user.map(str::to_owned),
+        ip: ip.map(|ip| ip.to_string()),
+        action: action.to_owned(),
+        path: path.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned(),
+        result: result.to_owned(),
+    };
+    let line = serde_json::to_string(&entry).expect("Cannot serialize audit log entry");
+    let _guard = AUDIT_LOCK.lock().expect("Audit log lock is poisoned");
+    rotate();
+    let mut file = OpenOptions::new().create(true).append(true).open(AUDIT_LOG).expect("Cannot open audit log");
+    writeln!(file, "{}", line).expect("Cannot write audit log");
+}
+
+// Error pages are recorded with their name, any other page is an unexpected error
+pub fn page_name(page: &str) -> &'static str {
+    let pages: [(&str, &str); 13] = [
+        (&ACCESS_DENIED, "access_denied"), (&READ_ONLY, "read_only"), (&NO_FILE, "no_file"), (&NO_DIRECTORY, "no_directory"),
+        (&IS_FILE, "file_exists"), (&IS_DIRECTORY, "directory_exists"), (&UPLOAD_ERROR, "upload_error"),
+        (&UNPACK_ERROR, "unpack_error"), (&MOVE_ERROR, "move_error"), (&QUOTA_EXCEEDED, "quota_exceeded"), (&SHARE_INVALID, "link_invalid"),
+        (&LOGIN_FAILED, "failure"), (&LOGIN_LOCKED, "locked")
+    ];
+    pages.iter().find(|(content, _)| page == *content).map_or("error", |(_, name)| name)
+}
+
+// Derive the result of a route from its response, a redirect or a file means success
+pub fn result_name<T>(response: &Either<T, RawHtml<String>>) -> &'static str {
+    match response {
+        Either::Left(_) => "success",
+        Either::Right(RawHtml(page)) => page_name(page)
+    }
+}
+
+pub fn record_response<T>(auth: &Auth<'_>, action: &str, path: &Path, response: &Either<T, RawHtml<String>>) {
+    record(auth.actor().as_deref(), auth.ip, action, path, result_name(response));
+}
+
+// The matching entries of the current and the rotated log files, newest first
+fn find_entries(limit: usize, matches: impl Fn(&Entry) -> bool) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let files = [AUDIT_LOG.to_owned()].into_iter().chain((1..=CONFIG.audit_log_files).map(rotated_name));
+    for file in files {
+        let content = read_to_string(file).unwrap_or_default();
+        for entry in content.lines().rev().filter_map(|line| serde_json::from_str::<Entry>(line).ok()) {
+            if matches(&entry) { entries.push(entry); }
+            if entries.len() >= limit { break }
+        }
+        if entries.len() >= limit { break }
+    }
+    entries
+}
+
+// The latest actions of the administrators as text lines
+pub fn admin_actions(limit: usize) -> Vec<String> {
+    find_entries(limit, |entry| entry.action.starts_with("admin_")).iter()
+        .map(|entry| format!("{} admin={:?} action={} target={:?}", entry.time, entry.user.as_deref().unwrap_or("-"), entry.action, entry.path))
+        .collect()
+}
+
+// The filtered entries for the administration area
+pub fn viewer(language: &str, user: &str, action: &str, path: &str, result: &str) -> String {
+    let mut texts = ("Audit log", "user", "action", "path", "result", "Filter", "time", "IP address", "No matching entries.");
+    if language == "de" {
+        texts = ("Audit-Log", "Nutzer", "Aktion", "Pfad", "Ergebnis", "Filtern", "Zeit", "IP-Adresse", "Keine passenden Einträge.");
+    }
+    let entries = find_entries(VIEWER_ENTRIES, |entry| {
+        (user.is_empty() || entry.user.as_deref() == Some(user))
+            && (action.is_empty() || entry.action == action)
+            && (path.is_empty() || entry.path.contains(path))
+            && (result.is_empty() || entry.result == result)
+    });
+
+    let filter = |name: &str, value: &str, placeholder: &str| format!(
+        "<input name=\"{0}\" type=\"text\" value=\"{1}\" placeholder=\"{2}\" style=\"font-family:sans-serif; font-size:14px; \
+        width:120px; color:var(--foreground); background-color:var(--input); border-radius:4px; border-style:hidden; padding:8px; \
+        margin:4px\" />",
+        name, html::escape(value), placeholder
+    );
+    let fields = format!(
+        "{}{}{}{}<br>{}",
+        filter("user", user, texts.1), filter("action", action, texts.2), filter("path", path, texts.3),
+        filter("result", result, texts.4), html::submit(texts.5)
+    );
+    let mut content = format!("{}{}", html::paragraph(&format!("<b>{}</b>", texts.0)), html::form("/admin/audit", "get", &fields));
+    if entries.is_empty() {
+        content.push_str(&html::paragraph(texts.8));
+        return content
+    }
+    let cell = |text: &str| format!("<td style=\"padding:4px 8px; border-bottom:1px solid var(--shadows)\">{}</td>", html::escape(text));
+    let mut rows = format!(
+        "<tr>{}{}{}{}{}{}</tr>", cell(texts.6), cell(texts.1), cell(texts.7), cell(texts.2), cell(texts.3), cell(texts.4)
+    );
+    for entry in &entries {
+        rows.push_str(&format!(
+            "<tr>{}{}{}{}{}{}</tr>", cell(&entry.time), cell(entry.user.as_deref().unwrap_or("-")),
+            cell(entry.ip.as_deref().unwrap_or("-")), cell(&entry.action), cell(&entry.path), cell(&entry.result)
+        ));
+    }
+    content.push_str(&format!(
+        "<table style=\"margin-left:50%; transform:translateX(-50%); font-size:12px; text-align:left; border-collapse:collapse\">{}</table>",
+        rows
+    ));
+    content
+}
@@ -0,0 +1,283 @@
+/*
+This file contains the authentication backends that verify the login credentials (users file and LDAP directory).
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use ldap3::{LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry, ldap_escape};
+use crate::CONFIG;
+use crate::users::{get_user, set_user_hash, hash_password, verify_password, dummy_verify, is_legacy_hash, is_valid_name, provision_user};
+
+// Password hash entry of users that are created automatically after their first LDAP login
+const LDAP_HASH: &str = "!ldap";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LdapConfig {
+    url: String,
+    starttls: bool,
+    bind_dn: String,
+    bind_password: String,
+    base_dn: String,
+    user_filter: String,
+    group_filter: String,
+}
+
+#[rocket::async_trait]
+pub trait AuthBackend: Send + Sync {
+    // Returns true if the credentials are valid, the user entry and home directory have to exist afterwards
+    async fn authenticate(&self, name: &str, password: &str) -> bool;
+}
+
+// The local users file
+pub struct CsvBackend;
+
+#[rocket::async_trait]
+impl AuthBackend for CsvBackend {
+    async fn authenticate(&self, name: &str, password: &str) -> bool {
+        let Some(user) = get_user(name) else {
+            dummy_verify(password);
+            return false
+        };
+        if !verify_password(&user.hash, &user.name, password) { return false }
+        // Replace legacy SHA-384 entries with a salted Argon2id hash after a successful login
+        if is_legacy_hash(&user.hash) {
+            set_user_hash(name, &hash_password(password));
+        }
+        true
+    }
+}
+
+// The operations of a directory server that are needed for the login
+#[rocket::async_trait]
+pub trait Directory: Send + Sync {
+    // The distinguished names of all entries below the base that match the filter, searched with the service account
+    async fn search(&self, filter: &str) -> Result<Vec<String>, LdapError>;
+    // Returns true if the entry accepts the password
+    async fn bind(&self, dn: &str, password: &str) -> Result<bool, LdapError>;
+}
+
+// The configured LDAP server, every operation uses its own connection
+pub struct LdapDirectory {
+    config: &'static LdapConfig,
+}
+
+impl LdapDirectory {
+    async fn connect(&self) -> Result<ldap3::Ldap, LdapError> {
+        let settings = LdapConnSettings::new().set_starttls(self.config.starttls).set_conn_timeout(Duration::from_secs(10));
+        let (connection, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(connection);
+        Ok(ldap)
+    }
+}
+
+#[rocket::async_trait]
+impl Directory for LdapDirectory {
+    async fn search(&self, filter: &str) -> Result<Vec<String>, LdapError> {
+        let mut ldap = self.connect().await?;
+        if !self.config.bind_dn.is_empty() {
+            ldap.simple_bind(&self.config.bind_dn, &self.config.bind_password).await?.success()?;
+        }
+        let (entries, _) = ldap.search(&self.config.base_dn, Scope::Subtree, filter, vec!["1.1"]).await?.success()?;
+        let _ = ldap.unbind().await;
+        Ok(entries.into_iter().map(|entry| SearchEntry::construct(entry).dn).collect())
+    }
+
+    async fn bind(&self, dn: &str, password: &str) -> Result<bool, LdapError> {
+        let mut ldap = self.connect().await?;
+        let valid = ldap.simple_bind(dn, password).await?.rc == 0;
+        let _ = ldap.unbind().await;
+        Ok(valid)
+    }
+}
+
+// A directory server, users are searched with the service account and verified by a bind with their own password
+pub struct LdapBackend {
+    config: &'static LdapConfig,
+    directory: Box<dyn Directory>,
+}
+
+impl LdapBackend {
+    pub fn new(config: &'static LdapConfig, directory: Box<dyn Directory>) -> Self {
+        LdapBackend { config, directory }
+    }
+
+    fn filter(&self, name: &str) -> String {
+        let filter = self.config.user_filter.replace("{name}", &ldap_escape(name));
+        if self.config.group_filter.is_empty() { filter } else { format!("(&{}{})", filter, self.config.group_filter) }
+    }
+
+    async fn verify(&self, name: &str, password: &str) -> Result<bool, LdapError> {
+        let entries = self.directory.search(&self.filter(name)).await?;
+        // The name has to identify exactly one entry that also matches the group filter
+        let [user_dn] = entries.as_slice() else { return Ok(false) };
+        self.directory.bind(user_dn, password).await
+    }
+}
+
+#[rocket::async_trait]
+impl AuthBackend for LdapBackend {
+    async fn authenticate(&self, name: &str, password: &str) -> bool {
+        // An empty password would result in an unauthenticated bind, which most servers accept
+        if password.is_empty() || !is_valid_name(name) { return false }
+        // Local accounts with the same name cannot be taken over by a directory account
+        if get_user(name).is_some_and(|user| user.hash != LDAP_HASH) { return false }
+        match self.verify(name, password).await {
+            Ok(true) => {
+                provision_user(name, LDAP_HASH);
+                true
+            }
+            Ok(false) => false,
+            Err(error) => {
+                eprintln!("LDAP authentication of {:?} failed: {}", name, error);
+                false
+            }
+        }
+    }
+}
+
+lazy_static! {
+    // The backends are asked in the given order until one of them accepts the credentials
+    static ref BACKENDS: Vec<Box<dyn AuthBackend>> = {
+        let mut backends: Vec<Box<dyn AuthBackend>> = vec![Box::new(CsvBackend)];
+        if let Some(config) = &CONFIG.ldap {
+            backends.push(Box::new(LdapBackend::new(config, Box::new(LdapDirectory { config }))));
+        }
+        backends
+    };
+}
+
+pub async fn authenticate(name: &str, password: &str) -> bool {
+    for backend in BACKENDS.iter() {
+        if backend.authenticate(name, password).await { return true }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STORAGE;
+    use crate::test_support::setup;
+
+    // An in-process directory that understands equality filters and their conjunction
+    struct MockDirectory {
+        // Distinguished name, uid, groups and password of every entry
+        entries: Vec<(&'static str, &'static str, Vec<&'static str>, &'static str)>,
+    }
+
+    impl MockDirectory {
+        fn matches(filter: &str, uid: &str, groups: &[&str]) -> bool {
+            let inner = &filter[1..filter.len() - 1];
+            if let Some(mut rest) = inner.strip_prefix('&') {
+                // Split the conjunction into its parenthesized parts
+                while !rest.is_empty() {
+                    let mut depth = 0;
+                    let end = rest.char_indices().find(|(_, c)| {
+                        depth += match c { '(' => 1, ')' => -1, _ => 0 };
+                        depth == 0
+                    }).expect("Unbalanced filter").0;
+                    if !Self::matches(&rest[..=end], uid, groups) { return false }
+                    rest = &rest[end + 1..];
+                }
+                return true
+            }
+            match inner.split_once('=') {
+                Some(("uid", value)) => value == uid,
+                Some(("memberOf", value)) => groups.contains(&value),
+                _ => false
+            }
+        }
+    }
+
+    #[rocket::async_trait]
+    impl Directory for MockDirectory {
+        async fn search(&self, filter: &str) -> Result<Vec<String>, LdapError> {
+            Ok(self.entries.iter().filter(|(_, uid, groups, _)| Self::matches(filter, uid, groups)).map(|(dn, ..)| dn.to_string()).collect())
+        }
+
+        async fn bind(&self, dn: &str, password: &str) -> Result<bool, LdapError> {
+            Ok(self.entries.iter().any(|entry| entry.0 == dn && entry.3 == password))
+        }
+    }
+
+    const GROUP: &str = "cn=nas,ou=groups,dc=example,dc=com";
+
+    fn backend(group_filter: &str) -> LdapBackend {
+        let config = Box::leak(Box::new(LdapConfig {
+            url: "ldap://localhost".to_owned(),
+            starttls: false,
+            bind_dn: String::new(),
+            bind_password: String::new(),
+            base_dn: "dc=example,dc=com".to_owned(),
+            user_filter: "(uid={name})".to_owned(),
+            group_filter: group_filter.to_owned(),
+        }));
+        let directory = MockDirectory { entries: vec![
+            ("uid=ldap_alice,dc=example,dc=com", "ldap_alice", vec![GROUP], "secret"),
+            ("uid=ldap_bob,dc=example,dc=com", "ldap_bob", vec![], "secret"),
+            ("uid=ldap_carol,dc=example,dc=com", "ldap_carol", vec![GROUP], "secret"),
+            ("uid=ldap_local,dc=example,dc=com", "ldap_local", vec![GROUP], "secret"),
+            ("uid=ldap_twice,ou=a,dc=example,dc=com", "ldap_twice", vec![GROUP], "secret"),
+            ("uid=ldap_twice,ou=b,dc=example,dc=com", "ldap_twice", vec![GROUP], "secret"),
+        ] };
+        LdapBackend::new(config, Box::new(directory))
+    }
+
+    #[rocket::async_test]
+    async fn bind_and_search() {
+        setup();
+        let backend = backend("");
+        assert_eq!(backend.filter("ldap_alice"), "(uid=ldap_alice)");
+        assert!(backend.authenticate("ldap_alice", "secret").await);
+        assert!(!backend.authenticate("ldap_alice", "wrong").await);
+        assert!(!backend.authenticate("ldap_alice", "").await);
+        assert!(!backend.authenticate("ldap_nobody", "secret").await);
+        // A name that matches several entries is ambiguous
+        assert!(!backend.authenticate("ldap_twice", "secret").await);
+    }
+
+    #[rocket::async_test]
+    async fn group_filter() {
+        setup();
+        let backend = backend(&format!("(memberOf={})", GROUP));
+        assert_eq!(backend.filter("ldap_bob"), format!("(&(uid=ldap_bob)(memberOf={}))", GROUP));
+        assert!(!backend.authenticate("ldap_bob", "secret").await);
+        assert!(get_user("ldap_bob").is_none());
+        assert!(backend.authenticate("ldap_alice", "secret").await);
+    }
+
+    #[rocket::async_test]
+    async fn first_login_creates_home_directory() {
+        setup();
+        assert!(get_user("ldap_carol").is_none() && !STORAGE.join("ldap_carol").exists());
+        assert!(backend("").authenticate("ldap_carol", "secret").await);
+        assert_eq!(get_user("ldap_carol").expect("User has not been created").hash, LDAP_HASH);
+        assert!(STORAGE.join("ldap_carol").is_dir());
+        // Later logins use the existing entry
+        assert!(backend("").authenticate("ldap_carol", "secret").await);
+    }
+
+    #[rocket::async_test]
+    async fn local_accounts_are_not_taken_over() {
+        setup();
+        provision_user("ldap_local", &hash_password("local"));
+        assert!(!backend("").authenticate("ldap_local", "secret").await);
+        assert_ne!(get_user("ldap_local").expect("Local user is missing").hash, LDAP_HASH);
+        assert!(CsvBackend.authenticate("ldap_local", "local").await);
+    }
+}
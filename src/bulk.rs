@@ -0,0 +1,303 @@
+/*
+This file contains the operations on several selected entries of a directory.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::{Component, Path, PathBuf};
+use md5::{Md5, Digest};
+use rocket::form::Form;
+use rocket::fs::NamedFile;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{ACCESS_DENIED, READ_ONLY, NO_DIRECTORY, Auth, DotPathBuf, Settings, audit, html, transfer,
+            check_login, check_csrf, check_path, delete_dir_response, delete_file_response, zip_entries};
+use crate::tokens::Scope;
+use crate::transfer::Target;
+
+#[derive(FromForm)]
+pub struct Selection {
+    #[field(default = Vec::new())]
+    items: Vec<String>,
+    action: String,
+    // Moving and copying first show the form to choose the destination
+    #[field(default = String::new())]
+    destination: String,
+    #[field(default = String::from("refuse"))]
+    conflict: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+// Only plain names are accepted, so every item stays inside the current directory
+fn item_path(path: &Path, item: &str) -> Option<PathBuf> {
+    let mut components = Path::new(item).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(path.join(name)),
+        _ => None
+    }
+}
+
+// The reason why an item has failed, derived from the name of the error page in the audit log
+pub fn failure_text(result: &str, language: &str) -> &'static str {
+    let de = language == "de";
+    match result {
+        "access_denied" => if de { "Zugriff verweigert" } else { "Access denied" },
+        "read_only" => if de { "Nur Lesezugriff" } else { "Read-only access" },
+        "no_file" | "no_directory" => if de { "Nicht gefunden" } else { "Not found" },
+        "file_exists" | "directory_exists" => if de { "Der Name existiert bereits im Ziel" } else { "The name already exists in the destination" },
+        "quota_exceeded" => if de { "Speicherkontingent überschritten" } else { "Storage quota exceeded" },
+        _ => if de { "Fehlgeschlagen" } else { "Failed" }
+    }
+}
+
+// The page with the result of every selected item
+fn report(settings: &Settings, path: &Path, results: &[(String, String)]) -> RawHtml<String> {
+    let mut texts = ("Selected entries", "Not all selected entries could be processed.", "No entries have been selected.", "Back");
+    if settings.language == "de" {
+        texts = ("Ausgewählte Einträge", "Nicht alle ausgewählten Einträge konnten bearbeitet werden.", "Es wurden keine Einträge ausgewählt.", "Zurück");
+    }
+    let mut content = if results.is_empty() { html::error(texts.2) } else { html::paragraph(texts.1) };
+    for (item, result) in results {
+        content.push_str(&html::paragraph(&format!("<b>{}</b> &ensp; {}", html::escape(item), result)));
+    }
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    content.push_str(&format!("<br>{}", html::link(&uri!(crate::list_directory(path_string)).to_string(), texts.3)));
+    RawHtml(html::page(settings, texts.0, &content))
+}
+
+// The toolbar of the directory view, the checkboxes of the entries belong to its form
+pub fn toolbar(language: &str, path: &str, csrf_token: &str, entry_controls: &str, copy_controls: &str) -> String {
+    let mut texts = ("Selection:", "Delete", "Move", "Copy", "Download as ZIP", "The selected entries will be moved to the trash. Continue?");
+    if language == "de" {
+        texts = ("Auswahl:", "Löschen", "Verschieben", "Kopieren", "Als ZIP herunterladen", "Die ausgewählten Einträge werden in den Papierkorb verschoben. Fortfahren?");
+    }
+    let button = |action: &str, label: &str, display: &str, extra: &str| format!(
+        "<button name=\"action\" value=\"{0}\" type=\"submit\" {1} style=\"font-family:sans-serif; font-size:12px; margin:4px; \
+        color:var(--foreground); background-color:var(--input); border-radius:4px; border-style:hidden; padding:6px; cursor:pointer; \
+        display:{2}\">{3}</button>",
+        action, extra, display, label
+    );
+    format!(
+        "<form id=\"selection\" action=\"/bulk/{0}\" method=\"post\" style=\"margin:8px; font-family:sans-serif; font-size:12px; \
+        color:var(--foreground)\"> {1}{2} {3}{4}{5}{6}</form>",
+        path, html::hidden("csrf_token", csrf_token), texts.0,
+        button("delete", texts.1, entry_controls, &format!("onclick=\"return confirm('{}');\"", texts.5)),
+        button("move", texts.2, entry_controls, ""), button("copy", texts.3, copy_controls, ""),
+        button("zip", texts.4, "inline-block", &format!("formaction=\"/bulk_zip/{}\"", path))
+    )
+}
+
+// The form to choose the destination of the selected entries
+fn destination_form(auth: &Auth<'_>, path: &Path, data: &Selection, sources: &[PathBuf]) -> RawHtml<String> {
+    let settings = auth.settings();
+    let mut texts = (if data.action == "copy" { "Copy" } else { "Move" }, "Destination of the selected entries", "Back");
+    if settings.language == "de" {
+        texts = (if data.action == "copy" { "Kopieren" } else { "Verschieben" }, "Ziel der ausgewählten Einträge", "Zurück");
+    }
+    let destinations = if data.action == "copy" {
+        let Some(username) = auth.user() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+        transfer::copy_destinations(auth, &username, sources)
+    } else {
+        let Some(root) = check_login(auth, path, Scope::Delete) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+        transfer::destinations(auth, Path::new(&root), sources)
+    };
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    let mut fields = format!("{}{}", html::hidden("csrf_token", &auth.csrf_token()), html::hidden("action", &data.action));
+    let mut names = String::new();
+    for item in &data.items {
+        fields.push_str(&html::hidden("items", item));
+        names.push_str(&format!("<br><b>{}</b>", html::escape(item)));
+    }
+    fields.push_str(&format!("{}<br>{}", transfer::destination_fields(&settings.language, destinations, path_string), html::submit(texts.0)));
+    RawHtml(html::page(&settings, texts.0, &format!(
+        "{}{}<br>{}",
+        html::paragraph(&format!("{}:{}", texts.1, names)),
+        html::form(&format!("/bulk/{}", path_string), "post", &fields),
+        html::link(&uri!(crate::list_directory(path_string)).to_string(), texts.2)
+    )))
+}
+
+#[post("/bulk/<path..>", data = "<data>")]
+pub fn bulk(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<Selection>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    let Some(data) = data else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    let action = match data.action.as_str() {
+        "delete" => "delete",
+        "move" => "move",
+        "copy" => "copy",
+        _ => return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    check_csrf(&auth, Some(&data.csrf_token), action, &path)?;
+    if check_login(&auth, &path, Scope::Read).is_none() { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) }
+    if auth.is_read_only() { return Ok(Either::Right(RawHtml(auth.page(&READ_ONLY)))) }
+    if !check_path(&path).1 { return Ok(Either::Right(RawHtml(auth.page(&NO_DIRECTORY)))) }
+    let Some(sources) = data.items.iter().map(|item| item_path(&path, item)).collect::<Option<Vec<PathBuf>>>() else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    let settings = auth.settings();
+    if sources.is_empty() { return Ok(Either::Right(report(&settings, &path, &[]))) }
+    if action != "delete" && data.destination.is_empty() { return Ok(Either::Right(destination_form(&auth, &path, &data, &sources))) }
+
+    // Every item is processed on its own, so a failed item does not stop the others
+    let target = Target { name: String::new(), destination: data.destination.to_owned(), conflict: data.conflict.to_owned(), csrf_token: String::new() };
+    let mut results = Vec::new();
+    let mut failed = false;
+    let texts = if settings.language == "de" { ("Erledigt", "Kopie gestartet", "Fortschritt anzeigen") } else { ("Done", "Copy started", "Show progress") };
+    for (item, source) in data.items.iter().zip(&sources) {
+        // Both the source and the requested target are recorded in the audit log
+        let entry = PathBuf::from(format!("{} -> {}", source.display(), Path::new(data.destination.trim_matches('/')).join(item).display()));
+        let result = match action {
+            "delete" => {
+                let response = if check_path(source).1 { delete_dir_response(&auth, source) } else { delete_file_response(&auth, source) };
+                audit::record_response(&auth, action, source, &response);
+                audit::result_name(&response)
+            }
+            "move" => {
+                let response = transfer::move_response(&auth, source, &target);
+                audit::record_response(&auth, action, &entry, &response);
+                audit::result_name(&response)
+            }
+            _ => match transfer::start_copy(&auth, source, &target) {
+                // A started copy is recorded by the job when it has finished
+                Ok(id) => {
+                    let link = format!("<a href=\"{}\" style=\"color:{}\">{}</a>", uri!(transfer::job_status(id)), settings.theme.foreground, texts.2);
+                    results.push((item.to_owned(), format!("{} &ensp; {}", texts.1, link)));
+                    continue
+                }
+                Err(RawHtml(page)) => {
+                    let result = audit::page_name(&page);
+                    audit::record(auth.actor().as_deref(), auth.ip, action, &entry, result);
+                    result
+                }
+            }
+        };
+        if result == "success" {
+            results.push((item.to_owned(), texts.0.to_owned()));
+        } else {
+            failed = true;
+            results.push((item.to_owned(), failure_text(result, &settings.language).to_owned()));
+        }
+    }
+    // Without failures the user returns to the directory, started copies are shown with a link to their progress
+    if !failed && action != "copy" {
+        let directory = if action == "move" { data.destination.trim_matches('/') } else { path.to_str().expect("Invalid path encoding (expected UTF-8)") };
+        return Ok(Either::Left(Redirect::to(uri!(crate::list_directory(directory)))))
+    }
+    Ok(Either::Right(report(&settings, &path, &results)))
+}
+
+#[post("/bulk_zip/<path..>", data = "<data>")]
+pub async fn bulk_zip(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<Selection>>) -> Either<Option<NamedFile>, RawHtml<String>> {
+    let path = path.0;
+    let Some(data) = data else { return Either::Right(RawHtml(auth.page(&ACCESS_DENIED))) };
+    if check_login(&auth, &path, Scope::Read).is_none() { return Either::Right(RawHtml(auth.page(&ACCESS_DENIED))) }
+    if !check_path(&path).1 { return Either::Right(RawHtml(auth.page(&NO_DIRECTORY))) }
+    // The archive is only created if every selected item can be read
+    let settings = auth.settings();
+    let mut results = Vec::new();
+    for item in &data.items {
+        let result = match item_path(&path, item) {
+            None => "access_denied",
+            Some(source) if check_login(&auth, &source, Scope::Read).is_none() => "access_denied",
+            Some(source) if check_path(&source) == (false, false) => "no_file",
+            Some(_) => continue
+        };
+        audit::record(auth.actor().as_deref(), auth.ip, "zip", &path.join(item), result);
+        results.push((item.to_owned(), failure_text(result, &settings.language).to_owned()));
+    }
+    if data.items.is_empty() || !results.is_empty() { return Either::Right(report(&settings, &path, &results)) }
+    for item in &data.items {
+        audit::record(auth.actor().as_deref(), auth.ip, "zip", &path.join(item), "success");
+    }
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    let hash_value = format!("{:x}", Md5::digest(format!("{}/{}", path_string, data.items.join("/"))));
+    let directory_name = path.file_name().and_then(|name| name.to_str()).expect("Cannot extract directory name");
+    let names: Vec<&str> = data.items.iter().map(String::as_str).collect();
+    Either::Left(NamedFile::open(zip_entries(&path, &names, &format!("{}-{}", directory_name, hash_value))).await.ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, write};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use crate::STORAGE;
+    use crate::users::Role;
+    use crate::test_support::{CSRF_TOKEN, client, login, test_user};
+
+    fn bulk_client(user: &str, files: &[&str]) -> Client {
+        test_user(user, Role::User);
+        for file in files {
+            let path = STORAGE.join(user).join(file);
+            create_dir_all(path.parent().expect("Cannot extract parent path")).expect("Cannot create test directory");
+            write(path, *file).expect("Cannot write test file");
+        }
+        client(routes![bulk, bulk_zip])
+    }
+
+    fn send(client: &Client, user: &str, uri: &str, fields: &str) -> (Status, String) {
+        let response = login(client.post(uri.to_owned()), user).header(ContentType::Form)
+            .body(format!("{}&csrf_token={}", fields, CSRF_TOKEN)).dispatch();
+        (response.status(), response.into_string().unwrap_or_default())
+    }
+
+    #[test]
+    fn items_are_plain_names() {
+        assert_eq!(item_path(Path::new("user/dir"), "file.txt"), Some(PathBuf::from("user/dir/file.txt")));
+        for item in ["", ".", "..", "../file.txt", "sub/file.txt", "/file.txt"] {
+            assert_eq!(item_path(Path::new("user/dir"), item), None, "item {:?}", item);
+        }
+    }
+
+    #[test]
+    fn every_deleted_item_is_reported() {
+        let client = bulk_client("bulk_delete", &["a.txt"]);
+        let (status, page) = send(&client, "bulk_delete", "/bulk/bulk_delete", "items=a.txt&items=missing.txt&action=delete");
+        assert_eq!(status, Status::Ok);
+        assert!(page.contains("<b>a.txt</b> &ensp; Done"));
+        assert!(page.contains("<b>missing.txt</b> &ensp; Not found"));
+        assert!(!STORAGE.join("bulk_delete/a.txt").exists());
+
+        // Without failures the directory is shown again
+        write(STORAGE.join("bulk_delete/b.txt"), "b").expect("Cannot write test file");
+        assert_eq!(send(&client, "bulk_delete", "/bulk/bulk_delete", "items=b.txt&action=delete").0, Status::SeeOther);
+    }
+
+    #[test]
+    fn conflicts_fail_only_their_item() {
+        let client = bulk_client("bulk_move", &["a.txt", "b.txt", "target/b.txt"]);
+        let (_, page) = send(&client, "bulk_move", "/bulk/bulk_move", "items=a.txt&items=b.txt&action=move&destination=bulk_move/target");
+        assert!(page.contains("<b>a.txt</b> &ensp; Done"));
+        assert!(page.contains("<b>b.txt</b> &ensp; The name already exists in the destination"));
+        assert_eq!(read_to_string(STORAGE.join("bulk_move/target/a.txt")).expect("Moved file is missing"), "a.txt");
+        assert_eq!(read_to_string(STORAGE.join("bulk_move/target/b.txt")).expect("Existing file is missing"), "target/b.txt");
+        assert!(STORAGE.join("bulk_move/b.txt").exists());
+    }
+
+    #[test]
+    fn zip_needs_every_item() {
+        let client = bulk_client("bulk_zip", &["a.txt"]);
+        let (status, page) = send(&client, "bulk_zip", "/bulk_zip/bulk_zip", "items=a.txt&items=missing.txt&action=zip");
+        assert_eq!(status, Status::Ok);
+        assert!(page.contains("<b>missing.txt</b> &ensp; Not found"));
+        assert!(!page.contains("<b>a.txt</b>"));
+        assert!(send(&client, "bulk_zip", "/bulk_zip/bulk_zip", "items=../other&action=zip").1.contains("Access denied"));
+    }
+}
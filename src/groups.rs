@@ -0,0 +1,155 @@
+/*
+This file contains the group folders that are shared between several users according to their access lists.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_to_string};
+use serde::Deserialize;
+use crate::{STORAGE, sanitize_string};
+use crate::tokens::Scope;
+
+// Directory inside the storage path that contains all group folders
+pub const SHARED_DIRECTORY: &str = "shared";
+
+#[derive(Deserialize)]
+struct Folder {
+    name: String,
+    // Entries are user names or group names with a leading "@", write and delete include read access
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+    #[serde(default)]
+    delete: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct GroupsFile {
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    folder: Vec<Folder>,
+}
+
+// The groups file is read for every request, so changes take effect without a restart
+fn get_groups() -> GroupsFile {
+    // A missing groups file simply means that no group folder exists
+    let Ok(content) = read_to_string("groups.toml") else { return GroupsFile::default() };
+    toml::from_str(&content).expect("Groups file formatting is invalid")
+}
+
+fn is_valid_folder(name: &str) -> bool {
+    !name.is_empty() && sanitize_string(name) == name
+}
+
+fn matches(groups: &GroupsFile, entries: &[String], username: &str) -> bool {
+    entries.iter().any(|entry| match entry.strip_prefix('@') {
+        Some(group) => groups.groups.get(group).is_some_and(|members| members.iter().any(|member| member == username)),
+        None => entry == username
+    })
+}
+
+fn folder_allows(groups: &GroupsFile, folder: &Folder, username: &str, scope: Scope) -> bool {
+    match scope {
+        Scope::Read => [&folder.read, &folder.write, &folder.delete].iter().any(|entries| matches(groups, entries, username)),
+        Scope::Upload => matches(groups, &folder.write, username),
+        Scope::Delete => matches(groups, &folder.delete, username),
+    }
+}
+
+pub fn allows(username: &str, folder: &str, scope: Scope) -> bool {
+    let groups = get_groups();
+    groups.folder.iter()
+        .filter(|entry| entry.name == folder && is_valid_folder(&entry.name))
+        .any(|entry| folder_allows(&groups, entry, username, scope))
+}
+
+// All group folders the user can at least read
+pub fn user_folders(username: &str) -> Vec<String> {
+    let groups = get_groups();
+    let mut folders: Vec<String> = groups.folder.iter()
+        .filter(|folder| is_valid_folder(&folder.name) && folder_allows(&groups, folder, username, Scope::Read))
+        .map(|folder| folder.name.to_owned())
+        .collect();
+    folders.sort_by_key(|name| name.to_lowercase());
+    folders.dedup();
+    folders
+}
+
+// Create the directories of all configured group folders, existing contents are kept
+pub fn create_folders() {
+    for folder in get_groups().folder {
+        if !is_valid_folder(&folder.name) {
+            eprintln!("Skipping group folder with invalid name {:?}", folder.name);
+            continue;
+        }
+        create_dir_all(STORAGE.join(SHARED_DIRECTORY).join(&folder.name)).expect("Cannot create group folder (permission error)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use crate::test_support::setup;
+
+    const GROUPS: &str = r#"
+        [groups]
+        family = ["alice", "bob"]
+
+        [[folder]]
+        name = "Photos"
+        read = ["@family"]
+        write = ["alice"]
+        delete = ["carol"]
+
+        [[folder]]
+        name = "Archive"
+        read = ["@unknown", "dave"]
+
+        [[folder]]
+        name = "../Escape"
+        read = ["alice"]
+    "#;
+
+    #[test]
+    fn access_lists_match_users_and_groups() {
+        let groups: GroupsFile = toml::from_str(GROUPS).expect("Groups file formatting is invalid");
+        let photos = &groups.folder[0];
+        let allowed = |username: &str| [Scope::Read, Scope::Upload, Scope::Delete].map(|scope| folder_allows(&groups, photos, username, scope));
+        assert_eq!(allowed("alice"), [true, true, false]);
+        assert_eq!(allowed("bob"), [true, false, false]);
+        // Write and delete include read access, but not each other
+        assert_eq!(allowed("carol"), [true, false, true]);
+        assert_eq!(allowed("eve"), [false, false, false]);
+        // Unknown groups have no members
+        assert!(!folder_allows(&groups, &groups.folder[1], "alice", Scope::Read));
+        assert!(folder_allows(&groups, &groups.folder[1], "dave", Scope::Read));
+    }
+
+    #[test]
+    fn invalid_folder_names_are_ignored() {
+        setup();
+        write("groups.toml", GROUPS).expect("Cannot write groups file");
+        assert_eq!(user_folders("alice"), ["Photos"]);
+        assert_eq!(user_folders("dave"), ["Archive"]);
+        assert!(allows("alice", "Photos", Scope::Upload));
+        assert!(!allows("alice", "../Escape", Scope::Read));
+        assert!(!allows("alice", "photos", Scope::Read));
+    }
+}
@@ -0,0 +1,141 @@
+/*
+This file contains helper functions to create the dynamically generated HTML pages in the style of the static ones.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use time::OffsetDateTime;
+use time::macros::format_description;
+use crate::{Settings, Theme};
+
+pub fn escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn date(timestamp: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()
+        .and_then(|date| date.format(format_description!("[year]-[month]-[day] [hour]:[minute]")).ok())
+        .unwrap_or_default()
+}
+
+// The colors of the theme as CSS variables, so the elements below can be used on every page without passing the theme
+pub fn theme_variables(theme: &Theme) -> String {
+    format!(
+        "--foreground:{}; --accent-background:{}; --accent-foreground:{}; --shadows:{}; --errors:{}; --input:{}",
+        theme.foreground, theme.accent_background, theme.accent_foreground, theme.shadows, theme.errors, theme.input
+    )
+}
+
+pub fn page(settings: &Settings, title: &str, content: &str) -> String {
+    format!(
+        "<!DOCTYPE html> \
+        <html lang=\"{0}\"> \
+        <head> \
+            <meta charset=\"utf-8\"> \
+            <title>{1}</title> \
+        </head> \
+        <body style=\"background-color:{2}; {8}\"> \
+            <h1 style=\"font-family:sans-serif; font-size:24px; text-align:center; font-weight:bold; color:{3}; background-color:{4}; \
+                    border-radius:10px; margin:16px; margin-bottom:32px; padding:8px; box-shadow:2px 2px 4px {5}\"> \
+                {1} \
+            </h1> \
+            <div style=\"margin:auto; width:600px; font-family:sans-serif; font-size:14px; text-align:center; color:{6}\"> \
+                {7} \
+            </div> \
+        </body> \
+        </html>",
+        settings.language, title, settings.theme.background, settings.theme.accent_foreground, settings.theme.accent_background,
+        settings.theme.shadows, settings.theme.foreground, content, theme_variables(&settings.theme)
+    )
+}
+
+pub fn paragraph(text: &str) -> String {
+    format!("<p style=\"margin:16px\">{}</p>", text)
+}
+
+pub fn error(text: &str) -> String {
+    format!(
+        "<p style=\"margin:auto; text-align:center; color:var(--foreground); background-color:var(--errors); border-radius:4px; \
+        margin-top:16px; margin-bottom:16px; padding:8px; width:400px; box-shadow:2px 2px 4px var(--shadows)\">{}</p>",
+        text
+    )
+}
+
+pub fn input(name: &str, input_type: &str, placeholder: &str) -> String {
+    format!(
+        "<input name=\"{0}\" type=\"{1}\" placeholder=\"{2}\" style=\"font-family:sans-serif; font-size:14px; text-align:left; \
+        width:234px; color:var(--foreground); background-color:var(--input); border-radius:4px; border-style:hidden; padding:8px; \
+        margin:4px\" />",
+        name, input_type, placeholder
+    )
+}
+
+// A text field that already contains a value
+pub fn input_value(name: &str, value: &str) -> String {
+    format!(
+        "<input name=\"{0}\" type=\"text\" value=\"{1}\" style=\"font-family:sans-serif; font-size:14px; text-align:left; \
+        width:234px; color:var(--foreground); background-color:var(--input); border-radius:4px; border-style:hidden; padding:8px; \
+        margin:4px\" />",
+        name, escape(value)
+    )
+}
+
+pub fn hidden(name: &str, value: &str) -> String {
+    format!("<input name=\"{}\" type=\"hidden\" value=\"{}\" />", name, escape(value))
+}
+
+pub fn checkbox(name: &str, label: &str) -> String {
+    format!(
+        "<label style=\"margin:8px; cursor:pointer\"><input name=\"{}\" type=\"checkbox\" style=\"accent-color:var(--accent-background)\" />{}</label>",
+        name, label
+    )
+}
+
+pub fn select(name: &str, options: &[(&str, &str)], selected: &str) -> String {
+    let mut items = String::new();
+    for (value, label) in options {
+        let attribute = if *value == selected { " selected" } else { "" };
+        items.push_str(&format!("<option value=\"{}\"{}>{}</option>", value, attribute, label));
+    }
+    format!(
+        "<select name=\"{}\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; color:var(--foreground); \
+        background-color:var(--input); border-radius:4px; border-style:hidden; padding:8px; margin:4px\">{}</select>",
+        name, items
+    )
+}
+
+pub fn submit(label: &str) -> String {
+    format!(
+        "<input value=\"{}\" type=\"submit\" style=\"font-family:sans-serif; font-size:14px; text-align:center; width:250px; \
+        color:var(--accent-foreground); background-color:var(--accent-background); border-radius:4px; border-style:hidden; \
+        padding:8px; margin:4px; cursor:pointer; box-shadow:2px 2px 4px var(--shadows)\" />",
+        label
+    )
+}
+
+pub fn form(action: &str, method: &str, fields: &str) -> String {
+    format!("<form action=\"{}\" method=\"{}\" style=\"margin:8px; display:inline-block\">{}</form>", action, method, fields)
+}
+
+pub fn link(href: &str, label: &str) -> String {
+    form(href, "get", &submit(label))
+}
@@ -0,0 +1,267 @@
+/*
+This file contains the internal sharing of files and directories between the users of the server.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::fs::read_to_string;
+use serde::{Deserialize, Serialize};
+use rocket::form::Form;
+use rocket::http::RawStr;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{STORAGE, ACCESS_DENIED, READ_ONLY, Auth, DotPathBuf, html, shares, check_csrf, unix_time, write_file_atomic};
+use crate::tokens::Scope;
+use crate::users::get_user;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InternalShare {
+    pub owner: String,
+    pub recipient: String,
+    // Path of the shared file or directory relative to the storage path
+    pub path: String,
+    // Read-write shares allow to upload and create inside the shared directory, but never to delete or move
+    pub write: bool,
+    pub created: u64,
+}
+
+impl InternalShare {
+    // The owner has to keep the access to the path, otherwise the share is ignored
+    fn allows(&self, scope: Scope) -> bool {
+        (scope == Scope::Read || (self.write && scope == Scope::Upload))
+            && shares::can_share(&self.owner, Path::new(&self.path), if self.write { Scope::Upload } else { Scope::Read })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct InternalSharesFile {
+    #[serde(default)]
+    share: Vec<InternalShare>,
+}
+
+#[derive(FromForm)]
+pub struct NewInternalShare {
+    recipient: String,
+    access: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct InternalShareKey {
+    recipient: String,
+    path: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+lazy_static! {
+    // Serialize all write access to the internal shares file
+    static ref INTERNAL_SHARES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn get_internal_shares() -> Vec<InternalShare> {
+    // A missing file simply means that nothing has been shared yet
+    let Ok(content) = read_to_string("internal_shares.toml") else { return Vec::new() };
+    toml::from_str::<InternalSharesFile>(&content).expect("Internal shares file formatting is invalid").share
+}
+
+fn update_internal_shares(change: impl FnOnce(&mut Vec<InternalShare>)) {
+    let _guard = INTERNAL_SHARES_LOCK.lock().expect("Internal shares file lock is poisoned");
+    let mut shares = get_internal_shares();
+    change(&mut shares);
+    let content = toml::to_string(&InternalSharesFile { share: shares }).expect("Cannot serialize internal shares file");
+    write_file_atomic("internal_shares.toml", &content);
+}
+
+pub fn remove_user_internal_shares(name: &str) {
+    update_internal_shares(|shares| shares.retain(|share| share.owner != name && share.recipient != name));
+}
+
+pub fn rename_user_internal_shares(old_name: &str, new_name: &str) {
+    update_internal_shares(|shares| {
+        for share in shares.iter_mut() {
+            if share.owner == old_name { share.owner = new_name.to_owned(); }
+            if share.recipient == old_name { share.recipient = new_name.to_owned(); }
+            if let Ok(relative_path) = Path::new(&share.path).strip_prefix(old_name) {
+                share.path = Path::new(new_name).join(relative_path).to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+            }
+        }
+    });
+}
+
+// Returns the shared root that grants the user access to the path with the given scope
+pub fn shared_root(username: &str, path: &Path, scope: Scope) -> Option<String> {
+    get_internal_shares().into_iter()
+        .find(|share| share.recipient == username && path.starts_with(&share.path) && share.allows(scope))
+        .map(|share| share.path)
+}
+
+// The shared directories the user can write to
+pub fn writable_roots(username: &str) -> Vec<String> {
+    get_internal_shares().into_iter()
+        .filter(|share| share.recipient == username && share.allows(Scope::Upload) && STORAGE.join(&share.path).is_dir())
+        .map(|share| share.path)
+        .collect()
+}
+
+// The form on the share page to give another user access
+pub fn share_section(path: &str, language: &str, csrf_token: &str) -> String {
+    let mut texts = ("Share with a user", "user name", "read-only", "read-write", "Share");
+    if language == "de" {
+        texts = ("Mit einem Nutzer teilen", "Nutzername", "nur lesen", "lesen und schreiben", "Teilen");
+    }
+    let options = [("read", texts.2), ("write", texts.3)];
+    let fields = format!(
+        "{}{}<br>{}<br>{}",
+        html::hidden("csrf_token", csrf_token), html::input("recipient", "text", texts.1), html::select("access", &options, "read"), html::submit(texts.4)
+    );
+    format!(
+        "<br>{}{}",
+        html::paragraph(&format!("<b>{}</b>", texts.0)), html::form(&format!("/share_user/{}", path), "post", &fields)
+    )
+}
+
+// The list of the own internal shares on the "My shares" page
+pub fn owner_section(username: &str, language: &str, csrf_token: &str) -> String {
+    let mut texts = ("Shared with users", "read-only", "read-write", "Revoke");
+    if language == "de" {
+        texts = ("Mit Nutzern geteilt", "nur lesen", "lesen und schreiben", "Widerrufen");
+    }
+    let shares: Vec<InternalShare> = get_internal_shares().into_iter().filter(|share| share.owner == username).collect();
+    if shares.is_empty() { return String::new() }
+    let mut content = format!("<br>{}", html::paragraph(&format!("<b>{}</b>", texts.0)));
+    for share in &shares {
+        content.push_str(&html::paragraph(&format!(
+            "<b>/{}</b> &ensp; {} &ensp; {}",
+            html::escape(&share.path), html::escape(&share.recipient), if share.write { texts.2 } else { texts.1 }
+        )));
+        let fields = format!(
+            "{}{}{}{}", html::hidden("recipient", &share.recipient), html::hidden("path", &share.path), html::hidden("csrf_token", csrf_token),
+            html::submit(texts.3)
+        );
+        content.push_str(&html::form("/shares/users/revoke", "post", &fields));
+    }
+    content
+}
+
+#[post("/share_user/<path..>", data = "<data>")]
+pub fn share_with_user(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<NewInternalShare>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "share_with_user", &path)?;
+    let (Some(username), Some(data)) = (auth.user(), data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    if auth.is_read_only() { return Ok(RawHtml(auth.page(&READ_ONLY))) }
+    let write = data.access == "write";
+    let settings = auth.settings();
+    let mut texts = ("Share with a user", "has access now.", "The user does not exist.", "My shares");
+    if settings.language == "de" {
+        texts = ("Mit einem Nutzer teilen", "hat jetzt Zugriff.", "Der Nutzer existiert nicht.", "Meine Freigaben");
+    }
+    if !shares::can_share(&username, &path, if write { Scope::Upload } else { Scope::Read }) || !STORAGE.join(&path).exists() {
+        return Ok(RawHtml(auth.page(&ACCESS_DENIED)))
+    }
+    let back = html::link(&uri!(shares::my_shares).to_string(), texts.3);
+    let recipient = data.recipient.trim().to_owned();
+    if recipient == username || get_user(&recipient).is_none() {
+        return Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.2), back))))
+    }
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+    let message = format!("<b>{}</b> {}", html::escape(&recipient), texts.1);
+    update_internal_shares(|shares| {
+        // Sharing the same path again replaces the access of the recipient
+        shares.retain(|share| share.recipient != recipient || share.path != path_string);
+        shares.push(InternalShare { owner: username, recipient, path: path_string, write, created: unix_time() });
+    });
+    Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::paragraph(&message), back))))
+}
+
+#[post("/shares/users/revoke", data = "<data>")]
+pub fn revoke_internal_share(auth: Auth<'_>, data: Option<Form<InternalShareKey>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "revoke_internal_share", Path::new(""))?;
+    let (Some(username), Some(data)) = (auth.user(), data) else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    update_internal_shares(|shares| {
+        shares.retain(|share| share.owner != username || share.recipient != data.recipient || share.path != data.path)
+    });
+    Ok(Either::Left(Redirect::to(uri!(shares::my_shares))))
+}
+
+#[get("/shared_with_me")]
+pub fn shared_with_me(auth: Auth<'_>) -> RawHtml<String> {
+    let Some(username) = auth.user() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = auth.settings();
+    let mut texts = ("Shared with me", "Nothing has been shared with you yet.", "from", "read-only", "read-write", "Back");
+    if settings.language == "de" {
+        texts = ("Mit mir geteilt", "Bisher wurde nichts geteilt.", "von", "nur lesen", "lesen und schreiben", "Zurück");
+    }
+    let mut content = String::new();
+    let shares: Vec<InternalShare> = get_internal_shares().into_iter()
+        .filter(|share| share.recipient == username && share.allows(Scope::Read) && STORAGE.join(&share.path).exists())
+        .collect();
+    if shares.is_empty() { content.push_str(&html::paragraph(texts.1)); }
+    for share in &shares {
+        let is_directory = STORAGE.join(&share.path).is_dir();
+        let name = Path::new(&share.path).file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let href: Vec<String> = share.path.split('/').map(|component| RawStr::new(component).percent_encode().to_string()).collect();
+        content.push_str(&format!(
+            "<p style=\"margin:8px; text-align:left\"><a href=\"/{0}/{1}\" style=\"color:var(--foreground); text-decoration:none\"> \
+            <img src=\"/icons/{2}_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/>{3}</a> &ensp; {4} {5} &ensp; {6}</p>",
+            if is_directory { "files" } else { "download" }, html::escape(&href.join("/")), if is_directory { "folder" } else { "file" },
+            html::escape(name), texts.2, html::escape(&share.owner), if share.write { texts.4 } else { texts.3 }
+        ));
+    }
+    content.push_str(&format!("<br>{}", html::link(&format!("/files/{}", username), texts.5)));
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use crate::users::Role;
+    use crate::test_support::{client, login, test_user};
+
+    fn test_share(owner: &str, recipient: &str, path: &str, write: bool) -> InternalShare {
+        test_user(owner, Role::User);
+        test_user(recipient, Role::User);
+        create_dir_all(STORAGE.join(path)).expect("Cannot create test directory");
+        let share = InternalShare { owner: owner.to_owned(), recipient: recipient.to_owned(), path: path.to_owned(), write, created: unix_time() };
+        update_internal_shares(|shares| shares.push(share.clone()));
+        share
+    }
+
+    #[test]
+    fn write_access_never_allows_deletion() {
+        let read = test_share("internal_owner", "internal_reader", "internal_owner/read", false);
+        let write = test_share("internal_owner", "internal_writer", "internal_owner/write", true);
+        assert!(read.allows(Scope::Read) && !read.allows(Scope::Upload) && !read.allows(Scope::Delete));
+        assert!(write.allows(Scope::Read) && write.allows(Scope::Upload) && !write.allows(Scope::Delete));
+        assert_eq!(shared_root("internal_writer", Path::new("internal_owner/write/sub"), Scope::Upload).as_deref(), Some("internal_owner/write"));
+        assert_eq!(shared_root("internal_writer", Path::new("internal_owner/write/sub"), Scope::Delete), None);
+    }
+
+    #[test]
+    fn shared_paths_are_encoded() {
+        test_share("internal_owner", "internal_viewer", "internal_owner/a \"b\" & <c>#", false);
+        let client = client(routes![shared_with_me]);
+        let page = login(client.get("/shared_with_me"), "internal_viewer").dispatch().into_string().unwrap_or_default();
+        assert!(page.contains("href=\"/files/internal_owner/a%20%22b%22%20%26%20%3Cc%3E%23\""));
+        assert!(page.contains("a &quot;b&quot; &amp; &lt;c&gt;#</a>"));
+    }
+}
@@ -20,25 +20,51 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate lazy_static;
 
+mod html;
+mod totp;
+mod users;
+mod backend;
+mod sso;
+mod account;
+mod session;
+mod throttle;
+mod tokens;
+mod admin;
+mod groups;
+mod shares;
+mod internal;
+mod audit;
+mod transfer;
+mod trash;
+mod bulk;
+mod tus;
+
 use std::env;
 use std::thread;
 use std::ffi::OsStr;
+use std::net::IpAddr;
 use std::fmt::Debug;
-use std::time::Duration;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::string::String;
 use std::process::Command;
-use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf, MAIN_SEPARATOR_STR};
-use std::fs::{File, read_to_string, remove_file, remove_dir_all, create_dir};
-use md5::Md5;
-use sha2::{Sha384, Digest};
+use std::fs::{File, read_to_string, remove_file, create_dir, create_dir_all, rename};
+use std::os::unix::fs::MetadataExt;
+use md5::{Md5, Digest};
 use serde::{Deserialize, Serialize};
 use rocket::form::Form;
 use rocket::response::Redirect;
-use rocket::request::FromSegments;
+use rocket::response::status::Forbidden;
+use rocket::mtls::Certificate;
+use rocket::mtls::x509::GeneralName;
+use rocket::request::{FromRequest, FromSegments, Outcome, Request};
 use rocket::http::{Cookie, CookieJar};
 use rocket::response::content::RawHtml;
 use rocket::{Rocket, Build, FromForm, Either};
+use users::{Role, User, get_users, get_user};
+use tokens::{Scope, Token};
 use rocket::fs::{FileServer, NamedFile, TempFile};
 use rocket::http::uri::{Segments, error::PathError};
 use rocket::http::uri::fmt::{FromUriParam, Path as RocketPath};
@@ -65,6 +91,132 @@ impl<'a> FromUriParam<RocketPath, &'a str> for DotPathBuf {
     }
 }
 
+// Collects everything that identifies the client: the session cookie, an optional verified client certificate
+// and an optional API token, the client address is kept for the audit log
+pub struct Auth<'r> {
+    cookies: &'r CookieJar<'r>,
+    cert_user: Option<String>,
+    bearer: Option<Token>,
+    ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Auth<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let cert_user = match request.guard::<Certificate<'_>>().await {
+            Outcome::Success(certificate) => certificate_user(&certificate, &CONFIG.cert_user_field),
+            _ => None
+        };
+        let bearer = request.headers().get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(tokens::find_token);
+        Outcome::Success(Auth { cookies: request.cookies(), cert_user, bearer, ip: request.client_ip() })
+    }
+}
+
+impl Auth<'_> {
+    // Determine the logged-in user according to the configured client certificate mode
+    fn account(&self) -> Option<User> {
+        let session_user = session_user(self.cookies);
+        let username = match CONFIG.cert_login.as_str() {
+            "cert_only" => self.cert_user.to_owned(),
+            "cert_or_password" => session_user.or(self.cert_user.to_owned()),
+            "cert_and_password" => session_user.filter(|username| self.cert_user.as_ref() == Some(username)),
+            _ => session_user
+        }?;
+        // Disabled users lose access immediately, including their running sessions
+        get_user(&username).filter(|user| user.enabled)
+    }
+
+    fn user(&self) -> Option<String> {
+        self.account().map(|user| user.name)
+    }
+
+    // The user that is recorded in the audit log, requests with an API token act on behalf of the token owner
+    fn actor(&self) -> Option<String> {
+        match &self.bearer {
+            Some(token) => Some(token.user.to_owned()),
+            None => self.user()
+        }
+    }
+
+    // The CSRF token that has to be sent with every form that changes files, browsers without one (e.g. with a
+    // certificate login) get a new token
+    fn csrf_token(&self) -> String {
+        match self.cookies.get_private("csrf_token") {
+            Some(cookie) => cookie.value().to_owned(),
+            None => add_csrf_cookie(self.cookies)
+        }
+    }
+
+    // Browsers send the session cookie with every request, so the token of the form has to match as well,
+    // requests with an API token cannot be forged by other websites and need no CSRF token
+    fn verify_csrf(&self, token: &str) -> bool {
+        self.bearer.is_some() || self.cookies.get_private("csrf_token").is_some_and(|cookie| !token.is_empty() && cookie.value() == token)
+    }
+
+    // The settings of the user that sends the request, anonymous visitors get the server defaults
+    fn settings(&self) -> Settings {
+        self.actor().map_or_else(Settings::default, |username| user_settings(&username))
+    }
+
+    // A static page in the language and theme of the user
+    fn page(&self, page: &str) -> String {
+        localize_page(page, &self.settings())
+    }
+
+    // The responses are recorded with the static pages, which are only localized afterwards
+    fn localize<T>(&self, response: Either<T, RawHtml<String>>) -> Either<T, RawHtml<String>> {
+        match response {
+            Either::Right(RawHtml(page)) => Either::Right(RawHtml(self.page(&page))),
+            response => response
+        }
+    }
+
+    // Read-only accounts and their API tokens can only browse and download files
+    fn is_read_only(&self) -> bool {
+        let user = match &self.bearer {
+            Some(token) => get_user(&token.user),
+            None => self.account()
+        };
+        user.is_some_and(|user| user.role == Role::ReadOnly)
+    }
+}
+
+// Interface colors, either from the config file or from one of the built-in themes
+#[derive(PartialEq)]
+pub struct Theme {
+    background: &'static str,
+    foreground: &'static str,
+    accent_background: &'static str,
+    accent_foreground: &'static str,
+    shadows: &'static str,
+    errors: &'static str,
+    input: &'static str,
+}
+
+// The language and theme of the pages, chosen by the user or the server defaults
+#[derive(PartialEq)]
+pub struct Settings {
+    language: String,
+    theme: Theme,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            language: CONFIG.language.to_owned(),
+            theme: Theme {
+                background: CONFIG.background.as_str(), foreground: CONFIG.foreground.as_str(),
+                accent_background: CONFIG.accent_background.as_str(), accent_foreground: CONFIG.accent_foreground.as_str(),
+                shadows: CONFIG.shadows.as_str(), errors: CONFIG.errors.as_str(), input: CONFIG.input.as_str(),
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Config {
     language: String,
@@ -78,33 +230,118 @@ struct Config {
     errors: String,
     input: String,
     clean_tmp_files: u64,
+    #[serde(default = "default_login_max_attempts")]
+    login_max_attempts: u32,
+    #[serde(default = "default_login_lockout_base")]
+    login_lockout_base: u64,
+    #[serde(default = "default_login_lockout_max")]
+    login_lockout_max: u64,
+    #[serde(default = "default_session_idle_timeout")]
+    session_idle_timeout: u64,
+    #[serde(default = "default_session_lifetime")]
+    session_lifetime: u64,
+    #[serde(default = "default_audit_log_max_size")]
+    audit_log_max_size: u64,
+    #[serde(default = "default_audit_log_files")]
+    audit_log_files: u64,
+    #[serde(default = "default_trash_retention")]
+    trash_retention: u64,
+    #[serde(default = "default_upload_expiry")]
+    upload_expiry: u64,
+    #[serde(default = "default_upload_conflict")]
+    upload_conflict: String,
+    #[serde(default = "default_cert_login")]
+    cert_login: String,
+    #[serde(default = "default_cert_user_field")]
+    cert_user_field: String,
     whitelist: String,
     name_length: usize,
+    ldap: Option<backend::LdapConfig>,
+    oidc: Option<sso::OidcConfig>,
 }
 
+// Settings that were added later are optional, so config files of older versions can still be used
+fn default_login_max_attempts() -> u32 { 5 }
+fn default_login_lockout_base() -> u64 { 30 }
+fn default_login_lockout_max() -> u64 { 3600 }
+fn default_session_idle_timeout() -> u64 { 3600 }
+fn default_session_lifetime() -> u64 { 86400 }
+fn default_audit_log_max_size() -> u64 { 10 }
+fn default_audit_log_files() -> u64 { 5 }
+fn default_trash_retention() -> u64 { 30 }
+fn default_upload_expiry() -> u64 { 86400 }
+fn default_upload_conflict() -> String { "reject".to_owned() }
+fn default_cert_login() -> String { "off".to_owned() }
+fn default_cert_user_field() -> String { "common_name".to_owned() }
+
 #[derive(FromForm)]
 struct LoginData {
     name: String,
     password: String,
 }
 
+#[derive(FromForm)]
+struct CsrfToken {
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
 #[derive(FromForm)]
 struct FolderName {
     folder_name: String,
+    #[field(default = String::new())]
+    csrf_token: String,
 }
 
 #[derive(FromForm)]
 struct ArchiveName {
     archive_name: String,
+    #[field(default = String::new())]
+    csrf_token: String,
 }
 
 #[derive(FromForm)]
 struct Upload<'r> {
-    file: TempFile<'r>,
+    // Several files and the files of a folder (with their relative paths) can be uploaded at once
+    #[field(default = Vec::new())]
+    file: Vec<TempFile<'r>>,
+    // The conflict policy chosen in the form, the configured default is used without a choice
+    #[field(default = String::new())]
+    conflict: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+// What happens if an uploaded file has the name of an existing entry
+#[derive(Clone, Copy, PartialEq)]
+pub enum UploadConflict {
+    Reject,
+    Overwrite,
+    // Store the upload under a free name like "report (1).pdf"
+    KeepBoth,
+    // Overwrite only if the uploaded file has been modified after the existing one
+    Newer,
+}
+
+impl UploadConflict {
+    // An empty value uses the configured default, unknown values reject the upload
+    pub fn parse(value: &str) -> Self {
+        match if value.is_empty() { CONFIG.upload_conflict.as_str() } else { value } {
+            "overwrite" => UploadConflict::Overwrite,
+            "keep_both" => UploadConflict::KeepBoth,
+            "newer" => UploadConflict::Newer,
+            _ => UploadConflict::Reject
+        }
+    }
 }
 
+type MenuContent<'a> = (&'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str, &'a str);
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Subdirectory of tmp for staged uploads, which the cleaning thread skips
+const UPLOAD_DIRECTORY: &str = "uploads";
+
 lazy_static! {
     // Load the config file
     static ref CONFIG: Config = toml::from_str(
@@ -112,7 +349,7 @@ lazy_static! {
     ).expect("Config file formatting is invalid");
 
     // Ensure a canonical, absolute storage path
-    static ref STORAGE: PathBuf = if CONFIG.storage_path.len() == 0 {
+    static ref STORAGE: PathBuf = if CONFIG.storage_path.is_empty() {
         env::current_dir().expect("Cannot access the current working directory")
     } else if &CONFIG.storage_path[0..1] != "/" {
         env::current_dir().expect("Cannot access the current working directory").join(CONFIG.storage_path.as_str())
@@ -123,6 +360,8 @@ lazy_static! {
     // Apply the settings to the static HTML files
     static ref HOME: String = load_static_file("home");
     static ref LOGIN_FAILED: String = load_static_file("login_failed");
+    static ref LOGIN_LOCKED: String = load_static_file("login_locked");
+    static ref TOTP: String = load_static_file("totp");
     static ref ACCESS_DENIED: String = load_static_file("access_denied");
     static ref NO_DIRECTORY: String = load_static_file("no_directory");
     static ref NO_FILE: String = load_static_file("no_file");
@@ -130,33 +369,56 @@ lazy_static! {
     static ref IS_FILE: String = load_static_file("is_file");
     static ref UPLOAD_ERROR: String = load_static_file("upload_error");
     static ref UNPACK_ERROR: String = load_static_file("unpack_error");
+    static ref MOVE_ERROR: String = load_static_file("move_error");
+    static ref QUOTA_EXCEEDED: String = load_static_file("quota_exceeded");
+    static ref READ_ONLY: String = load_static_file("read_only");
+    static ref SHARE_INVALID: String = load_static_file("share_invalid");
+    static ref CSRF_INVALID: String = load_static_file("csrf_invalid");
+
+    // The check for an existing name and the placement of an upload happen together, so parallel uploads cannot replace each other.
+    // Moves, copies and unpacking do not take it, a new upload name is therefore placed in a way that never replaces an entry
+    static ref UPLOAD_LOCK: Mutex<()> = Mutex::new(());
 }
 
 fn load_static_file(input: &str) -> String {
-    let tmp = read_to_string(format!("static/{}_{}.html", CONFIG.language, input)).expect("Cannot read static HTML file");
+    load_page(input, &Settings::default())
+}
+
+fn load_page(input: &str, settings: &Settings) -> String {
+    let tmp = read_to_string(format!("static/{}_{}.html", settings.language, input)).expect("Cannot read static HTML file");
     tmp
         .replace("{{OW}}", CONFIG.owner.as_str())
-        .replace("{{BG}}", CONFIG.background.as_str())
-        .replace("{{FG}}", CONFIG.foreground.as_str())
-        .replace("{{ABG}}", CONFIG.accent_background.as_str())
-        .replace("{{AFG}}", CONFIG.accent_foreground.as_str())
-        .replace("{{SH}}", CONFIG.shadows.as_str())
-        .replace("{{ER}}", CONFIG.errors.as_str())
-        .replace("{{IN}}", CONFIG.input.as_str())
-}
-
-fn get_users() -> Vec<(String, String)> {
-    let file = File::open("users.csv").expect("Cannot read users file");
-    let buffer = BufReader::new(file);
-    buffer.lines().map(|line| {
-        match line.expect("Cannot parse line in users file").split_once(";") {
-            None => ("".to_owned(), "".to_owned()),
-            Some(tuple) => (tuple.0.to_owned(), tuple.1.to_owned())
-        }
-    }).collect()
+        .replace("{{BG}}", settings.theme.background)
+        .replace("{{FG}}", settings.theme.foreground)
+        .replace("{{ABG}}", settings.theme.accent_background)
+        .replace("{{AFG}}", settings.theme.accent_foreground)
+        .replace("{{SH}}", settings.theme.shadows)
+        .replace("{{ER}}", settings.theme.errors)
+        .replace("{{IN}}", settings.theme.input)
+        .replace("{{SSO}}", if CONFIG.oidc.is_some() { "block" } else { "none" })
+}
+
+// The static pages with the names of their files
+fn static_pages() -> [(&'static str, &'static str); 16] {
+    [
+        (&HOME, "home"), (&LOGIN_FAILED, "login_failed"), (&LOGIN_LOCKED, "login_locked"), (&TOTP, "totp"),
+        (&ACCESS_DENIED, "access_denied"), (&NO_DIRECTORY, "no_directory"), (&NO_FILE, "no_file"),
+        (&IS_DIRECTORY, "is_directory"), (&IS_FILE, "is_file"), (&UPLOAD_ERROR, "upload_error"),
+        (&UNPACK_ERROR, "unpack_error"), (&MOVE_ERROR, "move_error"), (&QUOTA_EXCEEDED, "quota_exceeded"), (&READ_ONLY, "read_only"),
+        (&SHARE_INVALID, "share_invalid"), (&CSRF_INVALID, "csrf_invalid")
+    ]
+}
+
+// The static pages are loaded in the server language and theme, this returns one of them in the given settings
+fn localize_page(page: &str, settings: &Settings) -> String {
+    if *settings == Settings::default() { return page.to_owned() }
+    match static_pages().iter().find(|(content, _)| page == *content) {
+        Some((_, name)) => load_page(name, settings),
+        None => page.to_owned()
+    }
 }
 
-fn check_login(cookies: &CookieJar<'_>, path: &PathBuf) -> Option<String> {
+fn check_login(auth: &Auth<'_>, path: &Path, scope: Scope) -> Option<String> {
     let mut username = String::new();
     for item in path.iter() {
         if item != OsStr::new(&MAIN_SEPARATOR_STR) {
@@ -164,18 +426,123 @@ fn check_login(cookies: &CookieJar<'_>, path: &PathBuf) -> Option<String> {
             break;
         }
     }
-    let hash_value = match cookies.get_private("user_hash") {
-        None => return None,
-        Some(cookie) => cookie.value().to_owned()
-    };
-    for entry in get_users() {
-        if hash_value == entry.0 && username == entry.1 { return Some(username) }
+    // Requests with an API token are limited to the scopes and the path prefix of the token
+    if let Some(token) = &auth.bearer {
+        return if token.user == username && token.allows(scope, path) { Some(username) } else { None }
+    }
+    let account = auth.account()?;
+    if account.name == username { return Some(username) }
+    // Files and directories shared by other users are authorized through the share, its root takes the place of the home directory
+    if let Some(root) = internal::shared_root(&account.name, path, scope) { return Some(root) }
+    // Group folders are authorized by their access lists, their root takes the place of the home directory
+    if username == groups::SHARED_DIRECTORY {
+        let folder = path.iter().filter(|item| *item != OsStr::new(&MAIN_SEPARATOR_STR)).nth(1)?
+            .to_str().expect("Invalid path encoding (expected UTF-8)");
+        return if groups::allows(&account.name, folder, scope) { Some(format!("{}/{}", username, folder)) } else { None }
+    }
+    // Administrators may browse the directories of all users, which is recorded in the audit log
+    if account.role == Role::Admin && scope == Scope::Read && get_user(&username).is_some() {
+        admin::log_action(auth, "browse", path.to_str().expect("Invalid path encoding (expected UTF-8)"));
+        return Some(username)
     }
     None
 }
 
-fn check_path(path: &PathBuf) -> (bool, bool) {
-    let full_path = STORAGE.join(&path);
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("System time is before the UNIX epoch").as_secs()
+}
+
+// Write to a temporary file first and replace the target afterwards, so it is never left incomplete
+fn write_file_atomic(path: &str, content: &str) {
+    let temp_path = format!("{}.tmp", path);
+    let mut file = File::create(&temp_path).expect("Cannot create temporary file");
+    file.write_all(content.as_bytes()).expect("Cannot write temporary file");
+    file.sync_all().expect("Cannot write temporary file");
+    rename(&temp_path, path).expect("Cannot replace file");
+}
+
+fn session_user(cookies: &CookieJar<'_>) -> Option<String> {
+    session::get_session(cookies.get_private("session_id")?.value())
+}
+
+// Map the subject or a subject alternative name of a verified client certificate to an existing user
+fn certificate_user(certificate: &Certificate<'_>, field: &str) -> Option<String> {
+    let mut names: Vec<&str> = Vec::new();
+    match field {
+        "common_name" => names.extend(certificate.subject().common_names()),
+        "email" => names.extend(certificate.subject().emails()),
+        _ => {}
+    }
+    if let Ok(Some(extension)) = certificate.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match (field, name) {
+                ("email", GeneralName::RFC822Name(value)) | ("dns", GeneralName::DNSName(value)) => names.push(value),
+                _ => {}
+            }
+        }
+    }
+    let users = get_users();
+    names.into_iter().find(|name| users.iter().any(|user| user.name == *name)).map(str::to_owned)
+}
+
+fn start_session(cookies: &CookieJar<'_>, username: &str) -> Redirect {
+    let mut cookie = Cookie::new("session_id", session::create_session(username));
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_expires(None);
+    cookies.add_private(cookie);
+    // Every session gets its own CSRF token
+    add_csrf_cookie(cookies);
+    Redirect::to(uri!(list_directory(username)))
+}
+
+fn add_csrf_cookie(cookies: &CookieJar<'_>) -> String {
+    let token = session::random_token();
+    let mut cookie = Cookie::new("csrf_token", token.to_owned());
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_expires(None);
+    cookies.add_private(cookie);
+    token
+}
+
+// Reject requests with a missing or wrong CSRF token, which is recorded in the audit log
+fn check_csrf(auth: &Auth<'_>, token: Option<&str>, action: &str, path: &Path) -> Result<(), Forbidden<RawHtml<String>>> {
+    if auth.verify_csrf(token.unwrap_or_default()) { return Ok(()) }
+    audit::record(auth.actor().as_deref(), auth.ip, action, path, "csrf_rejected");
+    Err(Forbidden(RawHtml(auth.page(&CSRF_INVALID))))
+}
+
+// Returns the language and theme chosen by the user or the server defaults
+fn user_settings(username: &str) -> Settings {
+    let mut settings = Settings::default();
+    let Some(user) = get_user(username) else { return settings };
+    if !user.language.is_empty() { settings.language = user.language; }
+    match user.theme.as_str() {
+        "dark" => settings.theme = Theme {
+            background: "#222222", foreground: "#DDDDDD", accent_background: "#1E5014", accent_foreground: "#DDDDDD",
+            shadows: "#000000", errors: "#730D00", input: "#3C3C3C",
+        },
+        "light" => settings.theme = Theme {
+            background: "#DDDDDD", foreground: "#222222", accent_background: "#88DD3A", accent_foreground: "#222222",
+            shadows: "#808080", errors: "#FF4D33", input: "#ECECEC",
+        },
+        _ => ()
+    }
+    settings
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = path.read_dir() else { return 0 };
+    entries.flatten().map(|item| match item.metadata() {
+        Ok(metadata) if metadata.is_dir() => directory_size(&item.path()),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0
+    }).sum()
+}
+
+fn check_path(path: &Path) -> (bool, bool) {
+    let full_path = STORAGE.join(path);
     (full_path.is_file(), full_path.is_dir())
 }
 
@@ -188,10 +555,10 @@ fn sanitize_string(input: &str) -> String {
         }
     }
     // Remove leading and trailing whitespaces or dots
-    while temp_string.starts_with(" ") {
+    while temp_string.starts_with(' ') {
         temp_string = temp_string[1..].to_owned();
     }
-    while temp_string.ends_with(" ") || temp_string.ends_with(".") {
+    while temp_string.ends_with(' ') || temp_string.ends_with('.') {
         temp_string = temp_string[..temp_string.len() - 1].to_owned();
     }
     // Restrict the string length as specified in the config
@@ -202,42 +569,111 @@ fn sanitize_string(input: &str) -> String {
 }
 
 #[get("/")]
-fn home() -> RawHtml<String> { RawHtml(HOME.to_owned()) }
+fn home(auth: Auth<'_>) -> Either<Redirect, RawHtml<String>> {
+    match auth.user() {
+        Some(username) => Either::Left(Redirect::to(uri!(list_directory(&username)))),
+        None => Either::Right(RawHtml(auth.page(&HOME)))
+    }
+}
 
 #[get("/favicon.ico")]
 async fn favicon() -> Option<NamedFile> { NamedFile::open(Path::new("icons/favicon.ico")).await.ok() }
 
 #[post("/", data = "<data>")]
-fn login(cookies: &CookieJar<'_>, data: Option<Form<LoginData>>) -> Either<Redirect, RawHtml<String>> {
-    match data {
-        None => Either::Right(RawHtml(LOGIN_FAILED.to_owned())),
+async fn login(auth: Auth<'_>, ip: Option<IpAddr>, data: Option<Form<LoginData>>) -> Either<Redirect, RawHtml<String>> {
+    let cookies = auth.cookies;
+    let name = data.as_ref().map(|login_data| login_data.name.to_owned());
+    let response = match data {
+        None => Either::Right(RawHtml(auth.page(&LOGIN_FAILED))),
+        // Passwords are not accepted at all or only together with the matching client certificate
+        Some(_) if CONFIG.cert_login == "cert_only" => Either::Right(RawHtml(auth.page(&LOGIN_FAILED))),
+        Some(login_data) if CONFIG.cert_login == "cert_and_password" && auth.cert_user.as_ref() != Some(&login_data.name) => {
+            Either::Right(RawHtml(auth.page(&LOGIN_FAILED)))
+        }
         Some(login_data) => {
-            let hash_value = Sha384::digest(format!("{}{}", login_data.password, login_data.name));
-            for entry in get_users() {
-                if format!("{:x}", hash_value) == entry.0 {
-                    let mut cookie = Cookie::new("user_hash", format!("{:x}", hash_value));
-                    cookie.set_http_only(true);
-                    cookie.set_secure(true);
-                    cookie.set_expires(None);
-                    cookies.add_private(cookie);
-                    return Either::Left(Redirect::to(uri!(list_directory(&login_data.name))))
+            // Refuse any further attempts while the client or the account is locked
+            if throttle::is_locked(ip, &login_data.name) {
+                audit::record(Some(&login_data.name), ip, "login", Path::new(""), "locked");
+                return Either::Right(RawHtml(auth.page(&LOGIN_LOCKED)))
+            }
+            let user = match backend::authenticate(&login_data.name, &login_data.password).await {
+                true => get_user(&login_data.name),
+                false => None
+            };
+            match user {
+                // The result of a successful password check is recorded when the login is finished
+                Some(user) => return finish_login(cookies, ip, &user),
+                None => {
+                    throttle::register_failure(ip, &login_data.name);
+                    Either::Right(RawHtml(auth.page(&LOGIN_FAILED)))
                 }
             }
-            Either::Right(RawHtml(LOGIN_FAILED.to_owned()))
         }
+    };
+    audit::record(name.as_deref(), ip, "login", Path::new(""), "failure");
+    response
+}
+
+// Start the session of a user whose first factor has been verified or ask for the second factor
+fn finish_login(cookies: &CookieJar<'_>, ip: Option<IpAddr>, user: &User) -> Either<Redirect, RawHtml<String>> {
+    if !user.enabled {
+        audit::record(Some(&user.name), ip, "login", Path::new(""), "disabled");
+        return Either::Right(RawHtml(LOGIN_FAILED.to_owned()))
+    }
+    // Ask for the second factor if it is set up or has to be set up now
+    if !user.totp_secret.is_empty() {
+        audit::record(Some(&user.name), ip, "login", Path::new(""), "second_factor");
+        account::start_second_factor(cookies, &user.name);
+        return Either::Right(RawHtml(TOTP.to_owned()))
+    } else if user.totp_required {
+        audit::record(Some(&user.name), ip, "login", Path::new(""), "second_factor");
+        account::start_second_factor(cookies, &user.name);
+        return Either::Left(Redirect::to(uri!(account::totp_setup)))
     }
+    audit::record(Some(&user.name), ip, "login", Path::new(""), "success");
+    throttle::register_success(&user.name);
+    Either::Left(start_session(cookies, &user.name))
+}
+
+#[post("/logout", data = "<data>")]
+fn logout(auth: Auth<'_>, data: Option<Form<CsrfToken>>) -> Result<Redirect, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "logout", Path::new(""))?;
+    let cookies = auth.cookies;
+    if let Some(cookie) = cookies.get_private("session_id") {
+        session::remove_session(cookie.value());
+    }
+    cookies.remove_private("session_id");
+    cookies.remove_private("csrf_token");
+    Ok(Redirect::to(uri!(home)))
+}
+
+#[post("/logout_all", data = "<data>")]
+fn logout_all(auth: Auth<'_>, data: Option<Form<CsrfToken>>) -> Result<Redirect, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "logout_all", Path::new(""))?;
+    let cookies = auth.cookies;
+    if let Some(username) = cookies.get_private("session_id").and_then(|cookie| session::get_session(cookie.value())) {
+        session::remove_user_sessions(&username);
+    }
+    cookies.remove_private("session_id");
+    cookies.remove_private("csrf_token");
+    Ok(Redirect::to(uri!(home)))
 }
 
 #[get("/files/<path..>")]
-fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String> {
+fn list_directory(auth: Auth<'_>, path: DotPathBuf) -> RawHtml<String> {
     let path = path.0;
-    if let Some(username) = check_login(cookies, &path) {
+    if let Some(username) = check_login(&auth, &path, Scope::Read) {
         if check_path(&path).1 {
+            // Group folders and other homes are shown with the settings and home directory of the logged-in user
+            let home = auth.user().unwrap_or(username.to_owned());
+            let settings = user_settings(&home);
+            let (language, theme) = (&settings.language, &settings.theme);
 
             // Determine the path string and the parent directory
             let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
             let parent_path = match path.parent() {
                 None => String::new(),
+                Some(_) if path == Path::new(&username) && username != home => "files/".to_owned() + &home,
                 Some(parent) => match parent.to_str().expect("Invalid path encoding (expected UTF-8)") {
                     "" => String::new(),
                     path_string => "files/".to_owned() + path_string
@@ -247,22 +683,20 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
             // Create the top navigation bar
             let mut current_link = "/files".to_owned();
             let mut top_bar = String::new();
-            for part in path_string.split("/") {
+            for part in path_string.split('/') {
                 current_link.push_str(format!("/{0}", part).as_str());
-                top_bar.push_str(format!("/ <a href=\"{0}\" style=\"color:{1};\">{2}</a> ", current_link, CONFIG.accent_foreground, part).as_str());
+                top_bar.push_str(format!("/ <a href=\"{0}\" style=\"color:{1};\">{2}</a> ", current_link, theme.accent_foreground, part).as_str());
             }
 
             // Get and sort the files and subdirectories from the given path
             let mut files = Vec::new();
             let mut directories = Vec::new();
-            for item in STORAGE.join(&path).read_dir().expect("Cannot read directory contents") {
-                if let Ok(item) = item {
-                    match item.path().file_name() {
-                        None => {},
-                        Some(name) => {
-                            if item.path().is_file() { files.push(name.to_owned()) }
-                            else if item.path().is_dir() { directories.push(name.to_owned()) }
-                        }
+            for item in STORAGE.join(&path).read_dir().expect("Cannot read directory contents").flatten() {
+                match item.path().file_name() {
+                    None => {},
+                    Some(name) => {
+                        if item.path().is_file() { files.push(name.to_owned()) }
+                        else if item.path().is_dir() { directories.push(name.to_owned()) }
                     }
                 }
             }
@@ -270,32 +704,104 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
             directories.sort_by_key(|k| k.to_ascii_lowercase());
 
             // Configure translatable messages and texts
-            let mut del_dir = "The directory will be deleted permanently. Continue?";
-            let mut del_file = "The file will be deleted permanently. Continue?";
-            let mut menu_content: (&str, &str, &str, &str, &str, &str, &str, &str, &str, &str, &str, &str, &str) =
+            let mut del_dir = "The directory will be moved to the trash. Continue?";
+            let mut del_file = "The file will be moved to the trash. Continue?";
+            let mut shared = "Shared";
+            let mut shared_with_me = "Shared with me";
+            let mut share_text = "Share";
+            let mut move_text = "Move";
+            let mut copy_text = "Copy";
+            let mut folder_upload = "Upload a whole folder";
+            let mut upload_text = ("Uploading", "uploaded", "skipped, the name already exists", "failed");
+            let mut conflict_text = [("reject", "Skip existing names"), ("overwrite", "Overwrite existing files"),
+                                     ("keep_both", "Keep both (add a number)"), ("newer", "Overwrite older files")];
+            let mut logout_text = ("Log out", "Log out all devices", "Account", "Administration", "My shares", "Trash");
+            let mut menu_content: MenuContent =
                 ("Files from", "Home directory", "Parent directory", "Download directory (ZIP)", "Create directory",
-                 "Unpack ZIP file", "Upload file", "directory_name", "file_name.zip", "folder(s)", "file(s)", "version", "disk usage");
-            if CONFIG.language == "de" {
-                del_dir = "Der Ordner wird endgültig gelöscht. Fortfahren?";
-                del_file = "Die Datei wird endgültig gelöscht. Fortfahren?";
+                 "Unpack ZIP file", "Upload files", "directory_name", "file_name.zip", "folder(s)", "file(s)", "version", "disk usage");
+            if language == "de" {
+                del_dir = "Der Ordner wird in den Papierkorb verschoben. Fortfahren?";
+                del_file = "Die Datei wird in den Papierkorb verschoben. Fortfahren?";
+                shared = "Geteilt";
+                shared_with_me = "Mit mir geteilt";
+                share_text = "Teilen";
+                move_text = "Verschieben";
+                copy_text = "Kopieren";
+                folder_upload = "Einen ganzen Ordner hochladen";
+                upload_text = ("Lade hoch", "hochgeladen", "übersprungen, der Name existiert bereits", "fehlgeschlagen");
+                conflict_text = [("reject", "Vorhandene Namen überspringen"), ("overwrite", "Vorhandene Dateien überschreiben"),
+                                 ("keep_both", "Beide behalten (Nummer anhängen)"), ("newer", "Ältere Dateien überschreiben")];
+                logout_text = ("Abmelden", "Alle Geräte abmelden", "Konto", "Administration", "Meine Freigaben", "Papierkorb");
                 menu_content = ("Dateien von", "Hauptverzeichnis", "Übergeordnetes Verzeichnis", "Ordner herunterladen (ZIP)", "Ordner erstellen",
-                                "ZIP-Datei entpacken", "Datei hochladen", "Ordnername", "Dateiname.zip", "Ordner", "Datei(en)", "Version", "Festplattennutzung");
+                                "ZIP-Datei entpacken", "Dateien hochladen", "Ordnername", "Dateiname.zip", "Ordner", "Datei(en)", "Version", "Festplattennutzung");
             }
 
-            // Create the directory list
+            // The conflict policy of uploads is preselected with the configured default. Only the resumable upload sends
+            // the modification times, so overwriting older files is hidden until the script enables it (the plain form skips instead)
+            let default_conflict = UploadConflict::parse("");
+            let conflict_options: String = conflict_text.iter().map(|(value, label)| {
+                let conflict = UploadConflict::parse(value);
+                let attributes = match conflict {
+                    UploadConflict::Newer if default_conflict == conflict => " disabled hidden data-default",
+                    UploadConflict::Newer => " disabled hidden",
+                    _ if default_conflict == conflict => " selected",
+                    UploadConflict::Reject if default_conflict == UploadConflict::Newer => " selected",
+                    _ => ""
+                };
+                format!("<option value=\"{}\"{}>{}</option>", value, attributes, label)
+            }).collect();
+
+            // The link to the administration area is only shown to administrators
+            let admin_button = if auth.account().is_some_and(|user| user.role == Role::Admin) { "inline-block" } else { "none" };
+
+            // The controls that change files are hidden from read-only accounts and where the access is limited to reading
+            let read_only = auth.is_read_only();
+            let write_controls = if read_only || check_login(&auth, &path, Scope::Upload).is_none() { "none" } else { "block" };
+            let entry_controls = if read_only || check_login(&auth, &path, Scope::Delete).is_none() { "none" } else { "inline-block" };
+            let shares_button = if read_only { "none" } else { "inline-block" };
+            // Copies only need read access to the source, the destination is chosen on the copy page
+            let copy_controls = if read_only { "none" } else { "inline-block" };
+            let csrf_token = auth.csrf_token();
+
+            // Create the directory list, the home directory starts with the group folders of the user
             let mut dir_list = String::new();
+            if path == Path::new(&home) {
+                dir_list.push_str(format!(
+                    "<div style=\"display:inline-block; padding:8px; border-bottom-style:solid; border-width:1px; border-color:{1}\"> \
+                        <a href=\"/shared_with_me\" style=\"text-decoration:none; display:inline-block\"> \
+                            <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{0}; vertical-align:middle; width: 532px\"> \
+                                <img src=\"/icons/folder_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/> \
+                                {2} </div></a></div><br>",
+                    theme.foreground, theme.shadows, shared_with_me
+                ).as_str());
+                for folder in groups::user_folders(&home) {
+                    dir_list.push_str(format!(
+                        "<div style=\"display:inline-block; padding:8px; border-bottom-style:solid; border-width:1px; border-color:{3}\"> \
+                            <a href=\"/files/{0}/{1}\" style=\"text-decoration:none; display:inline-block\"> \
+                                <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{2}; vertical-align:middle; width: 532px\"> \
+                                    <img src=\"/icons/folder_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/> \
+                                    {4}: {1} </div></a></div><br>",
+                        groups::SHARED_DIRECTORY, folder, theme.foreground, theme.shadows, shared
+                    ).as_str())
+                }
+            }
             for dir in &directories {
                 dir_list.push_str(format!(
                     "<div style=\"display:inline-block; padding:8px; border-bottom-style:solid; border-width:1px; border-color:{3}\"> \
+                        <input name=\"items\" value=\"{1}\" type=\"checkbox\" form=\"selection\" style=\"vertical-align:middle; margin-right:8px\" /> \
                         <a href=\"/files/{0}/{1}\" style=\"text-decoration:none; display:inline-block\"> \
-                            <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{2}; vertical-align:middle; width: 500px\"> \
+                            <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{2}; vertical-align:middle; width: 476px\"> \
                                 <img src=\"/icons/folder_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/> \
                                 {1} </div></a> \
-                        <a href=\"/delete_dir/{0}/{1}\" onclick=\"return confirm(\'{4}\');\" style=\"text-decoration:none; display:inline-block\"> \
+                        <a href=\"/share/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{5}; width:48px\">{6}</a> \
+                        <a href=\"/copy/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{10}; width:56px\">{9}</a> \
+                        <a href=\"/move/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{5}; width:72px\">{8}</a> \
+                        <form action=\"/delete_dir/{0}/{1}\" method=\"post\" onsubmit=\"return confirm(\'{4}\');\" style=\"margin:0px; display:{5}\"> \
+                            <input name=\"csrf_token\" type=\"hidden\" value=\"{7}\" /> \
                             <div style=\"vertical-align:middle; width:32px\"> \
-                                <img src=\"/icons/trash_16x16.png\" style=\"vertical-align:middle\"/> \
-                    </div></a></div><br>",
-                    path_string, dir.to_str().expect("Invalid path encoding (expected UTF-8)"), CONFIG.foreground, CONFIG.shadows, del_dir
+                                <input type=\"image\" src=\"/icons/trash_16x16.png\" alt=\"\" style=\"vertical-align:middle\"/> \
+                    </div></form></div><br>",
+                    path_string, dir.to_str().expect("Invalid path encoding (expected UTF-8)"), theme.foreground, theme.shadows, del_dir, entry_controls, share_text, csrf_token, move_text, copy_text, copy_controls
                 ).as_str())
             }
 
@@ -318,18 +824,27 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                 };
                 file_list.push_str(format!(
                     "<div style=\"display:inline-block; padding:8px; border-bottom-style:solid; border-width:1px; border-color:{3}\"> \
+                        <input name=\"items\" value=\"{1}\" type=\"checkbox\" form=\"selection\" style=\"vertical-align:middle; margin-right:8px\" /> \
                         <a href=\"/download/{0}/{1}\" style=\"text-decoration:none; display:inline-block\"> \
-                            <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{2}; vertical-align:middle; width: 500px\"> \
+                            <div style=\"font-family:sans-serif; font-size:14px; text-align:left; color:{2}; vertical-align:middle; width: 476px\"> \
                                 <img src=\"/icons/{5}_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/> \
                                 {1} </div></a> \
-                        <a href=\"/delete_file/{0}/{1}\" onclick=\"return confirm(\'{4}\');\" style=\"text-decoration:none; display:inline-block\"> \
+                        <a href=\"/share/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{6}; width:48px\">{7}</a> \
+                        <a href=\"/copy/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{11}; width:56px\">{10}</a> \
+                        <a href=\"/move/{0}/{1}\" style=\"font-family:sans-serif; font-size:12px; color:{2}; display:{6}; width:72px\">{9}</a> \
+                        <form action=\"/delete_file/{0}/{1}\" method=\"post\" onsubmit=\"return confirm(\'{4}\');\" style=\"margin:0px; display:{6}\"> \
+                            <input name=\"csrf_token\" type=\"hidden\" value=\"{8}\" /> \
                             <div style=\"vertical-align:middle; width:32px\"> \
-                                <img src=\"/icons/trash_16x16.png\" style=\"vertical-align:middle\"/> \
-                    </div></a></div><br>",
-                    path_string, file.to_str().expect("Invalid path encoding (expected UTF-8)"), CONFIG.foreground, CONFIG.shadows, del_file, file_type
+                                <input type=\"image\" src=\"/icons/trash_16x16.png\" alt=\"\" style=\"vertical-align:middle\"/> \
+                    </div></form></div><br>",
+                    path_string, file.to_str().expect("Invalid path encoding (expected UTF-8)"), theme.foreground, theme.shadows, del_file, file_type, entry_controls, share_text, csrf_token, move_text, copy_text, copy_controls
                 ).as_str())
             }
 
+            // The toolbar for the selected entries is only needed if there are any
+            let toolbar = if directories.is_empty() && files.is_empty() { String::new() }
+                          else { bulk::toolbar(language, path_string, &csrf_token, entry_controls, copy_controls) };
+
             // Get the disk usage of the storage filesystem (Linux only)
             let storage_cmd = Command::new("df")
                 .arg(STORAGE.join(&path))
@@ -353,14 +868,14 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                     <meta charset=\"utf-8\"> \
                     <title>{8} {20}</title> \
                 </head> \
-                <body style=\"background-color:{1}; margin-top:0px\"> \
+                <body style=\"background-color:{1}; margin-top:0px; {49}\"> \
                 <div style=\"background-color:{1}; position:sticky; top:0px; width:100%; padding-top:16px; padding-bottom:8px\"> \
                     <h1 style=\"font-family:sans-serif; font-size:24px; text-align:center; font-weight:bold; color:{2}; background-color:{3}; \
                             border-radius:10px; margin:16px; margin-top:0px; margin-bottom:8px; padding:8px; box-shadow:2px 2px 4px {4}\"> \
                         {26} \
                     </h1> \
                     <div style=\"text-align:center\"> \
-                        <form action=\"/files/{20}\" style=\"margin:8px; display:inline-block\"> \
+                        <form action=\"/files/{37}\" style=\"margin:8px; display:inline-block\"> \
                             <input value=\"{9}\" type=\"submit\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; \
                             color:{2}; background:{3} url(\'/icons/home_16x16.png\') no-repeat scroll 10px; \
                             border-radius:4px; border-style:hidden; padding:8px; padding-left:36px; cursor:pointer; box-shadow:2px 2px 4px {4}\" /> \
@@ -376,8 +891,9 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                             border-radius:4px; border-style:hidden; padding:8px; padding-left:36px; cursor:pointer; box-shadow:2px 2px 4px {4}\" /> \
                         </form> \
                     </div> \
-                    <div style=\"text-align:center\"> \
+                    <div style=\"text-align:center; display:{36}\"> \
                         <form action=\"/new_dir/{28}\" method=\"post\" style=\"margin:8px; display:inline-block\"> \
+                            <input name=\"csrf_token\" type=\"hidden\" value=\"{40}\" /> \
                             <input value=\"{12}\" type=\"submit\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; \
                             color:{2}; background:{3} url(\'/icons/folder_16x16.png\') no-repeat scroll 10px; \
                             border-radius:4px; border-style:hidden; padding:8px; padding-left:36px; cursor:pointer; box-shadow:2px 2px 4px {4}\" /> \
@@ -387,6 +903,7 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                             placeholder=\"{15}\" required /> \
                         </form> \
                         <form action=\"/unpack/{28}\" method=\"post\" style=\"margin:8px; display:inline-block\"> \
+                            <input name=\"csrf_token\" type=\"hidden\" value=\"{40}\" /> \
                             <input value=\"{13}\" type=\"submit\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; \
                             color:{2}; background:{3} url(\'/icons/archive_16x16.png\') no-repeat scroll 10px; \
                             border-radius:4px; border-style:hidden; padding:8px; padding-left:36px; cursor:pointer; box-shadow:2px 2px 4px {4}\" /> \
@@ -395,18 +912,28 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                             color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:8px; margin-top:8px\" \
                             placeholder=\"{16}\" required /> \
                         </form> \
-                        <form action=\"/upload/{28}\" method=\"post\" style=\"margin:8px; display:inline-block\" enctype=\"multipart/form-data\"> \
+                        <form id=\"upload\" action=\"/upload/{28}\" method=\"post\" style=\"margin:8px; display:inline-block\" enctype=\"multipart/form-data\" \
+                        data-tus=\"/tus/{28}\" data-uploading=\"{44}\" data-uploaded=\"{45}\" data-skipped=\"{46}\" data-failed=\"{47}\"> \
+                            <input name=\"csrf_token\" type=\"hidden\" value=\"{40}\" /> \
                             <input value=\"{14}\" type=\"submit\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; \
                             color:{2}; background:{3} url(\'/icons/upload_16x16.png\') no-repeat scroll 10px; \
                             border-radius:4px; border-style:hidden; padding:8px; padding-left:36px; cursor:pointer; box-shadow:2px 2px 4px {4}\" /> \
                             <br> \
                             <input name=\"file\" type=\"file\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:240px; \
-                            color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:5px; margin-top:8px\" required /> \
+                            color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:5px; margin-top:8px\" multiple /> \
+                            <br> \
+                            <select name=\"conflict\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:250px; \
+                            color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:8px; margin-top:8px\">{48}</select> \
+                            <br> \
+                            <p style=\"font-family:sans-serif; font-size:12px; text-align:left; color:{6}; margin:8px 0px 0px 4px\">{43}:</p> \
+                            <input name=\"file\" type=\"file\" style=\"font-family:sans-serif; font-size:14px; text-align:left; width:240px; \
+                            color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:5px; margin-top:8px\" webkitdirectory /> \
+                            <div id=\"upload_status\" style=\"font-family:sans-serif; font-size:12px; text-align:left; color:{6}; width:250px; margin-top:8px\"></div> \
                         </form> \
                     </div> \
                 </div> \
                 <div style=\"text-align:center\"> \
-                    {21}<br><br> \
+                    {42}{21}<br><br> \
                     {22}<br><br> \
                 </div> \
                 <div style=\"margin:auto; border-radius:4px; border-style:hidden; width:270px; height:6px; \
@@ -414,75 +941,135 @@ fn list_directory(cookies: &CookieJar<'_>, path: DotPathBuf) -> RawHtml<String>
                 <p style=\"margin:auto; font-family:sans-serif; font-size:14px; text-align:center; color:{6}\"> \
                     {23} {17}, {24} {18} &ensp; | &ensp; {29}% {30} \
                 </p><br><br> \
+                <div style=\"text-align:center\"> \
+                    <form action=\"/account\" style=\"margin:4px; display:inline-block\"> \
+                        <input value=\"{33}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                    <form action=\"/shares\" style=\"margin:4px; display:{39}\"> \
+                        <input value=\"{38}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                    <form action=\"/trash\" style=\"margin:4px; display:{39}\"> \
+                        <input value=\"{41}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                    <form action=\"/admin\" style=\"margin:4px; display:{35}\"> \
+                        <input value=\"{34}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                    <form action=\"/logout\" method=\"post\" style=\"margin:4px; display:inline-block\"> \
+                        <input name=\"csrf_token\" type=\"hidden\" value=\"{40}\" /> \
+                        <input value=\"{31}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                    <form action=\"/logout_all\" method=\"post\" style=\"margin:4px; display:inline-block\"> \
+                        <input name=\"csrf_token\" type=\"hidden\" value=\"{40}\" /> \
+                        <input value=\"{32}\" type=\"submit\" style=\"font-family:sans-serif; font-size:12px; text-align:center; width:150px; \
+                        color:{6}; background-color:{7}; border-radius:4px; border-style:hidden; padding:6px; cursor:pointer\" /> \
+                    </form> \
+                </div><br> \
                 <p style=\"margin:auto; font-family:sans-serif; font-size:12px; text-align:center; color:{6}; \
                 border-top-style:solid; border-color:{4}; border-width:1px; width:250px; padding:10px\"> \
                     - {5} rNAS {19} {25} - \
                 </p> \
+                <script src=\"/tus.js\"></script> \
                 </body> \
                 </html>",
-                CONFIG.language, CONFIG.background, CONFIG.accent_foreground, CONFIG.accent_background, CONFIG.shadows, CONFIG.owner, CONFIG.foreground, CONFIG.input,
+                language, theme.background, theme.accent_foreground, theme.accent_background, theme.shadows, CONFIG.owner, theme.foreground, theme.input,
                 menu_content.0, menu_content.1, menu_content.2, menu_content.3, menu_content.4, menu_content.5, menu_content.6, menu_content.7, menu_content.8,
                 menu_content.9, menu_content.10, menu_content.11, username, dir_list, file_list, directories.len(), files.len(), VERSION,
-                top_bar, parent_path, path_string, percent, menu_content.12
+                top_bar, parent_path, path_string, percent, menu_content.12, logout_text.0, logout_text.1, logout_text.2,
+                logout_text.3, admin_button, write_controls, home, logout_text.4, shares_button, csrf_token, logout_text.5, toolbar, folder_upload,
+                upload_text.0, upload_text.1, upload_text.2, upload_text.3, conflict_options, html::theme_variables(theme)
             );
 
             RawHtml(directory_view)
         }
-        else { RawHtml(NO_DIRECTORY.to_owned()) }
+        else { RawHtml(auth.page(&NO_DIRECTORY)) }
     }
-    else { RawHtml(ACCESS_DENIED.to_owned()) }
+    else { RawHtml(auth.page(&ACCESS_DENIED)) }
 }
 
 #[get("/download/<path..>")]
-async fn download_file(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
-    let path = path.0;
-    if let Some(_username) = check_login(cookies, &path) {
-        if check_path(&path).0 {
-            Either::Left(NamedFile::open(STORAGE.join(&path)).await.ok())
+async fn download_file(auth: Auth<'_>, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
+    let response = download_file_response(&auth, &path.0).await;
+    audit::record_response(&auth, "download", &path.0, &response);
+    auth.localize(response)
+}
+
+async fn download_file_response(auth: &Auth<'_>, path: &Path) -> Either<Option<NamedFile>, RawHtml<String>> {
+    if let Some(_username) = check_login(auth, path, Scope::Read) {
+        if check_path(path).0 {
+            Either::Left(NamedFile::open(STORAGE.join(path)).await.ok())
         }
         else { Either::Right(RawHtml(NO_FILE.to_owned())) }
     }
     else { Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
 }
 
+// Pack a directory into a ZIP file inside the tmp directory and return the path of the archive
+fn zip_directory(path: &Path) -> PathBuf {
+    let hash_value = format!("{:x}", Md5::digest(path.to_str().expect("Invalid path encoding (expected UTF-8)")));
+    let directory_name = path
+        .file_name().expect("Cannot extract directory name")
+        .to_str().expect("Invalid directory name encoding (expected UTF-8)");
+    let parent_path = path.parent().unwrap_or(Path::new(""));
+    zip_entries(parent_path, &[directory_name], &(directory_name.to_owned() + "-" + &hash_value))
+}
+
+// Pack the given entries of a directory into a ZIP file inside the tmp directory
+fn zip_entries(path: &Path, names: &[&str], archive_name: &str) -> PathBuf {
+    let temp_file_path = STORAGE.join("tmp").join(archive_name.to_owned() + ".zip");
+    if temp_file_path.is_file() { remove_file(&temp_file_path).expect("Cannot delete temporary file (permission error)"); }
+    // The following zip command syntax can only be used on Linux, for Windows a check with cfg!(target_os = "windows")
+    // and an equivalent CMD / Powershell command is necessary
+    let mut zip_command = Command::new("zip");
+    zip_command.arg("-q")
+               .arg("-r")
+               .arg(temp_file_path.to_str().expect("Invalid path encoding (expected UTF-8)"))
+               // The prefix keeps names with a leading dash from being read as options
+               .args(names.iter().map(|name| format!(".{}{}", MAIN_SEPARATOR_STR, name)))
+               .current_dir(STORAGE.join(path).to_str().expect("Invalid path encoding (expected UTF-8)"));
+    zip_command.status().expect("Cannot execute zip command");
+    temp_file_path
+}
+
 #[get("/zip/<path..>")]
-async fn download_folder(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
-    let path = path.0;
-    if let Some(_username) = check_login(cookies, &path) {
-        if check_path(&path).1 {
-            let hash_value = format!("{:x}", Md5::digest(path.to_str().expect("Invalid path encoding (expected UTF-8)")));
-            let directory_name = path
-                .file_name().expect("Cannot extract directory name")
-                .to_str().expect("Invalid directory name encoding (expected UTF-8)");
-            let file_name = directory_name.to_owned() + "-" + &hash_value + ".zip";
-            let temp_file_path = STORAGE.join("tmp").join(&file_name);
-            if temp_file_path.is_file() { remove_file(&temp_file_path).expect("Cannot delete temporary file (permission error)"); }
-            // The following zip command syntax can only be used on Linux, for Windows a check with cfg!(target_os = "windows")
-            // and an equivalent CMD / Powershell command is necessary
-            let mut zip_command = Command::new("zip");
-            zip_command.arg("-q")
-                       .arg("-r")
-                       .arg(temp_file_path.to_str().expect("Invalid path encoding (expected UTF-8)"))
-                       .arg(directory_name);
-            if let Some(parent_path) = STORAGE.join(&path).parent() {
-                zip_command.current_dir(parent_path.to_str().expect("Invalid path encoding (expected UTF-8)"));
-            }
-            zip_command.status().expect("Cannot execute zip command");
-            Either::Left(NamedFile::open(&temp_file_path).await.ok())
+async fn download_folder(auth: Auth<'_>, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
+    let response = download_folder_response(&auth, &path.0).await;
+    audit::record_response(&auth, "zip", &path.0, &response);
+    auth.localize(response)
+}
+
+async fn download_folder_response(auth: &Auth<'_>, path: &Path) -> Either<Option<NamedFile>, RawHtml<String>> {
+    if let Some(_username) = check_login(auth, path, Scope::Read) {
+        if check_path(path).1 {
+            Either::Left(NamedFile::open(zip_directory(path)).await.ok())
         }
         else { Either::Right(RawHtml(NO_DIRECTORY.to_owned())) }
     }
     else { Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
 }
 
-#[get("/delete_dir/<path..>")]
-fn delete_dir(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Redirect, RawHtml<String>> {
-    let path = path.0;
-    if let Some(username) = check_login(cookies, &path) {
-        if check_path(&path).1 {
+#[post("/delete_dir/<path..>", data = "<data>")]
+fn delete_dir(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<CsrfToken>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "delete", &path.0)?;
+    let response = delete_dir_response(&auth, &path.0);
+    audit::record_response(&auth, "delete", &path.0, &response);
+    Ok(auth.localize(response))
+}
+
+fn delete_dir_response(auth: &Auth<'_>, path: &Path) -> Either<Redirect, RawHtml<String>> {
+    if let Some(username) = check_login(auth, path, Scope::Delete) {
+        if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+        if check_path(path).1 {
+            // Neither the home directory nor the root of a group folder can be deleted
+            if path == Path::new(&username) { return Either::Left(Redirect::to(uri!(list_directory(&username)))) }
             let parent_path = path.parent().expect("Cannot extract parent path");
-            if parent_path == Path::new("") { return Either::Left(Redirect::to(uri!(list_directory(&username)))) }
-            remove_dir_all(STORAGE.join(&path)).expect("Cannot delete directory (permission error)");
+            if let Err(error) = trash::move_to_trash(&auth.actor().expect("Cannot identify the deleting user"), path) {
+                return Either::Right(RawHtml(transfer::move_error(&error).to_owned()))
+            }
             Either::Left(Redirect::to(uri!(list_directory(parent_path.to_str().expect("Invalid path encoding (expected UTF-8)")))))
         }
         else { Either::Right(RawHtml(NO_DIRECTORY.to_owned())) }
@@ -490,14 +1077,23 @@ fn delete_dir(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Redirect, Raw
     else { Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
 }
 
-#[get("/delete_file/<path..>")]
-fn delete_file(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Redirect, RawHtml<String>> {
-    let path = path.0;
-    if let Some(username) = check_login(cookies, &path) {
-        if check_path(&path).0 {
+#[post("/delete_file/<path..>", data = "<data>")]
+fn delete_file(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<CsrfToken>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "delete", &path.0)?;
+    let response = delete_file_response(&auth, &path.0);
+    audit::record_response(&auth, "delete", &path.0, &response);
+    Ok(auth.localize(response))
+}
+
+fn delete_file_response(auth: &Auth<'_>, path: &Path) -> Either<Redirect, RawHtml<String>> {
+    if let Some(username) = check_login(auth, path, Scope::Delete) {
+        if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+        if check_path(path).0 {
             let parent_path = path.parent().expect("Cannot extract parent path");
             if parent_path == Path::new("") { return Either::Left(Redirect::to(uri!(list_directory(&username)))) }
-            remove_file(STORAGE.join(&path)).expect("Cannot delete file (permission error)");
+            if let Err(error) = trash::move_to_trash(&auth.actor().expect("Cannot identify the deleting user"), path) {
+                return Either::Right(RawHtml(transfer::move_error(&error).to_owned()))
+            }
             Either::Left(Redirect::to(uri!(list_directory(parent_path.to_str().expect("Invalid path encoding (expected UTF-8)")))))
         }
         else { Either::Right(RawHtml(NO_FILE.to_owned())) }
@@ -506,17 +1102,25 @@ fn delete_file(cookies: &CookieJar<'_>, path: DotPathBuf) -> Either<Redirect, Ra
 }
 
 #[post("/new_dir/<path..>", data = "<data>")]
-fn create_directory(cookies: &CookieJar<'_>, path: DotPathBuf, data: Option<Form<FolderName>>) -> Either<Redirect, RawHtml<String>> {
-    let path = path.0;
-    if let Some(username) = check_login(cookies, &path) {
-        if check_path(&path).1 {
+fn create_directory(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<FolderName>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let target = data.as_ref().map_or(path.0.to_owned(), |content| path.0.join(sanitize_string(&content.folder_name)));
+    check_csrf(&auth, data.as_ref().map(|content| content.csrf_token.as_str()), "mkdir", &target)?;
+    let response = create_directory_response(&auth, &path.0, data);
+    audit::record_response(&auth, "mkdir", &target, &response);
+    Ok(auth.localize(response))
+}
+
+fn create_directory_response(auth: &Auth<'_>, path: &Path, data: Option<Form<FolderName>>) -> Either<Redirect, RawHtml<String>> {
+    if let Some(username) = check_login(auth, path, Scope::Upload) {
+        if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+        if check_path(path).1 {
             match data {
                 None => Either::Left(Redirect::to(uri!(list_directory(&username)))),
                 Some(content) => {
                     // Remove some unwanted characters from the directory name (custom selection)
                     let mut new_dir = sanitize_string(&content.folder_name);
-                    if new_dir.len() == 0 { new_dir = "new_directory".to_owned(); }
-                    let new_path = STORAGE.join(&path).join(&new_dir);
+                    if new_dir.is_empty() { new_dir = "new_directory".to_owned(); }
+                    let new_path = STORAGE.join(path).join(&new_dir);
                     if !new_path.try_exists().expect("Cannot access files metadata (permission error)") {
                         create_dir(new_path).expect("Cannot create directory (permission error)");
                         Either::Left(Redirect::to(uri!(list_directory(path.to_str().expect("Invalid path encoding (expected UTF-8)")))))
@@ -531,10 +1135,18 @@ fn create_directory(cookies: &CookieJar<'_>, path: DotPathBuf, data: Option<Form
 }
 
 #[post("/unpack/<path..>", data = "<data>")]
-fn unpack_archive(cookies: &CookieJar<'_>, path: DotPathBuf, data: Option<Form<ArchiveName>>) -> Either<Redirect, RawHtml<String>> {
-    let path = path.0;
-    if let Some(username) = check_login(cookies, &path) {
-        if check_path(&path).1 {
+fn unpack_archive(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<ArchiveName>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let target = data.as_ref().map_or(path.0.to_owned(), |content| path.0.join(sanitize_string(&content.archive_name)));
+    check_csrf(&auth, data.as_ref().map(|content| content.csrf_token.as_str()), "unpack", &target)?;
+    let response = unpack_archive_response(&auth, &path.0, data);
+    audit::record_response(&auth, "unpack", &target, &response);
+    Ok(auth.localize(response))
+}
+
+fn unpack_archive_response(auth: &Auth<'_>, path: &Path, data: Option<Form<ArchiveName>>) -> Either<Redirect, RawHtml<String>> {
+    if let Some(username) = check_login(auth, path, Scope::Upload) {
+        if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+        if check_path(path).1 {
             match data {
                 None => Either::Left(Redirect::to(uri!(list_directory(&username)))),
                 Some(content) => {
@@ -543,7 +1155,7 @@ fn unpack_archive(cookies: &CookieJar<'_>, path: DotPathBuf, data: Option<Form<A
                     if new_dir.len() < 4 || new_dir[new_dir.len() - 4..].to_lowercase() != ".zip" {
                         return Either::Right(RawHtml(NO_FILE.to_owned()))
                     }
-                    let source_file = STORAGE.join(&path).join(&new_dir);
+                    let source_file = STORAGE.join(path).join(&new_dir);
                     let target_path = source_file.with_extension("");
                     if !source_file.is_file() {
                         Either::Right(RawHtml(NO_FILE.to_owned()))
@@ -570,65 +1182,532 @@ fn unpack_archive(cookies: &CookieJar<'_>, path: DotPathBuf, data: Option<Form<A
     else { Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
 }
 
-#[post("/upload/<path..>", format = "multipart/form-data", data = "<data>")]
-async fn upload_file(cookies: &CookieJar<'_>, path: DotPathBuf, mut data: Form<Upload<'_>>) -> Either<Redirect, RawHtml<String>> {
-    let path = path.0;
-    if let Some(_username) = check_login(cookies, &path) {
-        if check_path(&path).1 {
-            // Remove some unwanted characters from the file name (custom selection,
-            // automatic sanitation would remove dots and the file extension as well)
-            let mut file_name = match data.file.raw_name() {
-                None => return Either::Right(RawHtml(UPLOAD_ERROR.to_owned())),
-                Some(raw_name) => {
-                    sanitize_string(raw_name.dangerous_unsafe_unsanitized_raw().as_str())
+// Check if a file of the given size would exceed the quota of the owner of the home directory that contains the path
+fn exceeds_quota(path: &Path, size: u64) -> bool {
+    let Some(username) = path.iter().next().and_then(|item| item.to_str()) else { return false };
+    get_user(username).is_some_and(|user| user.quota > 0 && directory_size(&STORAGE.join(username)) + size > user.quota * 1024 * 1024)
+}
+
+// The sanitized components of the name of an uploaded file, folder uploads send the path inside the chosen folder
+fn path_components(name: &str) -> Vec<String> {
+    // Remove some unwanted characters from every component (custom selection,
+    // automatic sanitation would remove dots and the file extension as well)
+    name.split(['/', '\\'])
+        .map(|component| sanitize_string(component).trim_matches(' ').to_owned())
+        .filter(|component| !component.is_empty() && component != "." && component != "..")
+        .collect()
+}
+
+fn upload_components(file: &TempFile<'_>) -> Vec<String> {
+    file.raw_name().map_or_else(Vec::new, |raw_name| path_components(raw_name.dangerous_unsafe_unsanitized_raw().as_str()))
+}
+
+// Whether an upload may be stored at the target according to the conflict policy
+pub fn check_conflict(target: &Path, conflict: UploadConflict, modified: Option<SystemTime>) -> Result<(), &'static str> {
+    let existing = STORAGE.join(target);
+    if !existing.try_exists().expect("Cannot access files metadata (permission error)") { return Ok(()) }
+    match conflict {
+        UploadConflict::Reject => Err(IS_FILE.as_str()),
+        UploadConflict::KeepBoth => Ok(()),
+        // A directory is never replaced by a file
+        _ if existing.is_dir() => Err(IS_DIRECTORY.as_str()),
+        UploadConflict::Overwrite => Ok(()),
+        // Without a modification time from the client it is unknown which file is newer, so the existing one is kept
+        UploadConflict::Newer => {
+            let current = existing.metadata().and_then(|metadata| metadata.modified()).expect("Cannot access files metadata (permission error)");
+            if modified.is_some_and(|modified| modified > current) { Ok(()) } else { Err(IS_FILE.as_str()) }
+        }
+    }
+}
+
+// Move a staged upload into the directory according to the conflict policy, returns the path of the stored file
+pub async fn place_upload(staged: &Path, directory: &Path, name: &str, conflict: UploadConflict, modified: Option<SystemTime>) -> Result<PathBuf, &'static str> {
+    // A directory on another filesystem (e.g. a mounted group folder) gets a copy next to the target first, so only
+    // a rename is left for the upload lock and the copy does not block other uploads
+    let parent = STORAGE.join(directory);
+    let same_device = match (staged.metadata(), parent.metadata()) {
+        (Ok(staged), Ok(parent)) => staged.dev() == parent.dev(),
+        _ => return Err(UPLOAD_ERROR.as_str())
+    };
+    if same_device { return link_upload(staged, directory, name, conflict, modified) }
+    let local = parent.join(format!(".{}.upload", session::random_token()));
+    let (source, copy) = (staged.to_path_buf(), local.to_owned());
+    match rocket::tokio::task::spawn_blocking(move || transfer::move_path(&source, &copy)).await {
+        Ok(Ok(())) => {}
+        _ => return Err(UPLOAD_ERROR.as_str())
+    }
+    let result = link_upload(&local, directory, name, conflict, modified);
+    if result.is_err() { let _ = remove_file(&local); }
+    result
+}
+
+// Give the staged upload (on the same filesystem) its name under the upload lock. A new name is placed without replacing
+// an entry that a move, copy or unpack has created since the check
+fn link_upload(staged: &Path, directory: &Path, name: &str, conflict: UploadConflict, modified: Option<SystemTime>) -> Result<PathBuf, &'static str> {
+    let _guard = UPLOAD_LOCK.lock().expect("Upload lock is poisoned");
+    loop {
+        let mut target = directory.join(name);
+        check_conflict(&target, conflict, modified)?;
+        if conflict == UploadConflict::KeepBoth && STORAGE.join(&target).exists() {
+            target = directory.join(transfer::free_name(directory, name, true));
+        }
+        // Only an existing file that may be overwritten is replaced, a new name is never taken from another entry
+        let stored = STORAGE.join(&target);
+        let result = if stored.is_file() { rename(staged, &stored) } else { transfer::move_path(staged, &stored) };
+        match result {
+            Ok(()) => {
+                if let Some(modified) = modified {
+                    let _ = File::options().write(true).open(&stored).and_then(|file| file.set_modified(modified));
                 }
-            };
-            while file_name.starts_with(" ") {
-                file_name = file_name[1..].to_owned();
-            }
-            while file_name.ends_with(" ") {
-                file_name = file_name[..file_name.len() - 1].to_owned();
-            }
-            if file_name.len() == 0 {
-                return Either::Right(RawHtml(UPLOAD_ERROR.to_owned()))
-            }
-            else if STORAGE.join(&path).join(&file_name).try_exists().expect("Cannot access files metadata (permission error)") {
-                return Either::Right(RawHtml(IS_FILE.to_owned()))
+                return Ok(target)
             }
-            else {
-                // Try persisting the file to the given path
-                match data.file.persist_to(STORAGE.join(&path).join(&file_name)).await {
-                    Ok(_) => Either::Left(Redirect::to(uri!(list_directory(path.to_str().expect("Invalid path encoding (expected UTF-8)"))))),
-                    // If this failed, try to copy the temporary file to the given path
-                    // (e.g. the temp path is on a different logical device - see persist_to() docs)
-                    Err(_) => match data.file.move_copy_to(STORAGE.join(&path).join(&file_name)).await {
-                        Ok(_) => Either::Left(Redirect::to(uri!(list_directory(path.to_str().expect("Invalid path encoding (expected UTF-8)"))))),
-                        Err(_) => Either::Right(RawHtml(UPLOAD_ERROR.to_owned()))
-                    }
-                }
+            // The name has been taken in the meantime, so the conflict policy is applied again
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => return Err(UPLOAD_ERROR.as_str())
+        }
+    }
+}
+
+// Remove staged uploads that an aborted process has left behind, called by the cleaning thread
+fn purge_staged_uploads() {
+    let Ok(entries) = STORAGE.join("tmp").join(UPLOAD_DIRECTORY).read_dir() else { return };
+    let limit = SystemTime::now() - Duration::from_secs(CONFIG.upload_expiry);
+    for item in entries.flatten() {
+        if item.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified < limit) {
+            let _ = remove_file(item.path());
+        }
+    }
+}
+
+// Store an uploaded file in the given directory, the name (with an optional prefix) is sanitized and an existing name is handled
+// according to the conflict policy, returns the path of the stored file
+async fn store_upload(file: &mut TempFile<'_>, directory: &Path, prefix: &str, conflict: UploadConflict) -> Result<PathBuf, &'static str> {
+    // Only the file name itself is used, the directories of folder uploads are created by the caller
+    let file_name = match upload_components(file).pop() {
+        None => return Err(UPLOAD_ERROR.as_str()),
+        Some(name) => sanitize_string(format!("{}{}", prefix, name).as_str()).trim_matches(' ').to_owned()
+    };
+    if file_name.is_empty() { return Err(UPLOAD_ERROR.as_str()) }
+    // Skip the transfer if the upload would be refused anyway
+    check_conflict(&directory.join(&file_name), conflict, None)?;
+    // The file is staged in its own directory below tmp (which the cleaning thread skips), so the final check and write can happen at once
+    create_dir_all(STORAGE.join("tmp").join(UPLOAD_DIRECTORY)).expect("Cannot create upload directory (permission error)");
+    let staged = STORAGE.join("tmp").join(UPLOAD_DIRECTORY).join(format!("{}.upload", session::random_token()));
+    if file.persist_to(&staged).await.is_err() {
+        // If this failed, try to copy the temporary file to the given path
+        // (e.g. the temp path is on a different logical device - see persist_to() docs)
+        file.move_copy_to(&staged).await.map_err(|_| UPLOAD_ERROR.as_str())?;
+    }
+    let result = place_upload(&staged, directory, &file_name, conflict, None).await;
+    if result.is_err() { let _ = remove_file(&staged); }
+    result
+}
+
+// Create the subdirectories of a folder upload below the given directory, returns the directory of the file
+fn create_upload_directories(directory: &Path, components: &[String]) -> Result<PathBuf, &'static str> {
+    let mut target = directory.to_path_buf();
+    for component in components {
+        target.push(component);
+        // An existing file with the name of a subdirectory skips the file
+        if STORAGE.join(&target).is_file() { return Err(IS_FILE.as_str()) }
+        if !STORAGE.join(&target).is_dir() { create_dir(STORAGE.join(&target)).expect("Cannot create directory (permission error)"); }
+    }
+    Ok(target)
+}
+
+// Store an uploaded file below the given directory and create the subdirectories of folder uploads
+async fn store_upload_path(file: &mut TempFile<'_>, directory: &Path, conflict: UploadConflict) -> Result<PathBuf, &'static str> {
+    let mut components = upload_components(file);
+    components.pop();
+    let target = create_upload_directories(directory, &components)?;
+    store_upload(file, &target, "", conflict).await
+}
+
+// The path of an uploaded file as it is recorded in the audit log
+fn upload_target(directory: &Path, file: &TempFile<'_>) -> PathBuf {
+    directory.join(upload_components(file).iter().collect::<PathBuf>())
+}
+
+// The result of every uploaded file, existing files are skipped
+fn upload_summary(settings: &Settings, path: &Path, results: &[(PathBuf, Result<(), &str>)]) -> RawHtml<String> {
+    let mut texts = ("Upload", "uploaded", "skipped", "failed", "skipped, the name already exists", "Back");
+    if settings.language == "de" {
+        texts = ("Hochladen", "hochgeladen", "übersprungen", "fehlgeschlagen", "übersprungen, der Name existiert bereits", "Zurück");
+    }
+    let skipped = results.iter().filter(|(_, result)| *result == Err(IS_FILE.as_str())).count();
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count() - skipped;
+    let mut content = html::paragraph(&format!(
+        "{} {} &ensp; | &ensp; {} {} &ensp; | &ensp; {} {}", results.len() - skipped - failed, texts.1, skipped, texts.2, failed, texts.3
+    ));
+    for (target, result) in results {
+        let status = match result {
+            Ok(()) => texts.1,
+            Err(page) if *page == IS_FILE.as_str() => texts.4,
+            Err(page) => bulk::failure_text(audit::page_name(page), &settings.language)
+        };
+        let name = target.strip_prefix(path).unwrap_or(target).to_str().expect("Invalid path encoding (expected UTF-8)");
+        content.push_str(&html::paragraph(&format!("<b>{}</b> &ensp; {}", html::escape(name), status)));
+    }
+    content.push_str(&format!("<br>{}", html::link(&uri!(list_directory(path.to_str().expect("Invalid path encoding (expected UTF-8)"))).to_string(), texts.5)));
+    RawHtml(html::page(settings, texts.0, &content))
+}
+
+#[post("/upload/<path..>", format = "multipart/form-data", data = "<data>")]
+async fn upload_file(auth: Auth<'_>, path: DotPathBuf, mut data: Form<Upload<'_>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    check_csrf(&auth, Some(&data.csrf_token), "upload", &path)?;
+    let conflict = UploadConflict::parse(&data.conflict);
+    let results = match upload_file_response(&auth, &path, &mut data.file, conflict).await {
+        Ok(results) => results,
+        Err(page) => {
+            let response = Either::Right(page);
+            audit::record_response(&auth, "upload", &path, &response);
+            return Ok(auth.localize(response))
+        }
+    };
+    // The result of every file is recorded on its own
+    for (target, result) in &results {
+        audit::record(auth.actor().as_deref(), auth.ip, "upload", target, result.map_or_else(audit::page_name, |_| "success"));
+    }
+    if results.iter().all(|(_, result)| result.is_ok()) {
+        return Ok(Either::Left(Redirect::to(uri!(list_directory(path.to_str().expect("Invalid path encoding (expected UTF-8)"))))))
+    }
+    Ok(Either::Right(upload_summary(&auth.settings(), &path, &results)))
+}
+
+async fn upload_file_response(auth: &Auth<'_>, path: &Path, files: &mut [TempFile<'_>], conflict: UploadConflict) -> Result<Vec<(PathBuf, Result<(), &'static str>)>, RawHtml<String>> {
+    if let Some(_username) = check_login(auth, path, Scope::Upload) {
+        if auth.is_read_only() { return Err(RawHtml(READ_ONLY.to_owned())) }
+        if check_path(path).1 {
+            // Empty file fields of the form are ignored
+            let mut files: Vec<&mut TempFile<'_>> = files.iter_mut().filter(|file| !upload_components(file).is_empty()).collect();
+            if files.is_empty() { return Err(RawHtml(UPLOAD_ERROR.to_owned())) }
+            // Refuse files that would exceed the quota of the owner
+            let total = files.iter().map(|file| file.len()).sum();
+            if exceeds_quota(path, total) { return Err(RawHtml(QUOTA_EXCEEDED.to_owned())) }
+            let mut results = Vec::new();
+            for file in files.iter_mut() {
+                // Files that are kept under a free name are reported with their new name
+                let result = match store_upload_path(file, path, conflict).await {
+                    Ok(stored) => (stored, Ok(())),
+                    Err(page) => (upload_target(path, file), Err(page))
+                };
+                results.push(result);
             }
+            Ok(results)
         }
-        else { Either::Right(RawHtml(NO_DIRECTORY.to_owned())) }
+        else { Err(RawHtml(NO_DIRECTORY.to_owned())) }
     }
-    else { Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
+    else { Err(RawHtml(ACCESS_DENIED.to_owned())) }
 }
 
-#[launch]
 fn rocket() -> Rocket<Build> {
-    // Start an additional thread to clean the tmp directory once in a while
+    // Start an additional thread to clean the tmp directory and purge expired trash entries and uploads once in a while
     let tmp_path = STORAGE.join("tmp");
     thread::spawn(move || {
         loop {
-            for item in tmp_path.read_dir().expect("Cannot read tmp directory contents") {
-                if let Ok(item) = item {
-                    if item.path().is_file() { remove_file(item.path()).expect("Cannot delete temporary file (permission error)"); }
+            for item in tmp_path.read_dir().expect("Cannot read tmp directory contents").flatten() {
+                // A file can disappear in the meantime, e.g. a ZIP archive that is created again
+                if item.path().is_file() {
+                    if let Err(error) = remove_file(item.path()) {
+                        if error.kind() != io::ErrorKind::NotFound { panic!("Cannot delete temporary file (permission error)") }
+                    }
                 }
             }
+            trash::purge();
+            tus::purge();
+            purge_staged_uploads();
             thread::sleep(Duration::from_secs(CONFIG.clean_tmp_files));
         }
     });
     // Launch the server
     rocket::build()
-        .mount("/", routes![home, login, list_directory, favicon, download_file, download_folder, delete_dir, delete_file, create_directory, unpack_archive, upload_file])
+        .mount("/", routes![home, login, sso::sso_start, sso::sso_callback, sso::sso_complete, logout, logout_all, account::account, account::account_settings, account::totp_login, account::totp_setup,
+                            account::totp_confirm, account::totp_renew, account::totp_disable, tokens::create_token, tokens::revoke_token,
+                            admin::admin, admin::create_user, admin::reset_password, admin::set_user_state, admin::delete_user, admin::audit_log,
+                            shares::share_form, shares::create_share, shares::my_shares, shares::revoke_share, shares::open_link,
+                            shares::unlock_link, shares::browse_link, shares::download_link, shares::zip_link, shares::upload_link,
+                            internal::share_with_user, internal::revoke_internal_share, internal::shared_with_me, transfer::move_form, transfer::move_entry, transfer::copy_form, transfer::copy_entry, transfer::job_status,
+                            trash::trash, trash::restore, trash::empty_trash, bulk::bulk, bulk::bulk_zip,
+                            tus::script, tus::options, tus::create_upload, tus::upload_offset, tus::append_upload, tus::delete_upload,
+                            list_directory, favicon, download_file, download_folder, delete_dir, delete_file, create_directory, unpack_archive, upload_file])
         .mount("/icons", FileServer::from("icons"))
+        
+}
+
+fn main() {
+    // Run the user administration instead of the server if requested (e.g. "rnas user add <name>")
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "user") {
+        users::run_command(&args[1..]);
+    } else {
+        users::migrate_users();
+        groups::create_folders();
+        let _ = rocket::execute(rocket().launch());
+    }
+}
+
+#[cfg(test)]
+pub mod test_support {
+    use std::env;
+    use std::path::Path;
+    use std::sync::Once;
+    use std::fs::{copy, create_dir_all, write};
+    use rocket::Route;
+    use rocket::http::Cookie;
+    use rocket::local::blocking::{Client, LocalRequest};
+    use crate::session;
+    use crate::users::{Role, add_user, get_user};
+
+    // The CSRF token of the sessions created by login
+    pub const CSRF_TOKEN: &str = "test_csrf_token";
+
+    // The tests run in a temporary working directory with a copy of the config file and an empty users file,
+    // so the data files and the storage path of the repository are never touched
+    pub fn setup() {
+        static SETUP: Once = Once::new();
+        SETUP.call_once(|| {
+            let source = Path::new(env!("CARGO_MANIFEST_DIR"));
+            let directory = env::temp_dir().join(format!("rnas-test-{}", std::process::id()));
+            create_dir_all(directory.join("tmp")).expect("Cannot create test directory");
+            copy(source.join("config.toml"), directory.join("config.toml")).expect("Cannot copy config file");
+            write(directory.join("users.toml"), "").expect("Cannot create users file");
+            if !directory.join("static").exists() {
+                std::os::unix::fs::symlink(source.join("static"), directory.join("static")).expect("Cannot link static files");
+            }
+            env::set_current_dir(&directory).expect("Cannot change to test directory");
+        });
+    }
+
+    // A client for the given routes, without the TLS settings and the cleaning thread of the server
+    pub fn client(routes: Vec<Route>) -> Client {
+        setup();
+        Client::untracked(rocket::build().mount("/", routes)).expect("Cannot start test client")
+    }
+
+    // Create the user with its home directory unless it exists already
+    pub fn test_user(name: &str, role: Role) {
+        setup();
+        if get_user(name).is_none() { add_user(name, "password", role).expect("Cannot create test user"); }
+    }
+
+    // Send the request with a new session of the user and its CSRF token
+    pub fn login<'c>(request: LocalRequest<'c>, name: &str) -> LocalRequest<'c> {
+        request.private_cookie(Cookie::new("session_id", session::create_session(name)))
+            .private_cookie(Cookie::new("csrf_token", CSRF_TOKEN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::{Client, LocalRequest};
+    use crate::test_support::{CSRF_TOKEN, client, login, setup, test_user};
+
+    // Self-signed certificate with the subject "CN=cert_cn, emailAddress=cert_subject_mail" and the subject alternative
+    // names "email:cert_san_mail, DNS:cert_dns"
+    const TEST_CERTIFICATE: &str = "\
+    -----BEGIN CERTIFICATE-----\n\
+    MIIB4zCCAYmgAwIBAgIUOK9ME0T1viO8e3wzc5XTTKyY8GMwCgYIKoZIzj0EAwIw\n\
+    NDEQMA4GA1UEAwwHY2VydF9jbjEgMB4GCSqGSIb3DQEJARYRY2VydF9zdWJqZWN0\n\
+    X21haWwwIBcNMjYxMDE2MjA1NTEzWhgPMjEyNjA5MjIyMDU1MTNaMDQxEDAOBgNV\n\
+    BAMMB2NlcnRfY24xIDAeBgkqhkiG9w0BCQEWEWNlcnRfc3ViamVjdF9tYWlsMFkw\n\
+    EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEUWUuF3KoX7RSKYUSxtKZDhVbXbYmCoQf\n\
+    sKZ1sl98Qy4xb52d20sIq2pvbN6WdRkYhF9/8Z+YW2lI03w9biD5WaN3MHUwHQYD\n\
+    VR0OBBYEFM1A2VfePttGlUe3wM5+1xAoqdqVMB8GA1UdIwQYMBaAFM1A2VfePttG\n\
+    lUe3wM5+1xAoqdqVMA8GA1UdEwEB/wQFMAMBAf8wIgYDVR0RBBswGYENY2VydF9z\n\
+    YW5fbWFpbIIIY2VydF9kbnMwCgYIKoZIzj0EAwIDSAAwRQIhAJtGO56JntHajVJR\n\
+    g/Nu1gZPC4sTpoQpBYBq7Q/Bw/smAiAuRqzzed2hIHR8SDO11189cgwLCPRan7Ar\n\
+    aqvj6fNBdw==\n\
+    -----END CERTIFICATE-----\n";
+
+    #[get("/certificate/<field>")]
+    fn certificate_name(certificate: Certificate<'_>, field: &str) -> String {
+        certificate_user(&certificate, field).unwrap_or_default()
+    }
+
+    #[test]
+    fn certificates_are_mapped_to_existing_users() {
+        let client = client(routes![certificate_name]);
+        let name = |field: &str| client.get(format!("/certificate/{}", field)).identity(TEST_CERTIFICATE.as_bytes()).dispatch().into_string();
+        // Names without a matching user are never accepted
+        assert_eq!(name("common_name").as_deref(), Some(""));
+        for user in ["cert_cn", "cert_san_mail", "cert_dns"] { test_user(user, Role::User); }
+        assert_eq!(name("common_name").as_deref(), Some("cert_cn"));
+        assert_eq!(name("email").as_deref(), Some("cert_san_mail"));
+        assert_eq!(name("dns").as_deref(), Some("cert_dns"));
+        assert_eq!(name("unknown").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn pages_follow_user_settings() {
+        setup();
+        let mut settings = Settings::default();
+        assert_eq!(localize_page(&ACCESS_DENIED, &settings), *ACCESS_DENIED);
+
+        settings.language = "de".to_owned();
+        settings.theme.background = "#123456";
+        let page = localize_page(&ACCESS_DENIED, &settings);
+        assert!(page.contains("<html lang=\"de\">") && page.contains("<title>Zugriff verweigert</title>"));
+        assert!(page.contains("background-color:#123456"));
+
+        settings.language = "en".to_owned();
+        let page = localize_page(&ACCESS_DENIED, &settings);
+        assert!(page.contains("<html lang=\"en\">") && page.contains("<title>Access denied</title>"));
+        assert!(page.contains("background-color:#123456"));
+
+        // Pages that are built by the routes are kept as they are
+        assert_eq!(localize_page("<p>Other page</p>", &settings), "<p>Other page</p>");
+    }
+
+    #[test]
+    fn upload_keeps_existing_files() {
+        setup();
+        let directory = Path::new("upload_test");
+        create_dir_all(STORAGE.join(directory)).expect("Cannot create test directory");
+        create_dir_all(STORAGE.join("tmp").join(UPLOAD_DIRECTORY)).expect("Cannot create upload directory");
+        write(STORAGE.join(directory).join("data.txt"), "existing").expect("Cannot write test file");
+        let staged = STORAGE.join("tmp").join(UPLOAD_DIRECTORY).join("test.upload");
+        write(&staged, "upload").expect("Cannot write test file");
+
+        // Without a modification time the upload is not newer than the existing file
+        assert!(link_upload(&staged, directory, "data.txt", UploadConflict::Newer, None).is_err());
+        assert!(link_upload(&staged, directory, "data.txt", UploadConflict::Reject, None).is_err());
+        assert_eq!(read_to_string(STORAGE.join(directory).join("data.txt")).expect("Existing file has been removed"), "existing");
+
+        let target = link_upload(&staged, directory, "data.txt", UploadConflict::KeepBoth, None).expect("Cannot place upload");
+        assert_eq!(target, directory.join("data (1).txt"));
+        assert_eq!(read_to_string(STORAGE.join(&target)).expect("Upload is missing"), "upload");
+        assert!(!staged.exists());
+    }
+
+    #[test]
+    fn newer_upload_replaces_older_files() {
+        setup();
+        let directory = Path::new("newer_test");
+        create_dir_all(STORAGE.join(directory)).expect("Cannot create test directory");
+        create_dir_all(STORAGE.join("tmp").join(UPLOAD_DIRECTORY)).expect("Cannot create upload directory");
+        write(STORAGE.join(directory).join("data.txt"), "existing").expect("Cannot write test file");
+        let current = STORAGE.join(directory).join("data.txt").metadata().and_then(|metadata| metadata.modified()).expect("Cannot read modification time");
+        let staged = STORAGE.join("tmp").join(UPLOAD_DIRECTORY).join("newer.upload");
+        write(&staged, "upload").expect("Cannot write test file");
+
+        // An older upload is skipped, a newer one replaces the file and keeps its modification time
+        let older = current - Duration::from_secs(60);
+        assert_eq!(link_upload(&staged, directory, "data.txt", UploadConflict::Newer, Some(older)), Err(IS_FILE.as_str()));
+        assert_eq!(read_to_string(STORAGE.join(directory).join("data.txt")).expect("Existing file has been removed"), "existing");
+        let newer = current + Duration::from_secs(60);
+        let target = link_upload(&staged, directory, "data.txt", UploadConflict::Newer, Some(newer)).expect("Cannot place upload");
+        assert_eq!(target, directory.join("data.txt"));
+        assert_eq!(read_to_string(STORAGE.join(&target)).expect("Upload is missing"), "upload");
+        assert_eq!(STORAGE.join(&target).metadata().and_then(|metadata| metadata.modified()).expect("Cannot read modification time"), newer);
+        assert!(!staged.exists());
+    }
+
+    #[test]
+    fn purge_removes_stale_staged_uploads() {
+        setup();
+        let staging = STORAGE.join("tmp").join(UPLOAD_DIRECTORY);
+        create_dir_all(&staging).expect("Cannot create upload directory");
+        write(staging.join("stale.upload"), "stale").expect("Cannot write test file");
+        write(staging.join("current.upload"), "current").expect("Cannot write test file");
+        let stale = SystemTime::now() - Duration::from_secs(CONFIG.upload_expiry + 60);
+        File::options().write(true).open(staging.join("stale.upload")).and_then(|file| file.set_modified(stale)).expect("Cannot set modification time");
+
+        purge_staged_uploads();
+        assert!(!staging.join("stale.upload").exists());
+        assert!(staging.join("current.upload").exists());
+    }
+
+    fn upload(client: &Client, path: &str, files: &[(&str, &str)], conflict: &str) -> (Status, String) {
+        let mut body = String::new();
+        for (name, content) in files {
+            body.push_str(&format!("--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n\r\n{}\r\n", name, content));
+        }
+        body.push_str(&format!("--boundary\r\nContent-Disposition: form-data; name=\"conflict\"\r\n\r\n{}\r\n", conflict));
+        body.push_str(&format!("--boundary\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\n{}\r\n--boundary--\r\n", CSRF_TOKEN));
+        let response = login(client.post(format!("/upload/{}", path)), "upload_user").header(ContentType::new("multipart", "form-data").with_params(("boundary", "boundary")))
+            .header(Header::new("Content-Length", body.len().to_string())).body(body).dispatch();
+        (response.status(), response.into_string().unwrap_or_default())
+    }
+
+    #[test]
+    fn upload_names_keep_their_folders() {
+        assert_eq!(path_components("album/2024/photo.jpg"), ["album", "2024", "photo.jpg"]);
+        assert_eq!(path_components("album\\photo.jpg"), ["album", "photo.jpg"]);
+        // Parent and current directory components never leave the target directory
+        assert_eq!(path_components("../album/./../photo.jpg"), ["album", "photo.jpg"]);
+        assert!(path_components("/../.").is_empty());
+    }
+
+    #[test]
+    fn folder_uploads_create_their_directories() {
+        test_user("upload_user", Role::User);
+        let client = client(routes![upload_file]);
+        let files = [("album/one.txt", "one"), ("album/deep/two.txt", "two"), ("three.txt", "three")];
+        let (status, _) = upload(&client, "upload_user", &files, "reject");
+        assert_eq!(status, Status::SeeOther);
+        assert_eq!(read_to_string(STORAGE.join("upload_user/album/one.txt")).expect("Upload is missing"), "one");
+        assert_eq!(read_to_string(STORAGE.join("upload_user/album/deep/two.txt")).expect("Upload is missing"), "two");
+        assert_eq!(read_to_string(STORAGE.join("upload_user/three.txt")).expect("Upload is missing"), "three");
+    }
+
+    #[test]
+    fn existing_names_skip_only_their_file() {
+        test_user("upload_user", Role::User);
+        create_dir_all(STORAGE.join("upload_user/skip")).expect("Cannot create test directory");
+        write(STORAGE.join("upload_user/skip/old.txt"), "existing").expect("Cannot write test file");
+        let client = client(routes![upload_file]);
+        let files = [("old.txt", "replaced"), ("new.txt", "new")];
+        let (status, page) = upload(&client, "upload_user/skip", &files, "reject");
+
+        // The other files are stored and the summary names every file
+        assert_eq!(status, Status::Ok);
+        assert!(page.contains("1 uploaded &ensp; | &ensp; 1 skipped &ensp; | &ensp; 0 failed"));
+        assert!(page.contains("<b>old.txt</b> &ensp; skipped, the name already exists"));
+        assert!(page.contains("<b>new.txt</b> &ensp; uploaded"));
+        assert_eq!(read_to_string(STORAGE.join("upload_user/skip/old.txt")).expect("Existing file has been removed"), "existing");
+        assert_eq!(read_to_string(STORAGE.join("upload_user/skip/new.txt")).expect("Upload is missing"), "new");
+    }
+
+    #[test]
+    fn read_only_users_cannot_change_files() {
+        test_user("read_only_user", Role::ReadOnly);
+        write(STORAGE.join("read_only_user/file.txt"), "kept").expect("Cannot write test file");
+        let client = client(routes![download_file, create_directory, delete_file]);
+        let post = |uri: &str, body: String| login(client.post(uri.to_owned()), "read_only_user").header(ContentType::Form).body(body).dispatch().into_string();
+
+        let page = post("/new_dir/read_only_user", format!("folder_name=new&csrf_token={}", CSRF_TOKEN));
+        assert_eq!(page, Some(READ_ONLY.to_owned()));
+        assert!(!STORAGE.join("read_only_user/new").exists());
+        let page = post("/delete_file/read_only_user/file.txt", format!("csrf_token={}", CSRF_TOKEN));
+        assert_eq!(page, Some(READ_ONLY.to_owned()));
+
+        // Reading is still allowed
+        let download = login(client.get("/download/read_only_user/file.txt"), "read_only_user").dispatch().into_string();
+        assert_eq!(download.as_deref(), Some("kept"));
+    }
+
+    #[test]
+    fn forged_requests_are_rejected() {
+        test_user("csrf_user", Role::User);
+        write(STORAGE.join("csrf_user/file.txt"), "kept").expect("Cannot write test file");
+        let client = client(routes![delete_file]);
+        let delete = |request: LocalRequest<'_>, token: &str| request.header(ContentType::Form).body(format!("csrf_token={}", token)).dispatch().status();
+
+        // Deleting needs a POST request with the token of the session
+        assert_eq!(login(client.get("/delete_file/csrf_user/file.txt"), "csrf_user").dispatch().status(), Status::NotFound);
+        assert_eq!(delete(login(client.post("/delete_file/csrf_user/file.txt"), "csrf_user"), ""), Status::Forbidden);
+        assert_eq!(delete(login(client.post("/delete_file/csrf_user/file.txt"), "csrf_user"), "wrong"), Status::Forbidden);
+        let without_cookie = client.post("/delete_file/csrf_user/file.txt").private_cookie(Cookie::new("session_id", session::create_session("csrf_user")));
+        assert_eq!(delete(without_cookie, CSRF_TOKEN), Status::Forbidden);
+        assert!(STORAGE.join("csrf_user/file.txt").exists());
+        let log = read_to_string("audit.log").unwrap_or_default();
+        assert!(log.contains(r#""user":"csrf_user","ip":null,"action":"delete","path":"csrf_user/file.txt","result":"csrf_rejected""#));
+
+        assert_eq!(delete(login(client.post("/delete_file/csrf_user/file.txt"), "csrf_user"), CSRF_TOKEN), Status::SeeOther);
+        assert!(!STORAGE.join("csrf_user/file.txt").exists());
+    }
 }
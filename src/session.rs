@@ -0,0 +1,122 @@
+/*
+This file contains the server-side session store, which maps random session IDs to logged-in users.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::CONFIG;
+
+struct Session {
+    username: String,
+    created: Instant,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) > Duration::from_secs(CONFIG.session_idle_timeout)
+            || now.duration_since(self.created) > Duration::from_secs(CONFIG.session_lifetime)
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn create_session(username: &str) -> String {
+    let now = Instant::now();
+    let session_id = random_token();
+    let mut sessions = SESSIONS.lock().expect("Session store lock is poisoned");
+    sessions.retain(|_, session| !session.is_expired(now));
+    sessions.insert(session_id.clone(), Session { username: username.to_owned(), created: now, last_seen: now });
+    session_id
+}
+
+pub fn get_session(session_id: &str) -> Option<String> {
+    let now = Instant::now();
+    let mut sessions = SESSIONS.lock().expect("Session store lock is poisoned");
+    match sessions.get_mut(session_id) {
+        None => None,
+        Some(session) if session.is_expired(now) => {
+            sessions.remove(session_id);
+            None
+        }
+        Some(session) => {
+            session.last_seen = now;
+            Some(session.username.to_owned())
+        }
+    }
+}
+
+pub fn remove_session(session_id: &str) {
+    SESSIONS.lock().expect("Session store lock is poisoned").remove(session_id);
+}
+
+pub fn remove_user_sessions(username: &str) {
+    SESSIONS.lock().expect("Session store lock is poisoned").retain(|_, session| session.username != username);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_random_and_removable() {
+        let first = create_session("session_user");
+        let second = create_session("session_user");
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 64);
+        assert_eq!(get_session(&first).as_deref(), Some("session_user"));
+        assert_eq!(get_session("unknown"), None);
+
+        remove_session(&first);
+        assert_eq!(get_session(&first), None);
+        assert_eq!(get_session(&second).as_deref(), Some("session_user"));
+    }
+
+    #[test]
+    fn all_sessions_of_a_user_are_removed() {
+        let sessions = [create_session("session_all"), create_session("session_all")];
+        let other = create_session("session_other");
+        remove_user_sessions("session_all");
+        assert!(sessions.iter().all(|session_id| get_session(session_id).is_none()));
+        assert_eq!(get_session(&other).as_deref(), Some("session_other"));
+    }
+
+    #[test]
+    fn sessions_expire_after_idle_timeout_and_lifetime() {
+        let now = Instant::now();
+        let idle = Duration::from_secs(CONFIG.session_idle_timeout + 1);
+        let session = Session { username: "session_user".to_owned(), created: now, last_seen: now };
+        assert!(!session.is_expired(now));
+        assert!(session.is_expired(now + idle));
+        // Activity keeps a session alive only until its lifetime is over
+        let lifetime = Duration::from_secs(CONFIG.session_lifetime);
+        let session = Session { username: "session_user".to_owned(), created: now, last_seen: now + lifetime };
+        assert!(!session.is_expired(now + lifetime));
+        assert!(session.is_expired(now + lifetime + Duration::from_secs(1)));
+    }
+}
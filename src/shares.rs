@@ -0,0 +1,673 @@
+/*
+This file contains the public share links that give access to a file or directory without an account.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::fs::read_to_string;
+use serde::{Deserialize, Serialize};
+use rocket::data::{self, Data, FromData};
+use rocket::form::Form;
+use rocket::fs::{NamedFile, TempFile};
+use rocket::http::{Cookie, CookieJar};
+use rocket::http::uri::Host;
+use rocket::outcome::Outcome;
+use rocket::request::Request;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{STORAGE, ACCESS_DENIED, READ_ONLY, SHARE_INVALID, LOGIN_LOCKED, QUOTA_EXCEEDED, Auth, DotPathBuf, Settings, UploadConflict, html, session,
+            audit, groups, internal, throttle, check_csrf, zip_directory, exceeds_quota, store_upload, unix_time, write_file_atomic};
+use crate::tokens::Scope;
+use crate::users::{Role, get_user, hash_password, verify_password};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Share {
+    pub id: String,
+    pub user: String,
+    // Path of the shared file or directory relative to the storage path
+    pub path: String,
+    // Argon2id hash of the optional password ("" = no password)
+    pub password: String,
+    // Expiry as UNIX timestamp and the maximum number of downloads (0 = unlimited)
+    pub expires: u64,
+    pub max_downloads: u64,
+    pub downloads: u64,
+    pub created: u64,
+    // Upload-only links for a directory with the maximum file size in MiB (0 = unlimited) and an optional uploader name
+    #[serde(default)]
+    pub drop: bool,
+    #[serde(default)]
+    pub max_size: u64,
+    #[serde(default)]
+    pub ask_name: bool,
+}
+
+impl Share {
+    fn is_valid(&self) -> bool {
+        (self.expires == 0 || unix_time() < self.expires)
+            && (self.max_downloads == 0 || self.downloads < self.max_downloads)
+            && can_share(&self.user, Path::new(&self.path), if self.drop { Scope::Upload } else { Scope::Read })
+            && (if self.drop { STORAGE.join(&self.path).is_dir() } else { STORAGE.join(&self.path).exists() })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SharesFile {
+    #[serde(default)]
+    share: Vec<Share>,
+}
+
+#[derive(FromForm)]
+pub struct NewShare {
+    password: String,
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
+    drop: bool,
+    max_size: Option<u64>,
+    ask_name: bool,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct DropUpload<'r> {
+    file: TempFile<'r>,
+    name: Option<String>,
+}
+
+// The multipart encoding and the name of the sender are sent on top of the file itself
+const FORM_OVERHEAD: u64 = 64 * 1024;
+
+// A file drop refuses a body that is larger than the file size limit of the link or the quota of the owner
+// according to its length, before any of it is received
+pub enum DropData<'r> {
+    Upload(Box<Form<DropUpload<'r>>>),
+    TooLarge,
+    QuotaExceeded,
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for DropData<'r> {
+    type Error = <Form<DropUpload<'r>> as FromData<'r>>::Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let share = request.routed_segment(1).and_then(|id| get_shares().into_iter().find(|share| share.id == id));
+        let length = request.headers().get_one("Content-Length").and_then(|value| value.parse::<u64>().ok());
+        if let Some(share) = share {
+            let size = length.map(|length| length.saturating_sub(FORM_OVERHEAD));
+            if share.max_size > 0 && size.is_none_or(|size| size > share.max_size * 1024 * 1024) { return Outcome::Success(DropData::TooLarge) }
+            if size.is_some_and(|size| exceeds_quota(Path::new(&share.path), size)) { return Outcome::Success(DropData::QuotaExceeded) }
+        }
+        Form::from_data(request, data).await.map(|form| DropData::Upload(Box::new(form)))
+    }
+}
+
+#[derive(FromForm)]
+pub struct SharePassword {
+    password: String,
+}
+
+#[derive(FromForm)]
+pub struct ShareId {
+    id: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+lazy_static! {
+    // Serialize all write access to the shares file
+    static ref SHARES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn get_shares() -> Vec<Share> {
+    // A missing shares file simply means that no link has been created yet
+    let Ok(content) = read_to_string("shares.toml") else { return Vec::new() };
+    toml::from_str::<SharesFile>(&content).expect("Shares file formatting is invalid").share
+}
+
+fn update_shares(change: impl FnOnce(&mut Vec<Share>)) {
+    let _guard = SHARES_LOCK.lock().expect("Shares file lock is poisoned");
+    let mut shares = get_shares();
+    change(&mut shares);
+    write_file_atomic("shares.toml", &toml::to_string(&SharesFile { share: shares }).expect("Cannot serialize shares file"));
+}
+
+pub fn remove_user_shares(name: &str) {
+    update_shares(|shares| shares.retain(|share| share.user != name));
+}
+
+pub fn rename_user_shares(old_name: &str, new_name: &str) {
+    update_shares(|shares| {
+        for share in shares.iter_mut().filter(|share| share.user == old_name) {
+            share.user = new_name.to_owned();
+            if let Ok(relative_path) = Path::new(&share.path).strip_prefix(old_name) {
+                share.path = Path::new(new_name).join(relative_path).to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+            }
+        }
+    });
+}
+
+// Users can share everything inside their home directory and the group folders they can access with the given scope
+pub fn can_share(username: &str, path: &Path, scope: Scope) -> bool {
+    let mut components = path.iter().map(|item| item.to_str().unwrap_or_default());
+    let Some(user) = get_user(username).filter(|user| user.enabled) else { return false };
+    if scope != Scope::Read && user.role == Role::ReadOnly { return false }
+    match (components.next(), components.next()) {
+        (Some(groups::SHARED_DIRECTORY), Some(folder)) => groups::allows(username, folder, scope),
+        (Some(owner), _) => owner == username,
+        _ => false
+    }
+}
+
+// Count a download and refuse it if the limit of the link has been reached in the meantime
+fn register_download(id: &str) -> bool {
+    let mut allowed = false;
+    update_shares(|shares| {
+        if let Some(share) = shares.iter_mut().find(|share| share.id == id) {
+            allowed = share.max_downloads == 0 || share.downloads < share.max_downloads;
+            if allowed { share.downloads += 1; }
+        }
+    });
+    allowed
+}
+
+// Files inside a shared directory only count as one download per visitor, who is remembered by a cookie
+fn register_visit(cookies: &CookieJar<'_>, id: &str) -> bool {
+    let name = format!("share_{}_visited", id);
+    if cookies.get_private(&name).is_some() { return true }
+    if !register_download(id) { return false }
+    add_share_cookie(cookies, name);
+    true
+}
+
+fn add_share_cookie(cookies: &CookieJar<'_>, name: String) {
+    let mut cookie = Cookie::new(name, "1");
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookies.add_private(cookie);
+}
+
+fn share_url(host: Option<&Host<'_>>, id: &str) -> String {
+    match host {
+        Some(host) => format!("https://{}/s/{}", host, id),
+        None => format!("/s/{}", id)
+    }
+}
+
+fn is_unlocked(cookies: &CookieJar<'_>, share: &Share) -> bool {
+    share.password.is_empty() || cookies.get_private(&format!("share_{}", share.id)).is_some()
+}
+
+// Look up a link that is still valid and whose password has already been entered
+fn open_share(cookies: &CookieJar<'_>, id: &str) -> Result<Share, RawHtml<String>> {
+    let share = get_shares().into_iter().find(|share| share.id == id && share.is_valid());
+    match share {
+        Some(share) if is_unlocked(cookies, &share) => Ok(share),
+        Some(share) => Err(password_page(&share.id, false)),
+        None => Err(RawHtml(SHARE_INVALID.to_owned()))
+    }
+}
+
+// The pages of a link are shown to anonymous visitors, so they always use the server settings
+fn password_page(id: &str, failed: bool) -> RawHtml<String> {
+    let settings = Settings::default();
+    let mut texts = ("Shared link", "This link is protected by a password.", "password", "Open", "The password is wrong.");
+    if settings.language == "de" {
+        texts = ("Geteilter Link", "Dieser Link ist durch ein Passwort geschützt.", "Passwort", "Öffnen", "Das Passwort ist falsch.");
+    }
+    let message = if failed { html::error(texts.4) } else { html::paragraph(texts.1) };
+    let fields = format!("{}<br>{}", html::input("password", "password", texts.2), html::submit(texts.3));
+    RawHtml(html::page(&settings, texts.0, &format!("{}{}", message, html::form(&format!("/s/{}", id), "post", &fields))))
+}
+
+fn file_page(share: &Share) -> RawHtml<String> {
+    let settings = Settings::default();
+    let mut texts = ("Shared file", "Download");
+    if settings.language == "de" {
+        texts = ("Geteilte Datei", "Herunterladen");
+    }
+    let path = Path::new(&share.path);
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let size = STORAGE.join(path).metadata().map(|metadata| metadata.len()).unwrap_or(0) as f64 / 1024.0 / 1024.0;
+    RawHtml(html::page(&settings, texts.0, &format!(
+        "{}{}", html::paragraph(&format!("<b>{}</b> &ensp; {:.1} MiB", html::escape(name), size)),
+        html::link(&format!("/s/{}/download/", share.id), texts.1)
+    )))
+}
+
+// File drops only offer an upload form and never reveal the contents of the directory
+fn drop_page(share: &Share) -> RawHtml<String> {
+    let settings = Settings::default();
+    let mut texts = ("File drop", "Files uploaded here can only be seen by the recipient.", "maximum file size", "your name", "Upload file");
+    if settings.language == "de" {
+        texts = ("Dateiablage", "Hier hochgeladene Dateien sind nur für den Empfänger sichtbar.", "maximale Dateigröße", "Ihr Name", "Datei hochladen");
+    }
+    let mut message = texts.1.to_owned();
+    if share.max_size > 0 { message.push_str(&format!("<br>{}: {} MiB", texts.2, share.max_size)); }
+    let name = if share.ask_name { format!("{}<br>", html::input("name", "text", texts.3)) } else { String::new() };
+    RawHtml(html::page(&settings, texts.0, &format!(
+        "{}<form action=\"/s/{}/upload\" method=\"post\" enctype=\"multipart/form-data\" style=\"margin:8px; display:inline-block\"> \
+        {}<input name=\"file\" type=\"file\" style=\"font-family:sans-serif; font-size:14px; width:240px; color:var(--foreground); \
+        background-color:var(--input); border-radius:4px; border-style:hidden; padding:5px; margin:4px\" required /><br>{}</form>",
+        html::paragraph(&message), share.id, name, html::submit(texts.4)
+    )))
+}
+
+fn directory_page(share: &Share, sub_path: &Path) -> RawHtml<String> {
+    let settings = Settings::default();
+    let mut texts = ("Shared directory", "Parent directory", "Download directory (ZIP)", "The directory is empty.");
+    if settings.language == "de" {
+        texts = ("Geteiltes Verzeichnis", "Übergeordnetes Verzeichnis", "Ordner herunterladen (ZIP)", "Das Verzeichnis ist leer.");
+    }
+    let directory = STORAGE.join(&share.path).join(sub_path);
+    let Ok(entries) = directory.read_dir() else { return RawHtml(SHARE_INVALID.to_owned()) };
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    for item in entries.flatten() {
+        let Some(name) = item.file_name().to_str().map(str::to_owned) else { continue };
+        if item.path().is_dir() { directories.push(name) } else if item.path().is_file() { files.push(name) }
+    }
+    files.sort_by_key(|name| name.to_lowercase());
+    directories.sort_by_key(|name| name.to_lowercase());
+
+    let sub_string = sub_path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    let prefix = if sub_string.is_empty() { String::new() } else { format!("{}/", sub_string) };
+    let mut content = String::new();
+    let entry = |route: &str, name: &str, icon: &str| format!(
+        "<p style=\"margin:8px; text-align:left\"><a href=\"/s/{0}/{1}/{2}{3}\" style=\"color:var(--foreground); text-decoration:none\"> \
+        <img src=\"/icons/{4}_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/>{5}</a></p>",
+        share.id, route, prefix, name, icon, html::escape(name)
+    );
+    for name in &directories { content.push_str(&entry("files", name, "folder")); }
+    for name in &files { content.push_str(&entry("download", name, "file")); }
+    if directories.is_empty() && files.is_empty() { content.push_str(&html::paragraph(texts.3)); }
+
+    let mut buttons = html::link(&format!("/s/{}/zip/{}", share.id, sub_string), texts.2);
+    if let Some(parent) = sub_path.parent() {
+        buttons.push_str(&html::link(&format!("/s/{}/files/{}", share.id, parent.to_str().unwrap_or_default()), texts.1));
+    }
+    let name = Path::new(&share.path).join(sub_path);
+    let title = format!("{}: {}", texts.0, name.file_name().and_then(|name| name.to_str()).unwrap_or_default());
+    RawHtml(html::page(&settings, &html::escape(&title), &format!("{}<br><br>{}", buttons, content)))
+}
+
+#[get("/share/<path..>")]
+pub fn share_form(auth: Auth<'_>, path: DotPathBuf) -> RawHtml<String> {
+    let path = path.0;
+    let Some(username) = auth.user() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    if auth.is_read_only() { return RawHtml(auth.page(&READ_ONLY)) }
+    if !can_share(&username, &path, Scope::Read) || !STORAGE.join(&path).exists() { return RawHtml(auth.page(&ACCESS_DENIED)) }
+    let settings = auth.settings();
+    let mut texts = ("Share link", "Create a public link for", "password (optional)", "valid for days (empty = unlimited)",
+                     "maximum downloads (empty = unlimited)", "Create link", "Back", "Upload only (file drop)",
+                     "maximum file size in MiB (empty = unlimited)", "Ask for the name of the sender");
+    if settings.language == "de" {
+        texts = ("Link teilen", "Öffentlichen Link erstellen für", "Passwort (optional)", "gültig für Tage (leer = unbegrenzt)",
+                 "maximale Downloads (leer = unbegrenzt)", "Link erstellen", "Zurück", "Nur hochladen (Dateiablage)",
+                 "maximale Dateigröße in MiB (leer = unbegrenzt)", "Nach dem Namen des Absenders fragen");
+    }
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    let parent = path.parent().and_then(|parent| parent.to_str()).unwrap_or_default();
+    // Directories can also receive files from anonymous senders instead of being shared for download
+    let drop_fields = if STORAGE.join(&path).is_dir() && can_share(&username, &path, Scope::Upload) {
+        format!("{}<br>{}<br>{}<br>", html::checkbox("drop", texts.7), html::input("max_size", "number", texts.8), html::checkbox("ask_name", texts.9))
+    } else {
+        String::new()
+    };
+    let csrf_token = auth.csrf_token();
+    let fields = format!(
+        "{}{}<br>{}<br>{}<br>{}{}",
+        html::hidden("csrf_token", &csrf_token), html::input("password", "password", texts.2), html::input("expires_in", "number", texts.3),
+        html::input("max_downloads", "number", texts.4), drop_fields, html::submit(texts.5)
+    );
+    RawHtml(html::page(&settings, texts.0, &format!(
+        "{}{}{}<br>{}",
+        html::paragraph(&format!("{} <b>/{}</b>", texts.1, html::escape(path_string))),
+        html::form(&format!("/share/{}", path_string), "post", &fields), internal::share_section(path_string, &settings.language, &csrf_token),
+        html::link(&uri!(crate::list_directory(parent)).to_string(), texts.6)
+    )))
+}
+
+#[post("/share/<path..>", data = "<data>")]
+pub fn create_share(auth: Auth<'_>, host: Option<&Host<'_>>, path: DotPathBuf, data: Option<Form<NewShare>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "create_share", &path)?;
+    let (Some(username), Some(data)) = (auth.user(), data) else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    if auth.is_read_only() { return Ok(RawHtml(auth.page(&READ_ONLY))) }
+    let scope = if data.drop { Scope::Upload } else { Scope::Read };
+    if !can_share(&username, &path, scope) || !STORAGE.join(&path).exists() { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) }
+    if data.drop && !STORAGE.join(&path).is_dir() { return Ok(RawHtml(auth.page(&SHARE_INVALID))) }
+    let settings = auth.settings();
+    let mut texts = ("Share link", "The link has been created:", "My shares");
+    if settings.language == "de" {
+        texts = ("Link teilen", "Der Link wurde erstellt:", "Meine Freigaben");
+    }
+    let expires = match data.expires_in {
+        Some(days) if days > 0 => unix_time().saturating_add(days.saturating_mul(86400)),
+        _ => 0
+    };
+    let share = Share {
+        id: session::random_token()[..32].to_owned(),
+        user: username,
+        path: path.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned(),
+        password: if data.password.is_empty() { String::new() } else { hash_password(&data.password) },
+        expires,
+        max_downloads: if data.drop { 0 } else { data.max_downloads.unwrap_or(0) },
+        downloads: 0,
+        created: unix_time(),
+        drop: data.drop,
+        max_size: if data.drop { data.max_size.unwrap_or(0) } else { 0 },
+        ask_name: data.drop && data.ask_name,
+    };
+    let url = share_url(host, &share.id);
+    update_shares(|shares| shares.push(share));
+    Ok(RawHtml(html::page(&settings, texts.0, &format!(
+        "{}<pre style=\"font-size:16px\">{}</pre>{}",
+        html::paragraph(texts.1), html::escape(&url), html::link(&uri!(my_shares).to_string(), texts.2)
+    ))))
+}
+
+#[get("/shares")]
+pub fn my_shares(auth: Auth<'_>, host: Option<&Host<'_>>) -> RawHtml<String> {
+    let Some(username) = auth.user() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = auth.settings();
+    let mut texts = ("My shares", "No links created yet.", "password", "no password", "expires", "unlimited", "downloads",
+                     "invalid", "Revoke", "Back", "file drop, maximum file size");
+    if settings.language == "de" {
+        texts = ("Meine Freigaben", "Bisher wurden keine Links erstellt.", "Passwort", "kein Passwort", "läuft ab", "unbegrenzt",
+                 "Downloads", "ungültig", "Widerrufen", "Zurück", "Dateiablage, maximale Dateigröße");
+    }
+    let shares: Vec<Share> = get_shares().into_iter().filter(|share| share.user == username).collect();
+    let csrf_token = auth.csrf_token();
+    let mut content = String::new();
+    if shares.is_empty() { content.push_str(&html::paragraph(texts.1)); }
+    for share in &shares {
+        let expiry = if share.expires == 0 { texts.5.to_owned() } else { html::date(share.expires) };
+        let usage = if share.drop {
+            let size = if share.max_size == 0 { texts.5.to_owned() } else { format!("{} MiB", share.max_size) };
+            format!("{} {}", texts.10, size)
+        } else {
+            let limit = if share.max_downloads == 0 { "∞".to_owned() } else { share.max_downloads.to_string() };
+            format!("{} {} / {}", texts.6, share.downloads, limit)
+        };
+        content.push_str(&html::paragraph(&format!(
+            "<b>/{}</b><br>{}<br>{} &ensp; {} {} &ensp; {}{}",
+            html::escape(&share.path), html::escape(&share_url(host, &share.id)),
+            if share.password.is_empty() { texts.3 } else { texts.2 }, texts.4, expiry, usage,
+            if share.is_valid() { String::new() } else { format!(" &ensp; ({})", texts.7) }
+        )));
+        let fields = format!("{}{}{}", html::hidden("id", &share.id), html::hidden("csrf_token", &csrf_token), html::submit(texts.8));
+        content.push_str(&html::form("/shares/revoke", "post", &fields));
+    }
+    content.push_str(&internal::owner_section(&username, &settings.language, &csrf_token));
+    content.push_str(&format!("<br>{}", html::link(&format!("/files/{}", username), texts.9)));
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[post("/shares/revoke", data = "<data>")]
+pub fn revoke_share(auth: Auth<'_>, data: Option<Form<ShareId>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "revoke_share", Path::new(""))?;
+    let (Some(username), Some(data)) = (auth.user(), data) else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    update_shares(|shares| shares.retain(|share| share.user != username || share.id != data.id));
+    Ok(Either::Left(Redirect::to(uri!(my_shares))))
+}
+
+#[get("/s/<id>")]
+pub fn open_link(cookies: &CookieJar<'_>, id: &str) -> RawHtml<String> {
+    match open_share(cookies, id) {
+        Ok(share) if share.drop => drop_page(&share),
+        Ok(share) if STORAGE.join(&share.path).is_dir() => directory_page(&share, Path::new("")),
+        Ok(share) => file_page(&share),
+        Err(page) => page
+    }
+}
+
+#[post("/s/<id>", data = "<data>")]
+pub fn unlock_link(cookies: &CookieJar<'_>, ip: Option<IpAddr>, id: &str, data: Option<Form<SharePassword>>) -> Either<Redirect, RawHtml<String>> {
+    let Some(share) = get_shares().into_iter().find(|share| share.id == id && share.is_valid()) else {
+        return Either::Right(RawHtml(SHARE_INVALID.to_owned()))
+    };
+    // Password guessing is throttled like the login, but counted separately from it
+    if throttle::is_share_locked(ip, &share.id) { return Either::Right(RawHtml(LOGIN_LOCKED.to_owned())) }
+    let password = data.map(|data| data.password.to_owned()).unwrap_or_default();
+    if share.password.is_empty() || verify_password(&share.password, "", &password) {
+        throttle::register_share_success(&share.id);
+        add_share_cookie(cookies, format!("share_{}", share.id));
+        Either::Left(Redirect::to(uri!(open_link(id))))
+    } else {
+        throttle::register_share_failure(ip, &share.id);
+        Either::Right(password_page(&share.id, true))
+    }
+}
+
+#[get("/s/<id>/files/<path..>")]
+pub fn browse_link(cookies: &CookieJar<'_>, id: &str, path: DotPathBuf) -> RawHtml<String> {
+    match open_share(cookies, id) {
+        Ok(share) if !share.drop && STORAGE.join(&share.path).join(&path.0).is_dir() => directory_page(&share, &path.0),
+        Ok(_) => RawHtml(SHARE_INVALID.to_owned()),
+        Err(page) => page
+    }
+}
+
+fn shared_path(share: &Share, sub_path: &Path) -> PathBuf {
+    // Joining an empty path would append a separator, which is not valid for a shared file
+    if sub_path.as_os_str().is_empty() { PathBuf::from(&share.path) } else { Path::new(&share.path).join(sub_path) }
+}
+
+#[get("/s/<id>/download/<path..>")]
+pub async fn download_link(cookies: &CookieJar<'_>, ip: Option<IpAddr>, id: &str, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
+    let share = match open_share(cookies, id) {
+        Ok(share) if !share.drop => share,
+        Ok(_) => return Either::Right(RawHtml(SHARE_INVALID.to_owned())),
+        Err(page) => return Either::Right(page)
+    };
+    let file_path = shared_path(&share, &path.0);
+    if !STORAGE.join(&file_path).is_file() { return Either::Right(RawHtml(SHARE_INVALID.to_owned())) }
+    let allowed = if STORAGE.join(&share.path).is_dir() { register_visit(cookies, &share.id) } else { register_download(&share.id) };
+    if !allowed { return Either::Right(RawHtml(SHARE_INVALID.to_owned())) }
+    audit::record(None, ip, "link_download", &file_path, "success");
+    Either::Left(NamedFile::open(STORAGE.join(&file_path)).await.ok())
+}
+
+#[get("/s/<id>/zip/<path..>")]
+pub async fn zip_link(cookies: &CookieJar<'_>, ip: Option<IpAddr>, id: &str, path: DotPathBuf) -> Either<Option<NamedFile>, RawHtml<String>> {
+    let share = match open_share(cookies, id) {
+        Ok(share) if !share.drop => share,
+        Ok(_) => return Either::Right(RawHtml(SHARE_INVALID.to_owned())),
+        Err(page) => return Either::Right(page)
+    };
+    let directory_path = shared_path(&share, &path.0);
+    if !STORAGE.join(&directory_path).is_dir() { return Either::Right(RawHtml(SHARE_INVALID.to_owned())) }
+    if !register_download(&share.id) { return Either::Right(RawHtml(SHARE_INVALID.to_owned())) }
+    audit::record(None, ip, "link_zip", &directory_path, "success");
+    Either::Left(NamedFile::open(zip_directory(&directory_path)).await.ok())
+}
+
+#[post("/s/<id>/upload", format = "multipart/form-data", data = "<data>")]
+pub async fn upload_link(cookies: &CookieJar<'_>, ip: Option<IpAddr>, id: &str, data: DropData<'_>) -> RawHtml<String> {
+    let share = match open_share(cookies, id) {
+        Ok(share) if share.drop => share,
+        Ok(_) => return RawHtml(SHARE_INVALID.to_owned()),
+        Err(page) => return page
+    };
+    let settings = Settings::default();
+    let mut texts = ("File drop", "The file has been uploaded, thank you.", "The file exceeds the maximum file size of this link.", "Upload another file");
+    if settings.language == "de" {
+        texts = ("Dateiablage", "Die Datei wurde hochgeladen, vielen Dank.", "Die Datei überschreitet die maximale Dateigröße dieses Links.",
+                 "Weitere Datei hochladen");
+    }
+    let back = html::link(&uri!(open_link(id)).to_string(), texts.3);
+    let too_large = RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.2), back)));
+    let mut data = match data {
+        DropData::Upload(data) => *data,
+        DropData::TooLarge => return too_large,
+        DropData::QuotaExceeded => return RawHtml(QUOTA_EXCEEDED.to_owned())
+    };
+    // The length of the body is only an estimate, so the received file is checked again
+    if share.max_size > 0 && data.file.len() > share.max_size * 1024 * 1024 { return too_large }
+    if exceeds_quota(Path::new(&share.path), data.file.len()) { return RawHtml(QUOTA_EXCEEDED.to_owned()) }
+    // The name of the sender is put in front of the file name
+    let prefix = match &data.name {
+        Some(name) if share.ask_name && !name.trim().is_empty() => format!("{} - ", name.trim()),
+        _ => String::new()
+    };
+    // Files of the owner are never replaced by an upload link
+    let result = store_upload(&mut data.file, Path::new(&share.path), &prefix, UploadConflict::Reject).await.map(|_| ());
+    audit::record(None, ip, "link_upload", Path::new(&share.path), result.map_or_else(audit::page_name, |_| "success"));
+    match result {
+        Ok(()) => RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::paragraph(texts.1), back))),
+        Err(page) => RawHtml(page.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use crate::test_support::{setup, test_user};
+
+    // Every client keeps its own cookies and is therefore a separate visitor of the link
+    fn visitor() -> Client {
+        setup();
+        Client::tracked(rocket::build().mount("/", routes![open_link, unlock_link, browse_link, download_link, upload_link])).expect("Cannot start test client")
+    }
+
+    fn test_share(path: &str, password: &str, expires: u64, max_downloads: u64) -> String {
+        test_user("share_user", Role::User);
+        let id = session::random_token()[..32].to_owned();
+        let share = Share {
+            id: id.clone(), user: "share_user".to_owned(), path: path.to_owned(),
+            password: if password.is_empty() { String::new() } else { hash_password(password) },
+            expires, max_downloads, downloads: 0, created: unix_time(), drop: false, max_size: 0, ask_name: false,
+        };
+        update_shares(|shares| shares.push(share));
+        id
+    }
+
+    // Upload a file to a file drop, optionally with a different declared length of the body
+    fn drop_upload(client: &Client, id: &str, name: &str, content: &str, length: Option<usize>) -> String {
+        let body = format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n\r\n{}\r\n--boundary--\r\n",
+            name, content
+        );
+        client.post(format!("/s/{}/upload", id)).header(ContentType::new("multipart", "form-data").with_params(("boundary", "boundary")))
+            .header(Header::new("Content-Length", length.unwrap_or(body.len()).to_string())).body(body).dispatch().into_string().unwrap_or_default()
+    }
+
+    fn downloads(id: &str) -> u64 {
+        get_shares().into_iter().find(|share| share.id == id).expect("Share is missing").downloads
+    }
+
+    fn is_invalid(client: &Client, uri: String) -> bool {
+        client.get(uri).dispatch().into_string() == Some(SHARE_INVALID.to_owned())
+    }
+
+    #[test]
+    fn folder_downloads_count_once_per_visitor() {
+        test_user("share_user", Role::User);
+        create_dir_all(STORAGE.join("share_user/folder")).expect("Cannot create test directory");
+        write(STORAGE.join("share_user/folder/a.txt"), "a").expect("Cannot create test file");
+        write(STORAGE.join("share_user/folder/b.txt"), "b").expect("Cannot create test file");
+        let id = test_share("share_user/folder", "", 0, 2);
+
+        let first = visitor();
+        for name in ["a.txt", "b.txt", "a.txt"] {
+            assert_eq!(first.get(format!("/s/{}/download/{}", id, name)).dispatch().into_string().as_deref(), Some(&name[..1]));
+        }
+        assert_eq!(downloads(&id), 1);
+        let second = visitor();
+        assert_eq!(second.get(format!("/s/{}/download/a.txt", id)).dispatch().status(), Status::Ok);
+        assert_eq!(downloads(&id), 2);
+
+        // The limit is reached, so the link is invalid for every visitor
+        assert!(is_invalid(&visitor(), format!("/s/{}", id)));
+        assert!(is_invalid(&first, format!("/s/{}/download/b.txt", id)));
+    }
+
+    #[test]
+    fn file_downloads_are_limited() {
+        test_user("share_user", Role::User);
+        write(STORAGE.join("share_user/limited.txt"), "limited").expect("Cannot create test file");
+        let id = test_share("share_user/limited.txt", "", 0, 2);
+        let client = visitor();
+        for _ in 0..2 {
+            assert_eq!(client.get(format!("/s/{}/download/", id)).dispatch().into_string().as_deref(), Some("limited"));
+        }
+        assert!(is_invalid(&client, format!("/s/{}/download/", id)));
+        assert_eq!(downloads(&id), 2);
+    }
+
+    #[test]
+    fn expired_links_are_invalid() {
+        test_user("share_user", Role::User);
+        write(STORAGE.join("share_user/expired.txt"), "expired").expect("Cannot create test file");
+        let expired = test_share("share_user/expired.txt", "", unix_time() - 1, 0);
+        let current = test_share("share_user/expired.txt", "", unix_time() + 3600, 0);
+        let client = visitor();
+        assert!(is_invalid(&client, format!("/s/{}", expired)));
+        assert!(is_invalid(&client, format!("/s/{}/download/", expired)));
+        assert_eq!(client.get(format!("/s/{}/download/", current)).dispatch().into_string().as_deref(), Some("expired"));
+    }
+
+    #[test]
+    fn password_unlocks_link() {
+        test_user("share_user", Role::User);
+        write(STORAGE.join("share_user/protected.txt"), "protected").expect("Cannot create test file");
+        let id = test_share("share_user/protected.txt", "secret", 0, 0);
+        let client = visitor();
+
+        // Without the password only the password form is shown and nothing can be downloaded
+        let locked = password_page(&id, false).0;
+        assert_eq!(client.get(format!("/s/{}", id)).dispatch().into_string(), Some(locked.clone()));
+        assert_eq!(client.get(format!("/s/{}/download/", id)).dispatch().into_string(), Some(locked.clone()));
+        let response = client.post(format!("/s/{}", id)).header(ContentType::Form).body("password=wrong").dispatch();
+        assert_eq!(response.into_string(), Some(password_page(&id, true).0));
+
+        let response = client.post(format!("/s/{}", id)).header(ContentType::Form).body("password=secret").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(client.get(format!("/s/{}/download/", id)).dispatch().into_string().as_deref(), Some("protected"));
+        assert_eq!(visitor().get(format!("/s/{}/download/", id)).dispatch().into_string(), Some(locked));
+        assert_eq!(downloads(&id), 1);
+    }
+
+    #[test]
+    fn oversized_drop_upload_is_refused() {
+        test_user("share_user", Role::User);
+        create_dir_all(STORAGE.join("share_user/drop")).expect("Cannot create test directory");
+        let id = test_share("share_user/drop", "", 0, 0);
+        update_shares(|shares| for share in shares.iter_mut().filter(|share| share.id == id) { share.drop = true; share.max_size = 1; });
+        let client = visitor();
+
+        // The declared length already exceeds the limit, so the body is not even received
+        let page = drop_upload(&client, &id, "declared.txt", "small", Some(2 * 1024 * 1024));
+        assert!(page.contains("The file exceeds the maximum file size of this link."));
+        assert!(!STORAGE.join("share_user/drop/declared.txt").exists());
+        let page = drop_upload(&client, &id, "large.txt", &"x".repeat(2 * 1024 * 1024), None);
+        assert!(page.contains("The file exceeds the maximum file size of this link."));
+        assert!(!STORAGE.join("share_user/drop/large.txt").exists());
+
+        let page = drop_upload(&client, &id, "small.txt", "small", None);
+        assert!(page.contains("The file has been uploaded, thank you."));
+        assert_eq!(read_to_string(STORAGE.join("share_user/drop/small.txt")).expect("Upload is missing"), "small");
+    }
+}
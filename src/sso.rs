@@ -0,0 +1,239 @@
+/*
+This file contains the single sign-on with an OpenID Connect provider (authorization code flow with PKCE).
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata};
+use openidconnect::{AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet, EndpointNotSet, EndpointSet,
+                    IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, reqwest};
+use rocket::time::Duration;
+use rocket::response::Redirect;
+use rocket::http::{Cookie, CookieJar};
+use rocket::response::content::RawHtml;
+use rocket::Either;
+use crate::{CONFIG, LOGIN_FAILED, Auth, audit, html, finish_login, unix_time};
+use crate::users::{User, get_user, is_valid_name, provision_user};
+
+// Time to complete the login at the provider (in seconds)
+const SSO_TIMEOUT: u64 = 300;
+
+// Password hash entry of users that are created automatically after their first SSO login or linked with "rnas user sso"
+pub const OIDC_HASH: &str = "!oidc";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcConfig {
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scopes: String,
+    username_claim: String,
+    auto_provision: bool,
+}
+
+type OidcClient = CoreClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointMaybeSet, EndpointMaybeSet>;
+
+fn http_client() -> Result<reqwest::Client, String> {
+    // Redirects must not be followed to prevent server-side request forgery
+    reqwest::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|error| error.to_string())
+}
+
+// The provider metadata is discovered for every login, so changed signing keys are picked up automatically
+async fn client(config: &OidcConfig, http_client: &reqwest::Client) -> Result<OidcClient, String> {
+    let issuer_url = IssuerUrl::new(config.issuer_url.to_owned()).map_err(|error| error.to_string())?;
+    let metadata = CoreProviderMetadata::discover_async(issuer_url, http_client).await.map_err(|error| error.to_string())?;
+    let redirect_url = RedirectUrl::new(config.redirect_url.to_owned()).map_err(|error| error.to_string())?;
+    let client_secret = if config.client_secret.is_empty() { None } else { Some(ClientSecret::new(config.client_secret.to_owned())) };
+    Ok(CoreClient::from_provider_metadata(metadata, ClientId::new(config.client_id.to_owned()), client_secret)
+        .set_redirect_uri(redirect_url))
+}
+
+fn login_failed(ip: Option<IpAddr>, message: String) -> Either<Redirect, RawHtml<String>> {
+    eprintln!("SSO login failed: {}", message);
+    audit::record(None, ip, "login_sso", Path::new(""), "failure");
+    Either::Right(RawHtml(LOGIN_FAILED.to_owned()))
+}
+
+#[get("/sso")]
+pub async fn sso_start(cookies: &CookieJar<'_>, ip: Option<IpAddr>) -> Either<Redirect, RawHtml<String>> {
+    let Some(config) = &CONFIG.oidc else { return Either::Right(RawHtml(LOGIN_FAILED.to_owned())) };
+    let client = match async { client(config, &http_client()?).await }.await {
+        Ok(client) => client,
+        Err(error) => return login_failed(ip, error)
+    };
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut request = client.authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random);
+    for scope in config.scopes.split_whitespace().filter(|scope| *scope != "openid") {
+        request = request.add_scope(Scope::new(scope.to_owned()));
+    }
+    let (url, state, nonce) = request.set_pkce_challenge(pkce_challenge).url();
+    // Remember the state, nonce and PKCE verifier until the provider redirects back
+    let mut cookie = Cookie::new("sso_login", format!(
+        "{};{};{};{}", unix_time(), state.secret(), nonce.secret(), pkce_verifier.secret()
+    ));
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_max_age(Duration::seconds(SSO_TIMEOUT as i64));
+    cookies.add_private(cookie);
+    Either::Left(Redirect::to(url.to_string()))
+}
+
+// Browsers do not send strict same-site cookies along with the redirect from the provider, so the login is
+// completed after an additional navigation from this site
+#[get("/sso/callback?<code>&<state>")]
+pub fn sso_callback(code: Option<String>, state: Option<String>) -> RawHtml<String> {
+    let target = uri!(sso_complete(code, state)).to_string();
+    RawHtml(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"0; url={0}\"></head> \
+        <body style=\"background-color:{1}\"></body></html>",
+        html::escape(&target), CONFIG.background
+    ))
+}
+
+// Check the login state from the cookie against the state returned by the provider, returns the nonce and the PKCE verifier
+fn check_state(cookie: &str, state: &str, now: u64) -> Result<(String, String), String> {
+    let fields: Vec<&str> = cookie.split(';').collect();
+    let [timestamp, expected_state, nonce, pkce_verifier] = fields.as_slice() else { return Err("invalid login state".to_owned()) };
+    if now.saturating_sub(timestamp.parse().unwrap_or(0)) > SSO_TIMEOUT { return Err("expired login state".to_owned()) }
+    if state != *expected_state { return Err("state mismatch".to_owned()) }
+    Ok((nonce.to_string(), pkce_verifier.to_string()))
+}
+
+// The user name from the configured claim, e-mail addresses cannot be used because user names cannot contain "@"
+fn claim_username(claim: &str, claims: &CoreIdTokenClaims) -> Result<String, String> {
+    let username = match claim {
+        "preferred_username" => claims.preferred_username().map(|name| name.to_string()),
+        "sub" => Some(claims.subject().to_string()),
+        other => return Err(format!("unsupported username claim {:?}", other))
+    };
+    username.ok_or(format!("the ID token does not contain the claim {:?}", claim))
+}
+
+// Map the user name to a user of the provider or create a new one if enabled, local accounts with the same name
+// cannot be taken over because the claim can often be changed by the user at the provider
+fn sso_user(username: &str, auto_provision: bool) -> Result<User, String> {
+    match get_user(username) {
+        Some(user) if user.hash == OIDC_HASH => Ok(user),
+        Some(_) => Err(format!("the local user {:?} is not linked to the provider", username)),
+        None if auto_provision && is_valid_name(username) => {
+            provision_user(username, OIDC_HASH);
+            get_user(username).ok_or(format!("cannot create the user {:?}", username))
+        }
+        None => Err(format!("no matching user for {:?}", username))
+    }
+}
+
+#[get("/sso/complete?<code>&<state>")]
+pub async fn sso_complete(auth: Auth<'_>, ip: Option<IpAddr>, code: Option<String>, state: Option<String>) -> Either<Redirect, RawHtml<String>> {
+    let cookies = auth.cookies;
+    let Some(config) = &CONFIG.oidc else { return Either::Right(RawHtml(auth.page(&LOGIN_FAILED))) };
+    let Some(cookie) = cookies.get_private("sso_login") else { return login_failed(ip, "missing or expired login state".to_owned()) };
+    cookies.remove_private("sso_login");
+    let (Some(code), Some(state)) = (code, state) else { return login_failed(ip, "the provider did not return a code".to_owned()) };
+    let (nonce, pkce_verifier) = match check_state(cookie.value(), &state, unix_time()) {
+        Ok(values) => values,
+        Err(error) => return login_failed(ip, error)
+    };
+
+    // Exchange the code for the tokens and verify the signature, audience, issuer and nonce of the ID token
+    let result: Result<String, String> = async {
+        let http_client = http_client()?;
+        let client = client(config, &http_client).await?;
+        let response = client.exchange_code(AuthorizationCode::new(code)).map_err(|error| error.to_string())?
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(&http_client).await.map_err(|error| error.to_string())?;
+        let id_token = response.id_token().ok_or("the provider did not return an ID token")?;
+        let claims = id_token.claims(&client.id_token_verifier(), &Nonce::new(nonce)).map_err(|error| error.to_string())?;
+        claim_username(&config.username_claim, claims)
+    }.await;
+    let user = match result.and_then(|username| sso_user(&username, config.auto_provision)) {
+        Ok(user) => user,
+        Err(error) => return login_failed(ip, error)
+    };
+    // The provider replaces the password, so a required client certificate still has to match
+    match CONFIG.cert_login.as_str() {
+        "cert_only" => return login_failed(ip, "only client certificates are accepted".to_owned()),
+        "cert_and_password" if auth.cert_user.as_ref() != Some(&user.name) => {
+            return login_failed(ip, "the client certificate does not match".to_owned())
+        }
+        _ => {}
+    }
+    finish_login(cookies, ip, &user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::setup;
+    use crate::users::hash_password;
+
+    fn claims(extra: &str) -> CoreIdTokenClaims {
+        serde_json::from_str(&format!(
+            "{{\"iss\":\"https://idp.example.com\",\"aud\":\"rnas\",\"exp\":4102444800,\"iat\":1700000000,\"sub\":\"a1b2c3\"{}}}", extra
+        )).expect("Invalid test claims")
+    }
+
+    #[test]
+    fn state_is_checked() {
+        let cookie = "1000;expected;nonce;verifier";
+        assert_eq!(check_state(cookie, "expected", 1000 + SSO_TIMEOUT), Ok(("nonce".to_owned(), "verifier".to_owned())));
+        assert_eq!(check_state(cookie, "other", 1001), Err("state mismatch".to_owned()));
+        assert_eq!(check_state(cookie, "", 1001), Err("state mismatch".to_owned()));
+        assert_eq!(check_state("1000;expected;nonce", "expected", 1001), Err("invalid login state".to_owned()));
+    }
+
+    #[test]
+    fn state_expires() {
+        assert_eq!(check_state("1000;expected;nonce;verifier", "expected", 1001 + SSO_TIMEOUT), Err("expired login state".to_owned()));
+        assert_eq!(check_state("invalid;expected;nonce;verifier", "expected", 1001), Err("expired login state".to_owned()));
+    }
+
+    #[test]
+    fn claims_are_mapped() {
+        let full = claims(",\"preferred_username\":\"alice\",\"email\":\"alice@example.com\",\"email_verified\":true");
+        assert_eq!(claim_username("preferred_username", &full), Ok("alice".to_owned()));
+        assert_eq!(claim_username("sub", &full), Ok("a1b2c3".to_owned()));
+        // User names cannot contain "@", so the e-mail address is refused as user name
+        assert!(claim_username("email", &full).is_err());
+        assert!(claim_username("preferred_username", &claims("")).is_err());
+    }
+
+    #[test]
+    fn local_users_are_not_taken_over() {
+        setup();
+        provision_user("sso_local", &hash_password("local"));
+        assert!(sso_user("sso_local", true).is_err());
+        provision_user("sso_linked", OIDC_HASH);
+        assert_eq!(sso_user("sso_linked", false).map(|user| user.name), Ok("sso_linked".to_owned()));
+    }
+
+    #[test]
+    fn unknown_users_are_provisioned_if_enabled() {
+        setup();
+        assert!(sso_user("sso_new", false).is_err());
+        assert!(get_user("sso_new").is_none());
+        assert_eq!(sso_user("sso_new", true).map(|user| user.hash), Ok(OIDC_HASH.to_owned()));
+        assert!(crate::STORAGE.join("sso_new").is_dir());
+        assert!(sso_user("sso/../new", true).is_err());
+    }
+}
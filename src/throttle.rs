@@ -0,0 +1,168 @@
+/*
+This file contains the tracking of failed login attempts per client IP and per account.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Mutex;
+use std::net::IpAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use time::macros::format_description;
+use crate::CONFIG;
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+lazy_static! {
+    // Failed attempts are tracked with the keys "ip:<address>" and "user:<name>" for logins and
+    // "share_ip:<address>" and "share:<id>" for share passwords, so guessing a share password cannot lock an address out of the login
+    static ref ATTEMPTS: Mutex<HashMap<String, Attempts>> = Mutex::new(HashMap::new());
+}
+
+fn keys(ip: Option<IpAddr>, name: &str) -> Vec<String> {
+    let mut keys = vec![format!("user:{}", name)];
+    if let Some(ip) = ip { keys.push(format!("ip:{}", ip)); }
+    keys
+}
+
+fn share_keys(ip: Option<IpAddr>, id: &str) -> Vec<String> {
+    let mut keys = vec![format!("share:{}", id)];
+    if let Some(ip) = ip { keys.push(format!("share_ip:{}", ip)); }
+    keys
+}
+
+fn ip_string(ip: Option<IpAddr>) -> String {
+    ip.map_or("unknown".to_owned(), |ip| ip.to_string())
+}
+
+pub fn is_locked(ip: Option<IpAddr>, name: &str) -> bool {
+    locked(&keys(ip, name))
+}
+
+pub fn register_failure(ip: Option<IpAddr>, name: &str) {
+    failure(&keys(ip, name), ip, "login", "user", name);
+}
+
+// Only the account is released, otherwise the owner of any account could reset the counter of the address between guesses
+pub fn register_success(name: &str) {
+    success(&format!("user:{}", name));
+}
+
+pub fn is_share_locked(ip: Option<IpAddr>, id: &str) -> bool {
+    locked(&share_keys(ip, id))
+}
+
+pub fn register_share_failure(ip: Option<IpAddr>, id: &str) {
+    failure(&share_keys(ip, id), ip, "share", "share", id);
+}
+
+pub fn register_share_success(id: &str) {
+    success(&format!("share:{}", id));
+}
+
+fn locked(keys: &[String]) -> bool {
+    let now = Instant::now();
+    let attempts = ATTEMPTS.lock().expect("Login attempts lock is poisoned");
+    keys.iter().any(|key| {
+        attempts.get(key).and_then(|entry| entry.locked_until).is_some_and(|until| until > now)
+    })
+}
+
+// Lockouts are logged as "rNAS login lockout" or "rNAS share lockout", only the first one is banned by the fail2ban filter
+fn failure(keys: &[String], ip: Option<IpAddr>, kind: &str, field: &str, name: &str) {
+    let now = Instant::now();
+    let mut attempts = ATTEMPTS.lock().expect("Login attempts lock is poisoned");
+    // Forget about entries whose last failure is older than the maximum lockout duration
+    attempts.retain(|_, entry| now.duration_since(entry.last_failure) < Duration::from_secs(CONFIG.login_lockout_max));
+    for key in keys {
+        let entry = attempts.entry(key.to_owned()).or_insert(Attempts { failures: 0, last_failure: now, locked_until: None });
+        entry.failures += 1;
+        entry.last_failure = now;
+        if entry.failures >= CONFIG.login_max_attempts {
+            // Double the lockout duration with every further failed attempt
+            let exponent = (entry.failures - CONFIG.login_max_attempts).min(31);
+            let duration = CONFIG.login_lockout_base.saturating_mul(1 << exponent).min(CONFIG.login_lockout_max);
+            entry.locked_until = Some(now + Duration::from_secs(duration));
+            let timestamp = OffsetDateTime::now_utc()
+                .format(format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"))
+                .expect("Cannot format timestamp");
+            eprintln!("{} rNAS {} lockout: ip={} {}={:?} key={:?} failures={} duration={}s",
+                      timestamp, kind, ip_string(ip), field, name, key, entry.failures, duration);
+        }
+    }
+}
+
+fn success(key: &str) {
+    ATTEMPTS.lock().expect("Login attempts lock is poisoned").remove(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::setup;
+
+    // The duration of the current lockout of a key
+    fn lockout(key: &str) -> Option<Duration> {
+        let attempts = ATTEMPTS.lock().expect("Login attempts lock is poisoned");
+        let entry = attempts.get(key)?;
+        entry.locked_until.map(|until| until.duration_since(entry.last_failure))
+    }
+
+    #[test]
+    fn share_guesses_do_not_lock_the_login() {
+        setup();
+        let ip = Some(IpAddr::from([192, 0, 2, 1]));
+        for _ in 0..CONFIG.login_max_attempts { register_share_failure(ip, "share_a"); }
+        assert!(is_share_locked(ip, "share_a"));
+        // Another share from the same address is locked as well, the login is not
+        assert!(is_share_locked(ip, "share_b"));
+        assert!(!is_locked(ip, "share_user"));
+        assert!(!is_share_locked(Some(IpAddr::from([192, 0, 2, 2])), "share_b"));
+    }
+
+    #[test]
+    fn login_success_keeps_the_address_counter() {
+        setup();
+        let ip = Some(IpAddr::from([192, 0, 2, 3]));
+        for _ in 0..CONFIG.login_max_attempts { register_failure(ip, "victim"); }
+        assert!(is_locked(Some(IpAddr::from([192, 0, 2, 4])), "victim"));
+        // A successful login only releases the account, the address stays locked for all other accounts
+        register_success("victim");
+        assert!(!is_locked(Some(IpAddr::from([192, 0, 2, 4])), "victim"));
+        assert!(is_locked(ip, "other"));
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure() {
+        setup();
+        let ip = Some(IpAddr::from([192, 0, 2, 5]));
+        let key = "ip:192.0.2.5";
+        for _ in 1..CONFIG.login_max_attempts { register_failure(ip, "doubling"); }
+        assert_eq!(lockout(key), None);
+        register_failure(ip, "doubling");
+        assert_eq!(lockout(key), Some(Duration::from_secs(CONFIG.login_lockout_base)));
+        register_failure(ip, "doubling");
+        assert_eq!(lockout(key), Some(Duration::from_secs(CONFIG.login_lockout_base * 2)));
+        // The duration is capped at the maximum
+        for _ in 0..32 { register_failure(ip, "doubling"); }
+        assert_eq!(lockout(key), Some(Duration::from_secs(CONFIG.login_lockout_max)));
+    }
+}
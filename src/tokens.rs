@@ -0,0 +1,315 @@
+/*
+This file contains the personal access tokens that allow scripts to use the file routes with a bearer token.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::fs::read_to_string;
+use sha2::{Sha384, Digest};
+use rocket::form::Form;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{ACCESS_DENIED, Auth, html, session, account, check_csrf, sanitize_string, unix_time, write_file_atomic};
+use crate::users::get_user;
+
+const TOKEN_PREFIX: &str = "rnas_";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    Read,
+    Upload,
+    Delete,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Delete => "delete",
+        }
+    }
+}
+
+pub struct Token {
+    pub hash: String,
+    pub user: String,
+    pub scopes: Vec<String>,
+    pub expires: u64,
+    pub path_prefix: String,
+    pub label: String,
+}
+
+impl Token {
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && unix_time() >= self.expires
+    }
+
+    // A token grants its scopes for the given path prefix inside the home directory of its user only
+    pub fn allows(&self, scope: Scope, path: &Path) -> bool {
+        !self.is_expired()
+            && self.scopes.iter().any(|name| name == scope.name())
+            && path.starts_with(Path::new(&self.user).join(&self.path_prefix))
+    }
+}
+
+#[derive(FromForm)]
+pub struct NewToken {
+    label: String,
+    read: bool,
+    upload: bool,
+    delete: bool,
+    path_prefix: String,
+    expires_in: Option<u64>,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+pub struct TokenHash {
+    hash: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+lazy_static! {
+    // Serialize all write access to the tokens file
+    static ref TOKENS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn get_tokens() -> Vec<Token> {
+    // A missing tokens file simply means that no token has been created yet
+    let content = read_to_string("tokens.csv").unwrap_or_default();
+    content.lines().filter_map(|line| {
+        // Each line has the format "hash;user;scopes;expires;path_prefix;label"
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() < 6 { return None }
+        Some(Token {
+            hash: fields[0].to_owned(),
+            user: fields[1].to_owned(),
+            scopes: fields[2].split(',').filter(|scope| !scope.is_empty()).map(str::to_owned).collect(),
+            expires: fields[3].parse().unwrap_or(0),
+            path_prefix: fields[4].to_owned(),
+            label: fields[5].to_owned(),
+        })
+    }).collect()
+}
+
+fn write_tokens(tokens: &[Token]) {
+    let mut content = String::new();
+    for token in tokens {
+        content.push_str(format!(
+            "{};{};{};{};{};{}\n", token.hash, token.user, token.scopes.join(","), token.expires, token.path_prefix, token.label
+        ).as_str());
+    }
+    write_file_atomic("tokens.csv", &content);
+}
+
+fn update_tokens(change: impl FnOnce(&mut Vec<Token>)) {
+    let _guard = TOKENS_LOCK.lock().expect("Tokens file lock is poisoned");
+    let mut tokens = get_tokens();
+    change(&mut tokens);
+    write_tokens(&tokens);
+}
+
+fn hash_token(secret: &str) -> String {
+    format!("{:x}", Sha384::digest(secret.trim()))
+}
+
+// Look up the token sent in an "Authorization: Bearer" header
+pub fn find_token(secret: &str) -> Option<Token> {
+    if !secret.starts_with(TOKEN_PREFIX) { return None }
+    let hash_value = hash_token(secret);
+    let token = get_tokens().into_iter().find(|token| token.hash == hash_value)?;
+    get_user(&token.user).filter(|user| user.enabled).map(|_| token)
+}
+
+pub fn remove_user_tokens(name: &str) {
+    update_tokens(|tokens| tokens.retain(|token| token.user != name));
+}
+
+pub fn rename_user_tokens(old_name: &str, new_name: &str) {
+    update_tokens(|tokens| {
+        for token in tokens.iter_mut().filter(|token| token.user == old_name) {
+            token.user = new_name.to_owned();
+        }
+    });
+}
+
+// The prefix is relative to the home directory and every component has to survive the sanitation unchanged
+fn check_path_prefix(input: &str) -> Option<String> {
+    let components: Vec<&str> = input.split('/').filter(|component| !component.is_empty()).collect();
+    if components.iter().all(|component| sanitize_string(component) == *component && !component.is_empty()) {
+        Some(components.join("/"))
+    } else {
+        None
+    }
+}
+
+// The token list and creation form on the account page
+pub fn token_section(username: &str, language: &str, csrf_token: &str) -> String {
+    let mut texts = ("API tokens", "No tokens created yet.", "Revoke", "label", "path prefix (optional)",
+                     "valid for days (empty = unlimited)", "Create token", "expires", "expired", "unlimited", "whole home directory");
+    if language == "de" {
+        texts = ("API-Tokens", "Bisher wurden keine Tokens erstellt.", "Widerrufen", "Bezeichnung", "Pfadpräfix (optional)",
+                 "gültig für Tage (leer = unbegrenzt)", "Token erstellen", "läuft ab", "abgelaufen", "unbegrenzt", "gesamtes Hauptverzeichnis");
+    }
+    let mut content = format!("<br>{}", html::paragraph(&format!("<b>{}</b>", texts.0)));
+    let tokens: Vec<Token> = get_tokens().into_iter().filter(|token| token.user == username).collect();
+    if tokens.is_empty() { content.push_str(&html::paragraph(texts.1)); }
+    for token in &tokens {
+        let expiry = if token.expires == 0 {
+            format!("{} {}", texts.7, texts.9)
+        } else if token.is_expired() {
+            texts.8.to_owned()
+        } else {
+            format!("{} {}", texts.7, html::date(token.expires))
+        };
+        let prefix = if token.path_prefix.is_empty() { texts.10.to_owned() } else { format!("/{}", token.path_prefix) };
+        content.push_str(&html::paragraph(&format!(
+            "<b>{}</b> &ensp; {} &ensp; {} &ensp; {}",
+            html::escape(&token.label), token.scopes.join(", "), html::escape(&prefix), expiry
+        )));
+        content.push_str(&html::form("/tokens/revoke", "post", &format!(
+            "{}{}{}", html::hidden("hash", &token.hash), html::hidden("csrf_token", csrf_token), html::submit(texts.2)
+        )));
+    }
+    let fields = format!(
+        "{}{}<br>{}{}{}<br>{}<br>{}<br>{}",
+        html::hidden("csrf_token", csrf_token), html::input("label", "text", texts.3), html::checkbox("read", Scope::Read.name()), html::checkbox("upload", Scope::Upload.name()),
+        html::checkbox("delete", Scope::Delete.name()), html::input("path_prefix", "text", texts.4),
+        html::input("expires_in", "number", texts.5), html::submit(texts.6)
+    );
+    content.push_str(&format!("<br>{}", html::form("/tokens", "post", &fields)));
+    content
+}
+
+#[post("/tokens", data = "<data>")]
+pub fn create_token(auth: Auth<'_>, data: Option<Form<NewToken>>) -> Result<RawHtml<String>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "create_token", Path::new(""))?;
+    let Some(username) = auth.user() else { return Ok(RawHtml(auth.page(&ACCESS_DENIED))) };
+    let settings = auth.settings();
+    let mut texts = ("API token", "The token has been created. It is shown only once, so please copy it now:",
+                     "Please select at least one scope and enter a valid path prefix.", "Back");
+    if settings.language == "de" {
+        texts = ("API-Token", "Das Token wurde erstellt. Es wird nur einmal angezeigt und sollte daher jetzt kopiert werden:",
+                 "Bitte mindestens einen Bereich auswählen und ein gültiges Pfadpräfix angeben.", "Zurück");
+    }
+    let back = html::link(&uri!(account::account).to_string(), texts.3);
+    let Some(data) = data else { return Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.2), back)))) };
+    let scopes: Vec<String> = [(data.read, Scope::Read), (data.upload, Scope::Upload), (data.delete, Scope::Delete)]
+        .iter().filter(|(selected, _)| *selected).map(|(_, scope)| scope.name().to_owned()).collect();
+    let Some(path_prefix) = check_path_prefix(&data.path_prefix) else {
+        return Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.2), back))))
+    };
+    if scopes.is_empty() {
+        return Ok(RawHtml(html::page(&settings, texts.0, &format!("{}{}", html::error(texts.2), back))))
+    }
+    let mut label = sanitize_string(&data.label);
+    if label.is_empty() { label = "token".to_owned(); }
+    let secret = format!("{}{}", TOKEN_PREFIX, session::random_token());
+    let expires = match data.expires_in {
+        Some(days) if days > 0 => unix_time().saturating_add(days.saturating_mul(86400)),
+        _ => 0
+    };
+    update_tokens(|tokens| tokens.push(Token { hash: hash_token(&secret), user: username, scopes, expires, path_prefix, label }));
+    Ok(RawHtml(html::page(&settings, texts.0, &format!("{}<pre style=\"font-size:16px\">{}</pre>{}", html::paragraph(texts.1), secret, back))))
+}
+
+#[post("/tokens/revoke", data = "<data>")]
+pub fn revoke_token(auth: Auth<'_>, data: Option<Form<TokenHash>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "revoke_token", Path::new(""))?;
+    let (Some(username), Some(data)) = (auth.user(), data) else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    update_tokens(|tokens| tokens.retain(|token| token.user != username || token.hash != data.hash));
+    Ok(Either::Left(Redirect::to(uri!(account::account))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use rocket::http::Header;
+    use crate::{STORAGE, download_file};
+    use crate::users::{Role, update_user};
+    use crate::test_support::{client, test_user};
+
+    fn token(scopes: &[&str], path_prefix: &str, expires: u64) -> Token {
+        Token {
+            hash: String::new(), user: "token_user".to_owned(), scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires, path_prefix: path_prefix.to_owned(), label: "test".to_owned(),
+        }
+    }
+
+    // Store a new token of the user and return its secret
+    fn create(user: &str, scopes: &[&str], path_prefix: &str) -> String {
+        test_user(user, Role::User);
+        let secret = format!("{}{}", TOKEN_PREFIX, session::random_token());
+        let token = Token { hash: hash_token(&secret), user: user.to_owned(), ..token(scopes, path_prefix, 0) };
+        update_tokens(|tokens| tokens.push(token));
+        secret
+    }
+
+    #[test]
+    fn scopes_and_prefix_limit_tokens() {
+        let scoped = token(&["read", "upload"], "docs", 0);
+        assert!(scoped.allows(Scope::Read, Path::new("token_user/docs")));
+        assert!(scoped.allows(Scope::Upload, Path::new("token_user/docs/report.pdf")));
+        assert!(!scoped.allows(Scope::Delete, Path::new("token_user/docs/report.pdf")));
+        // The prefix is compared by whole components and never reaches other homes
+        assert!(!scoped.allows(Scope::Read, Path::new("token_user/docs2")));
+        assert!(!scoped.allows(Scope::Read, Path::new("token_user")));
+        assert!(!scoped.allows(Scope::Read, Path::new("other_user/docs")));
+
+        assert!(token(&["read"], "", 0).allows(Scope::Read, Path::new("token_user/any")));
+        assert!(!token(&["read"], "", unix_time() - 1).allows(Scope::Read, Path::new("token_user/any")));
+    }
+
+    #[test]
+    fn path_prefixes_are_normalized() {
+        assert_eq!(check_path_prefix("").as_deref(), Some(""));
+        assert_eq!(check_path_prefix("/docs//backups/").as_deref(), Some("docs/backups"));
+        assert_eq!(check_path_prefix("docs/../other"), None);
+    }
+
+    #[test]
+    fn secrets_need_prefix_and_enabled_user() {
+        let secret = create("token_lookup", &["read"], "");
+        assert!(find_token(&secret).is_some_and(|token| token.user == "token_lookup"));
+        assert!(find_token(&secret[TOKEN_PREFIX.len()..]).is_none());
+        assert!(find_token(&format!("{}{}", TOKEN_PREFIX, session::random_token())).is_none());
+        update_user("token_lookup", |user| user.enabled = false);
+        assert!(find_token(&secret).is_none());
+    }
+
+    #[test]
+    fn bearer_tokens_download_inside_their_prefix() {
+        let secret = create("token_download", &["read"], "docs");
+        create_dir_all(STORAGE.join("token_download/docs")).expect("Cannot create test directory");
+        write(STORAGE.join("token_download/docs/file.txt"), "inside").expect("Cannot create test file");
+        write(STORAGE.join("token_download/file.txt"), "outside").expect("Cannot create test file");
+        let client = client(routes![download_file]);
+        let download = |path: &str| client.get(format!("/download/{}", path))
+            .header(Header::new("Authorization", format!("Bearer {}", secret))).dispatch().into_string();
+        assert_eq!(download("token_download/docs/file.txt").as_deref(), Some("inside"));
+        assert_eq!(download("token_download/file.txt"), Some(ACCESS_DENIED.to_owned()));
+    }
+}
@@ -0,0 +1,156 @@
+/*
+This file contains the time-based one-time passwords (RFC 6238) and recovery codes for the two-factor authentication.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha1::Sha1;
+use sha2::{Sha384, Digest};
+use hmac::{Hmac, Mac};
+use qrcode::QrCode;
+use qrcode::render::svg;
+use data_encoding::BASE32_NOPAD;
+use rocket::http::RawStr;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::CONFIG;
+
+const TIME_STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+
+lazy_static! {
+    // Remember the last accepted time step per user, so a code cannot be used twice
+    static ref LAST_USED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+fn code_at(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    // Dynamic truncation as described in RFC 4226
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    binary % 10u32.pow(DIGITS)
+}
+
+pub fn verify_code(name: &str, secret: &str, code: &str) -> bool {
+    let code = code.trim();
+    if code.len() != DIGITS as usize { return false }
+    let (Ok(code), Ok(secret)) = (code.parse::<u32>(), BASE32_NOPAD.decode(secret.as_bytes())) else { return false };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("System time is before the UNIX epoch").as_secs() / TIME_STEP;
+    let mut last_used = LAST_USED.lock().expect("TOTP lock is poisoned");
+    // Accept the previous and the next time step as well to allow for a small clock drift
+    for counter in [now - 1, now, now + 1] {
+        if code_at(&secret, counter) == code && last_used.get(name).is_none_or(|last| counter > *last) {
+            last_used.insert(name.to_owned(), counter);
+            return true
+        }
+    }
+    false
+}
+
+pub fn otpauth_uri(name: &str, secret: &str) -> String {
+    let issuer = if CONFIG.owner.is_empty() { "rNAS".to_owned() } else { format!("{} rNAS", CONFIG.owner) };
+    format!(
+        "otpauth://totp/{0}:{1}?secret={2}&issuer={0}&algorithm=SHA1&digits={3}&period={4}",
+        RawStr::new(&issuer).percent_encode(), RawStr::new(name).percent_encode(), secret, DIGITS, TIME_STEP
+    )
+}
+
+pub fn qr_code_svg(uri: &str) -> String {
+    QrCode::new(uri.as_bytes()).expect("Cannot encode QR code").render::<svg::Color>().min_dimensions(200, 200).build()
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+pub fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha384::digest(normalize_recovery_code(code)))
+}
+
+// Remove the hash of the code from the stored recovery codes, returns false if the code is unknown or has been used before
+pub fn consume_recovery_code(hashes: &mut Vec<String>, code: &str) -> bool {
+    let code_hash = hash_recovery_code(code);
+    let count = hashes.len();
+    hashes.retain(|hash| *hash != code_hash);
+    hashes.len() < count
+}
+
+// Returns the readable recovery codes together with their hashes to be stored in the users file
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let mut codes = Vec::new();
+    for _ in 0..RECOVERY_CODES {
+        let mut bytes = [0u8; 5];
+        OsRng.fill_bytes(&mut bytes);
+        let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+        codes.push(format!("{}-{}", &code[..4], &code[4..]));
+    }
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors of RFC 6238 (Appendix B) for SHA-1, the expected values are the last six of the eight digits
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        let secret = b"12345678901234567890";
+        let vectors = [(59, 287082), (1111111109, 81804), (1111111111, 50471), (1234567890, 5924), (2000000000, 279037), (20000000000, 353130)];
+        for (time, expected) in vectors {
+            assert_eq!(code_at(secret, time / TIME_STEP), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn code_is_accepted_once() {
+        let secret = generate_secret();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / TIME_STEP;
+        let code = format!("{:06}", code_at(&BASE32_NOPAD.decode(secret.as_bytes()).unwrap(), now));
+        assert!(verify_code("totp_once", &secret, &code));
+        assert!(!verify_code("totp_once", &secret, &code));
+    }
+
+    #[test]
+    fn wrong_codes_are_refused() {
+        let secret = generate_secret();
+        assert!(!verify_code("totp_wrong", &secret, "12345"));
+        assert!(!verify_code("totp_wrong", &secret, "abcdef"));
+    }
+
+    #[test]
+    fn recovery_code_is_accepted_once() {
+        let (codes, mut hashes) = generate_recovery_codes();
+        assert_eq!(hashes.len(), RECOVERY_CODES);
+        // Recovery codes are accepted without the separator and in upper case as well
+        let code = codes[0].replace('-', "").to_uppercase();
+        assert!(consume_recovery_code(&mut hashes, &code));
+        assert!(!consume_recovery_code(&mut hashes, &codes[0]));
+        assert_eq!(hashes.len(), RECOVERY_CODES - 1);
+        assert!(consume_recovery_code(&mut hashes, &codes[1]));
+    }
+}
@@ -0,0 +1,509 @@
+/*
+This file contains renaming, moving and copying of files and directories.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::thread;
+use std::sync::Mutex;
+use std::net::IpAddr;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use std::path::{Component, Path, PathBuf};
+use std::fs::{File, Metadata, create_dir, hard_link, read_dir, remove_dir, remove_dir_all, remove_file, rename};
+use rocket::form::Form;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{STORAGE, ACCESS_DENIED, READ_ONLY, NO_FILE, NO_DIRECTORY, IS_FILE, IS_DIRECTORY, MOVE_ERROR, QUOTA_EXCEEDED, Auth, DotPathBuf, Settings,
+            audit, groups, html, internal, session, check_login, check_csrf, check_path, directory_size, exceeds_quota, sanitize_string};
+use crate::tokens::Scope;
+
+// Finished copy jobs are kept for the progress page until they expire (in seconds)
+const JOB_LIFETIME: u64 = 86400;
+
+#[derive(FromForm)]
+pub struct Target {
+    pub name: String,
+    pub destination: String,
+    pub conflict: String,
+    #[field(default = String::new())]
+    pub csrf_token: String,
+}
+
+enum JobState {
+    Running,
+    Done,
+    StorageFull,
+    Failed(String),
+}
+
+// A copy that runs in the background, its progress is shown on the job page
+struct Job {
+    user: String,
+    source: String,
+    target: String,
+    total: u64,
+    copied: u64,
+    state: JobState,
+    started: Instant,
+}
+
+lazy_static! {
+    static ref JOBS: Mutex<HashMap<String, Job>> = Mutex::new(HashMap::new());
+}
+
+fn update_job(id: &str, change: impl FnOnce(&mut Job)) {
+    if let Some(job) = JOBS.lock().expect("Job list lock is poisoned").get_mut(id) { change(job); }
+}
+
+// Copy a file or a directory with all of its contents and modification times, the copied bytes are reported to the progress function.
+// An incomplete copy is removed again, but only if the target has been created by this copy: an entry that already exists
+// (e.g. created by another job in the meantime) makes the copy fail without touching it
+pub fn copy_recursive(source: &Path, target: &Path, progress: &mut dyn FnMut(u64)) -> io::Result<()> {
+    let metadata = source.metadata()?;
+    if metadata.is_dir() {
+        create_dir(target)?;
+        let result = copy_directory(source, target, &metadata, progress);
+        if result.is_err() { let _ = remove_dir_all(target); }
+        result
+    } else {
+        let writer = File::create_new(target)?;
+        let result = copy_file(source, writer, &metadata, progress);
+        if result.is_err() { let _ = remove_file(target); }
+        result
+    }
+}
+
+fn copy_directory(source: &Path, target: &Path, metadata: &Metadata, progress: &mut dyn FnMut(u64)) -> io::Result<()> {
+    for item in read_dir(source)? {
+        let item = item?;
+        copy_recursive(&item.path(), &target.join(item.file_name()), progress)?;
+    }
+    // Adding the contents changes the modification time of the directory, so it is set at the end
+    File::open(target)?.set_modified(metadata.modified()?)
+}
+
+fn copy_file(source: &Path, mut writer: File, metadata: &Metadata, progress: &mut dyn FnMut(u64)) -> io::Result<()> {
+    let mut reader = File::open(source)?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error)
+        };
+        writer.write_all(&buffer[..count])?;
+        progress(count as u64);
+    }
+    writer.set_permissions(metadata.permissions())?;
+    writer.set_modified(metadata.modified()?)
+}
+
+pub fn remove_path(path: &Path) -> io::Result<()> {
+    if path.is_dir() { remove_dir_all(path) } else { remove_file(path) }
+}
+
+// Rename inside the same filesystem, otherwise copy the source and delete it afterwards. An entry that has been created
+// at the target in the meantime is never replaced, the move fails with AlreadyExists instead: a file gets a hard link,
+// a directory is renamed onto an empty directory that this move has created itself
+pub fn move_path(source: &Path, target: &Path) -> io::Result<()> {
+    let metadata = source.symlink_metadata()?;
+    if metadata.is_dir() {
+        create_dir(target)?;
+        match rename(source, target) {
+            Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+                // The source is kept if the copy fails
+                if let Err(error) = copy_directory(source, target, &metadata, &mut |_| {}) {
+                    let _ = remove_dir_all(target);
+                    return Err(error)
+                }
+                remove_path(source)
+            }
+            Err(error) => {
+                let _ = remove_dir(target);
+                Err(error)
+            }
+            result => result
+        }
+    } else {
+        match hard_link(source, target) {
+            Ok(()) => remove_file(source),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Err(error),
+            Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+                copy_recursive(source, target, &mut |_| {})?;
+                remove_file(source)
+            }
+            // Filesystems without hard links get an empty file first, which only this move replaces
+            Err(_) => {
+                File::create_new(target)?;
+                let result = rename(source, target);
+                if result.is_err() { let _ = remove_file(target); }
+                result
+            }
+        }
+    }
+}
+
+// The error page of a failed move, e.g. with a full disk or a source that has been removed in the meantime
+pub fn move_error(error: &io::Error) -> &'static str {
+    if error.kind() == io::ErrorKind::NotFound { NO_FILE.as_str() } else { MOVE_ERROR.as_str() }
+}
+
+// Find a free name inside the directory by appending a number, e.g. "report (1).pdf" for files
+pub fn free_name(directory: &Path, name: &str, is_file: bool) -> String {
+    let path = Path::new(name);
+    let (stem, extension) = match (path.file_stem().and_then(|stem| stem.to_str()), path.extension().and_then(|extension| extension.to_str())) {
+        (Some(stem), Some(extension)) if is_file => (stem, format!(".{}", extension)),
+        _ => (name, String::new())
+    };
+    (1..).map(|number| format!("{} ({}){}", stem, number, extension))
+        .find(|candidate| !STORAGE.join(directory).join(candidate).exists())
+        .expect("Cannot find a free name")
+}
+
+// Only plain relative paths are accepted as destination, so a move cannot leave the storage path
+fn parse_destination(destination: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(destination.trim_matches('/'));
+    if path.components().all(|component| matches!(component, Component::Normal(_))) { Some(path) } else { None }
+}
+
+// All directories below the root that the sources can be moved to, the sources themselves and their subdirectories are excluded
+pub fn destinations(auth: &Auth<'_>, root: &Path, sources: &[PathBuf]) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        if sources.iter().any(|source| directory.starts_with(source)) { continue }
+        if check_login(auth, &directory, Scope::Upload).is_some() {
+            found.push(directory.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned());
+        }
+        let Ok(entries) = read_dir(STORAGE.join(&directory)) else { continue };
+        for item in entries.flatten() {
+            if item.path().is_dir() { pending.push(directory.join(item.file_name())); }
+        }
+    }
+    found.sort_by_key(|name| name.to_lowercase());
+    found
+}
+
+// The selection of the destination folder and the conflict handling for moving or copying
+pub fn destination_fields(language: &str, destinations: Vec<String>, selected: &str) -> String {
+    let mut labels = ("If the name already exists", "cancel", "keep both (add a number)");
+    if language == "de" {
+        labels = ("Falls der Name bereits existiert", "abbrechen", "beide behalten (Nummer anhängen)");
+    }
+    let folders: Vec<(String, String)> = destinations.into_iter()
+        .map(|folder| (html::escape(&folder), format!("/{}", html::escape(&folder))))
+        .collect();
+    let options: Vec<(&str, &str)> = folders.iter().map(|(value, label)| (value.as_str(), label.as_str())).collect();
+    let conflicts = [("refuse", labels.1), ("keep_both", labels.2)];
+    format!(
+        "{}<br>{}<br>{}",
+        html::select("destination", &options, &html::escape(selected)), html::paragraph(labels.0), html::select("conflict", &conflicts, "refuse")
+    )
+}
+
+// The form to choose the new name, the destination and the conflict handling for moving or copying
+fn target_form(auth: &Auth<'_>, settings: &Settings, path: &Path, action: &str, destinations: Vec<String>, texts: (&str, &str, &str)) -> RawHtml<String> {
+    let back = if settings.language == "de" { "Zurück" } else { "Back" };
+    let path_string = path.to_str().expect("Invalid path encoding (expected UTF-8)");
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let parent = path.parent().and_then(|parent| parent.to_str()).unwrap_or_default();
+    let fields = format!(
+        "{}{}<br>{}<br>{}",
+        html::hidden("csrf_token", &auth.csrf_token()), html::input_value("name", name), destination_fields(&settings.language, destinations, parent), html::submit(texts.2)
+    );
+    RawHtml(html::page(settings, texts.0, &format!(
+        "{}{}<br>{}",
+        html::paragraph(&format!("{} <b>/{}</b>", texts.1, html::escape(path_string))),
+        html::form(&format!("/{}/{}", action, path_string), "post", &fields),
+        html::link(&uri!(crate::list_directory(parent)).to_string(), back)
+    )))
+}
+
+#[get("/move/<path..>")]
+pub fn move_form(auth: Auth<'_>, path: DotPathBuf) -> RawHtml<String> {
+    let path = path.0;
+    let Some(root) = check_login(&auth, &path, Scope::Delete) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    if auth.is_read_only() { return RawHtml(auth.page(&READ_ONLY)) }
+    // The home directory and the roots of group folders and shares cannot be moved
+    if path == Path::new(&root) { return RawHtml(auth.page(&ACCESS_DENIED)) }
+    if !STORAGE.join(&path).exists() { return RawHtml(auth.page(&NO_FILE)) }
+    let settings = auth.settings();
+    let mut texts = ("Rename or move", "New name and destination of", "Move");
+    if settings.language == "de" {
+        texts = ("Umbenennen oder verschieben", "Neuer Name und Ziel von", "Verschieben");
+    }
+    let destinations = destinations(&auth, Path::new(&root), &[path.to_owned()]);
+    target_form(&auth, &settings, &path, "move", destinations, texts)
+}
+
+#[post("/move/<path..>", data = "<data>")]
+pub fn move_entry(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<Target>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    let Some(data) = data else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    // Both the source and the requested target are recorded in the audit log
+    let target = Path::new(data.destination.trim_matches('/')).join(sanitize_string(&data.name));
+    let entry = PathBuf::from(format!("{} -> {}", path.display(), target.display()));
+    check_csrf(&auth, Some(&data.csrf_token), "move", &entry)?;
+    let response = move_response(&auth, &path, &data);
+    audit::record_response(&auth, "move", &entry, &response);
+    Ok(auth.localize(response))
+}
+
+// Determine the path of the moved or copied entry inside the destination according to the chosen conflict handling
+fn resolve_target(path: &Path, destination: &Path, data: &Target, is_file: bool) -> Result<PathBuf, RawHtml<String>> {
+    // Remove some unwanted characters from the new name (custom selection), an empty name keeps the current one
+    let mut name = sanitize_string(&data.name);
+    if name.is_empty() {
+        name = path.file_name().and_then(|name| name.to_str()).expect("Cannot extract file name").to_owned();
+    }
+    let target = destination.join(&name);
+    if target == path || !STORAGE.join(&target).try_exists().expect("Cannot access files metadata (permission error)") {
+        return Ok(target)
+    }
+    if data.conflict != "keep_both" {
+        return Err(RawHtml(if STORAGE.join(&target).is_dir() { IS_DIRECTORY.to_owned() } else { IS_FILE.to_owned() }))
+    }
+    Ok(destination.join(free_name(destination, &name, is_file)))
+}
+
+pub fn move_response(auth: &Auth<'_>, path: &Path, data: &Target) -> Either<Redirect, RawHtml<String>> {
+    let Some(root) = check_login(auth, path, Scope::Delete) else { return Either::Right(RawHtml(ACCESS_DENIED.to_owned())) };
+    if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+    if path == Path::new(&root) { return Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
+    let (is_file, is_directory) = check_path(path);
+    if !is_file && !is_directory { return Either::Right(RawHtml(NO_FILE.to_owned())) }
+    // The destination has to be a writable directory inside the same root, a directory cannot be moved into itself
+    let Some(destination) = parse_destination(&data.destination) else { return Either::Right(RawHtml(ACCESS_DENIED.to_owned())) };
+    if !destination.starts_with(&root) || destination.starts_with(path) || check_login(auth, &destination, Scope::Upload).is_none() {
+        return Either::Right(RawHtml(ACCESS_DENIED.to_owned()))
+    }
+    if !check_path(&destination).1 { return Either::Right(RawHtml(NO_DIRECTORY.to_owned())) }
+    loop {
+        let target = match resolve_target(path, &destination, data, is_file) {
+            Ok(target) => target,
+            Err(page) => return Either::Right(page)
+        };
+        if target == path { break }
+        match move_path(&STORAGE.join(path), &STORAGE.join(&target)) {
+            Ok(()) => break,
+            // The name has been taken since the check, so the conflict handling is applied again
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Either::Right(RawHtml(move_error(&error).to_owned()))
+        }
+    }
+    Either::Left(Redirect::to(uri!(crate::list_directory(destination.to_str().expect("Invalid path encoding (expected UTF-8)")))))
+}
+
+// All directories the user can copy to: the home directory, writable group folders and directories shared with write access
+pub fn copy_destinations(auth: &Auth<'_>, username: &str, sources: &[PathBuf]) -> Vec<String> {
+    let mut roots = vec![username.to_owned()];
+    roots.extend(groups::user_folders(username).into_iter()
+        .filter(|folder| groups::allows(username, folder, Scope::Upload))
+        .map(|folder| format!("{}/{}", groups::SHARED_DIRECTORY, folder)));
+    roots.extend(internal::writable_roots(username));
+    let mut found: Vec<String> = roots.iter().flat_map(|root| destinations(auth, Path::new(root), sources)).collect();
+    found.sort_by_key(|name| name.to_lowercase());
+    found.dedup();
+    found
+}
+
+#[get("/copy/<path..>")]
+pub fn copy_form(auth: Auth<'_>, path: DotPathBuf) -> RawHtml<String> {
+    let path = path.0;
+    let (Some(username), Some(_root)) = (auth.user(), check_login(&auth, &path, Scope::Read)) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    if auth.is_read_only() { return RawHtml(auth.page(&READ_ONLY)) }
+    if !STORAGE.join(&path).exists() { return RawHtml(auth.page(&NO_FILE)) }
+    let settings = auth.settings();
+    let mut texts = ("Copy", "Name and destination of the copy of", "Copy");
+    if settings.language == "de" {
+        texts = ("Kopieren", "Name und Ziel der Kopie von", "Kopieren");
+    }
+    let destinations = copy_destinations(&auth, &username, &[path.to_owned()]);
+    target_form(&auth, &settings, &path, "copy", destinations, texts)
+}
+
+#[post("/copy/<path..>", data = "<data>")]
+pub fn copy_entry(auth: Auth<'_>, path: DotPathBuf, data: Option<Form<Target>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let path = path.0;
+    let Some(data) = data else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    let target = Path::new(data.destination.trim_matches('/')).join(sanitize_string(&data.name));
+    let entry = PathBuf::from(format!("{} -> {}", path.display(), target.display()));
+    check_csrf(&auth, Some(&data.csrf_token), "copy", &entry)?;
+    let response = copy_response(&auth, &path, &data);
+    // A started copy is recorded by the job when it has finished
+    if let Either::Right(_) = response { audit::record_response(&auth, "copy", &entry, &response); }
+    Ok(auth.localize(response))
+}
+
+fn copy_response(auth: &Auth<'_>, path: &Path, data: &Target) -> Either<Redirect, RawHtml<String>> {
+    match start_copy(auth, path, data) {
+        Ok(id) => Either::Left(Redirect::to(uri!(job_status(id)))),
+        Err(page) => Either::Right(page)
+    }
+}
+
+// Check the copy and start it in the background, returns the id of the job
+pub fn start_copy(auth: &Auth<'_>, path: &Path, data: &Target) -> Result<String, RawHtml<String>> {
+    let (Some(user), Some(_root)) = (auth.actor(), check_login(auth, path, Scope::Read)) else { return Err(RawHtml(ACCESS_DENIED.to_owned())) };
+    if auth.is_read_only() { return Err(RawHtml(READ_ONLY.to_owned())) }
+    let (is_file, is_directory) = check_path(path);
+    if !is_file && !is_directory { return Err(RawHtml(NO_FILE.to_owned())) }
+    // The destination has to be a writable directory, a directory cannot be copied into itself
+    let Some(destination) = parse_destination(&data.destination) else { return Err(RawHtml(ACCESS_DENIED.to_owned())) };
+    if destination.starts_with(path) || check_login(auth, &destination, Scope::Upload).is_none() {
+        return Err(RawHtml(ACCESS_DENIED.to_owned()))
+    }
+    if !check_path(&destination).1 { return Err(RawHtml(NO_DIRECTORY.to_owned())) }
+    let target = match resolve_target(path, &destination, data, is_file) {
+        Ok(target) if target != path => target,
+        Ok(_) => return Err(RawHtml(IS_FILE.to_owned())),
+        Err(page) => return Err(page)
+    };
+    // Refuse copies that would exceed the quota of the owner of the destination
+    let total = if is_directory { directory_size(&STORAGE.join(path)) } else { STORAGE.join(path).metadata().map_or(0, |metadata| metadata.len()) };
+    if exceeds_quota(&destination, total) { return Err(RawHtml(QUOTA_EXCEEDED.to_owned())) }
+    Ok(start_job(user, auth.ip, path, &target, total))
+}
+
+fn start_job(user: String, ip: Option<IpAddr>, source: &Path, target: &Path, total: u64) -> String {
+    let id = session::random_token();
+    let source = source.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+    let target = target.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+    {
+        let mut jobs = JOBS.lock().expect("Job list lock is poisoned");
+        jobs.retain(|_, job| matches!(job.state, JobState::Running) || job.started.elapsed() < Duration::from_secs(JOB_LIFETIME));
+        jobs.insert(id.to_owned(), Job {
+            user: user.to_owned(), source: source.to_owned(), target: target.to_owned(), total, copied: 0, state: JobState::Running,
+            started: Instant::now()
+        });
+    }
+    let job_id = id.to_owned();
+    thread::spawn(move || {
+        let result = copy_recursive(&STORAGE.join(&source), &STORAGE.join(&target), &mut |bytes| update_job(&job_id, |job| job.copied += bytes));
+        // The incomplete copy has already been removed by the copy itself
+        let state = match result {
+            Ok(()) => JobState::Done,
+            Err(error) if error.kind() == io::ErrorKind::StorageFull => JobState::StorageFull,
+            Err(error) => JobState::Failed(error.to_string())
+        };
+        let result = match &state {
+            JobState::Done => "success",
+            JobState::StorageFull => "storage_full",
+            _ => "error"
+        };
+        audit::record(Some(&user), ip, "copy", Path::new(&format!("{} -> {}", source, target)), result);
+        update_job(&job_id, |job| job.state = state);
+    });
+    id
+}
+
+#[get("/jobs/<id>")]
+pub fn job_status(auth: Auth<'_>, id: &str) -> RawHtml<String> {
+    let Some(user) = auth.actor() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let jobs = JOBS.lock().expect("Job list lock is poisoned");
+    let Some(job) = jobs.get(id).filter(|job| job.user == user) else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = auth.settings();
+    let mut texts = ("Copy", "Copying", "to", "The copy has been completed.", "There is not enough storage space left, the incomplete copy has been removed.",
+                     "The copy has failed, the incomplete copy has been removed:", "Open destination", "of");
+    if settings.language == "de" {
+        texts = ("Kopieren", "Kopiere", "nach", "Die Kopie ist abgeschlossen.", "Es ist nicht genügend Speicherplatz frei, die unvollständige Kopie wurde entfernt.",
+                 "Die Kopie ist fehlgeschlagen, die unvollständige Kopie wurde entfernt:", "Ziel öffnen", "von");
+    }
+    let mut content = html::paragraph(&format!(
+        "{} <b>/{}</b> {} <b>/{}</b>", texts.1, html::escape(&job.source), texts.2, html::escape(&job.target)
+    ));
+    let percent = (job.copied.min(job.total) * 100).checked_div(job.total).unwrap_or(100);
+    match &job.state {
+        JobState::Running => content.push_str(&format!(
+            // Reload the page until the copy has finished
+            "{}<meta http-equiv=\"refresh\" content=\"2\">",
+            html::paragraph(&format!("{}% &ensp; {:.1} MiB {} {:.1} MiB", percent, job.copied as f64 / 1024.0 / 1024.0, texts.7,
+                                     job.total as f64 / 1024.0 / 1024.0))
+        )),
+        JobState::Done => content.push_str(&html::paragraph(texts.3)),
+        JobState::StorageFull => content.push_str(&html::error(texts.4)),
+        JobState::Failed(error) => content.push_str(&html::error(&format!("{} {}", texts.5, html::escape(error))))
+    }
+    let destination = Path::new(&job.target).parent().and_then(|parent| parent.to_str()).unwrap_or_default();
+    content.push_str(&html::link(&uri!(crate::list_directory(destination)).to_string(), texts.6));
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, write};
+    use crate::test_support::setup;
+
+    #[test]
+    fn copy_keeps_existing_target() {
+        setup();
+        let base = Path::new("copy_test");
+        create_dir_all(base.join("source/inner")).expect("Cannot create test directory");
+        write(base.join("source/inner/data.txt"), "source").expect("Cannot write test file");
+        write(base.join("source.txt"), "source").expect("Cannot write test file");
+        // Another job has created both targets in the meantime
+        create_dir_all(base.join("target")).expect("Cannot create test directory");
+        write(base.join("target/existing.txt"), "existing").expect("Cannot write test file");
+        write(base.join("target.txt"), "existing").expect("Cannot write test file");
+
+        let error = copy_recursive(&base.join("source"), &base.join("target"), &mut |_| {}).expect_err("Copy onto a directory succeeded");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(base.join("target/existing.txt")).expect("Existing directory has been removed"), "existing");
+        let error = copy_recursive(&base.join("source.txt"), &base.join("target.txt"), &mut |_| {}).expect_err("Copy onto a file succeeded");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(base.join("target.txt")).expect("Existing file has been removed"), "existing");
+
+        // A free target receives the complete copy
+        copy_recursive(&base.join("source"), &base.join("copy"), &mut |_| {}).expect("Cannot copy directory");
+        assert_eq!(read_to_string(base.join("copy/inner/data.txt")).expect("Copy is incomplete"), "source");
+    }
+
+    #[test]
+    fn move_keeps_existing_target() {
+        setup();
+        let base = Path::new("move_test");
+        create_dir_all(base.join("source/inner")).expect("Cannot create test directory");
+        write(base.join("source/inner/data.txt"), "source").expect("Cannot write test file");
+        write(base.join("source.txt"), "source").expect("Cannot write test file");
+        // Another request has created both targets in the meantime, an empty directory would be replaced by a plain rename
+        create_dir_all(base.join("target")).expect("Cannot create test directory");
+        write(base.join("target.txt"), "existing").expect("Cannot write test file");
+
+        let error = move_path(&base.join("source"), &base.join("target")).expect_err("Move onto a directory succeeded");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(base.join("source/inner/data.txt").is_file());
+        let error = move_path(&base.join("source.txt"), &base.join("target.txt")).expect_err("Move onto a file succeeded");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(base.join("target.txt")).expect("Existing file has been removed"), "existing");
+        assert!(base.join("source.txt").is_file());
+
+        // Free targets receive the entries, a missing source is reported as such
+        move_path(&base.join("source"), &base.join("moved")).expect("Cannot move directory");
+        move_path(&base.join("source.txt"), &base.join("moved.txt")).expect("Cannot move file");
+        assert_eq!(read_to_string(base.join("moved/inner/data.txt")).expect("Move is incomplete"), "source");
+        assert_eq!(read_to_string(base.join("moved.txt")).expect("Move is incomplete"), "source");
+        assert!(!base.join("source").exists() && !base.join("source.txt").exists());
+        let error = move_path(&base.join("source.txt"), &base.join("other.txt")).expect_err("Move of a missing file succeeded");
+        assert_eq!(move_error(&error), NO_FILE.as_str());
+    }
+}
@@ -0,0 +1,280 @@
+/*
+This file contains the trash that keeps deleted files and directories until they are restored or purged.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::fs::{create_dir_all, read_to_string, rename};
+use serde::{Deserialize, Serialize};
+use rocket::form::Form;
+use rocket::response::Redirect;
+use rocket::response::status::Forbidden;
+use rocket::response::content::RawHtml;
+use rocket::{Either, FromForm};
+use crate::{CONFIG, STORAGE, ACCESS_DENIED, READ_ONLY, QUOTA_EXCEEDED, Auth, CsrfToken, DotPathBuf, audit, html, session, transfer,
+            check_login, check_csrf, directory_size, exceeds_quota, unix_time, write_file_atomic};
+use crate::tokens::Scope;
+
+// Directory inside the storage path that contains the deleted entries of all users
+pub const TRASH_DIRECTORY: &str = "trash";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TrashEntry {
+    id: String,
+    // The user who deleted the entry
+    user: String,
+    // Original path relative to the storage path
+    path: String,
+    deleted: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TrashFile {
+    #[serde(default)]
+    entry: Vec<TrashEntry>,
+}
+
+#[derive(FromForm)]
+pub struct TrashItem {
+    id: String,
+    #[field(default = String::new())]
+    csrf_token: String,
+}
+
+lazy_static! {
+    // Serialize all write access to the trash file
+    static ref TRASH_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn get_trash() -> Vec<TrashEntry> {
+    // A missing file simply means that nothing has been deleted yet
+    let Ok(content) = read_to_string("trash.toml") else { return Vec::new() };
+    toml::from_str::<TrashFile>(&content).expect("Trash file formatting is invalid").entry
+}
+
+fn update_trash(change: impl FnOnce(&mut Vec<TrashEntry>)) {
+    let _guard = TRASH_LOCK.lock().expect("Trash file lock is poisoned");
+    let mut entries = get_trash();
+    change(&mut entries);
+    let content = toml::to_string(&TrashFile { entry: entries }).expect("Cannot serialize trash file");
+    write_file_atomic("trash.toml", &content);
+}
+
+fn stored_path(entry: &TrashEntry) -> PathBuf {
+    STORAGE.join(TRASH_DIRECTORY).join(&entry.user).join(&entry.id)
+}
+
+// Move a deleted file or directory into the trash of the user, without a retention it is deleted permanently
+pub fn move_to_trash(user: &str, path: &Path) -> io::Result<()> {
+    if CONFIG.trash_retention == 0 { return transfer::remove_path(&STORAGE.join(path)) }
+    let id = session::random_token();
+    let directory = STORAGE.join(TRASH_DIRECTORY).join(user);
+    create_dir_all(&directory)?;
+    transfer::move_path(&STORAGE.join(path), &directory.join(&id))?;
+    let path = path.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+    update_trash(|entries| entries.push(TrashEntry { id, user: user.to_owned(), path, deleted: unix_time() }));
+    Ok(())
+}
+
+// Permanently delete all entries that are older than the retention, called by the cleaning thread
+pub fn purge() {
+    let limit = unix_time().saturating_sub(CONFIG.trash_retention.saturating_mul(86400));
+    update_trash(|entries| entries.retain(|entry| {
+        if entry.deleted > limit { return true }
+        let _ = transfer::remove_path(&stored_path(entry));
+        false
+    }));
+}
+
+pub fn remove_user_trash(name: &str) {
+    update_trash(|entries| entries.retain(|entry| entry.user != name));
+    let _ = transfer::remove_path(&STORAGE.join(TRASH_DIRECTORY).join(name));
+}
+
+pub fn rename_user_trash(old_name: &str, new_name: &str) {
+    update_trash(|entries| {
+        for entry in entries.iter_mut() {
+            if entry.user == old_name { entry.user = new_name.to_owned(); }
+            if let Ok(relative_path) = Path::new(&entry.path).strip_prefix(old_name) {
+                entry.path = Path::new(new_name).join(relative_path).to_str().expect("Invalid path encoding (expected UTF-8)").to_owned();
+            }
+        }
+    });
+    if STORAGE.join(TRASH_DIRECTORY).join(old_name).is_dir() {
+        rename(STORAGE.join(TRASH_DIRECTORY).join(old_name), STORAGE.join(TRASH_DIRECTORY).join(new_name))
+            .expect("Cannot rename trash directory (permission error)");
+    }
+}
+
+#[get("/trash")]
+pub fn trash(auth: Auth<'_>) -> RawHtml<String> {
+    let Some(username) = auth.user() else { return RawHtml(auth.page(&ACCESS_DENIED)) };
+    let settings = auth.settings();
+    let mut texts = ("Trash", "The trash is empty.", "deleted", "Restore", "Empty trash", "Back", "Entries are deleted permanently after", "days.");
+    if settings.language == "de" {
+        texts = ("Papierkorb", "Der Papierkorb ist leer.", "gelöscht", "Wiederherstellen", "Papierkorb leeren", "Zurück",
+                 "Einträge werden endgültig gelöscht nach", "Tagen.");
+    }
+    let mut entries: Vec<TrashEntry> = get_trash().into_iter().filter(|entry| entry.user == username).collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.deleted));
+    let csrf_token = auth.csrf_token();
+    let mut content = html::paragraph(&format!("{} {} {}", texts.6, CONFIG.trash_retention, texts.7));
+    if entries.is_empty() { content.push_str(&html::paragraph(texts.1)); }
+    for entry in &entries {
+        let icon = if stored_path(entry).is_dir() { "folder" } else { "file" };
+        content.push_str(&html::paragraph(&format!(
+            "<img src=\"/icons/{}_32x32.png\" style=\"vertical-align:middle; margin-right:8px\"/><b>/{}</b> &ensp; {} {}",
+            icon, html::escape(&entry.path), texts.2, html::date(entry.deleted)
+        )));
+        let fields = format!("{}{}{}", html::hidden("id", &entry.id), html::hidden("csrf_token", &csrf_token), html::submit(texts.3));
+        content.push_str(&html::form("/trash/restore", "post", &fields));
+    }
+    if !entries.is_empty() {
+        let fields = format!("{}{}", html::hidden("csrf_token", &csrf_token), html::submit(texts.4));
+        content.push_str(&format!("<br><br>{}", html::form("/trash/empty", "post", &fields)));
+    }
+    content.push_str(&format!("<br>{}", html::link(&format!("/files/{}", username), texts.5)));
+    RawHtml(html::page(&settings, texts.0, &content))
+}
+
+#[post("/trash/restore", data = "<data>")]
+pub fn restore(auth: Auth<'_>, data: Option<Form<TrashItem>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let (Some(username), Some(data)) = (auth.user(), data) else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    let Some(entry) = get_trash().into_iter().find(|entry| entry.id == data.id && entry.user == username) else {
+        return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED))))
+    };
+    check_csrf(&auth, Some(&data.csrf_token), "restore", Path::new(&entry.path))?;
+    let response = restore_response(&auth, &entry);
+    audit::record_response(&auth, "restore", Path::new(&entry.path), &response);
+    Ok(auth.localize(response))
+}
+
+fn restore_response(auth: &Auth<'_>, entry: &TrashEntry) -> Either<Redirect, RawHtml<String>> {
+    let path = Path::new(&entry.path);
+    // The entry can only be restored where the user is still allowed to write
+    if check_login(auth, path, Scope::Upload).is_none() { return Either::Right(RawHtml(ACCESS_DENIED.to_owned())) }
+    if auth.is_read_only() { return Either::Right(RawHtml(READ_ONLY.to_owned())) }
+    let source = stored_path(entry);
+    let size = if source.is_dir() { directory_size(&source) } else { source.metadata().map_or(0, |metadata| metadata.len()) };
+    if exceeds_quota(path, size) { return Either::Right(RawHtml(QUOTA_EXCEEDED.to_owned())) }
+    match restore_entry(entry) {
+        Ok(target) => {
+            let parent = target.parent().expect("Cannot extract parent path");
+            Either::Left(Redirect::to(uri!(crate::list_directory(parent.to_str().expect("Invalid path encoding (expected UTF-8)")))))
+        }
+        Err(error) => Either::Right(RawHtml(transfer::move_error(&error).to_owned()))
+    }
+}
+
+// Move the entry back to its original path and remove it from the trash, returns the restored path.
+// Missing parent directories are created again, an entry with the same name is kept (also one created in the meantime)
+fn restore_entry(entry: &TrashEntry) -> io::Result<PathBuf> {
+    let path = Path::new(&entry.path);
+    let source = stored_path(entry);
+    let parent = path.parent().expect("Cannot extract parent path");
+    create_dir_all(STORAGE.join(parent))?;
+    let name = path.file_name().and_then(|name| name.to_str()).expect("Cannot extract file name");
+    loop {
+        let mut target = path.to_path_buf();
+        if STORAGE.join(&target).try_exists()? {
+            target = parent.join(transfer::free_name(parent, name, source.is_file()));
+        }
+        match transfer::move_path(&source, &STORAGE.join(&target)) {
+            Ok(()) => {
+                update_trash(|entries| entries.retain(|item| item.id != entry.id));
+                return Ok(target)
+            }
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error)
+        }
+    }
+}
+
+#[post("/trash/empty", data = "<data>")]
+pub fn empty_trash(auth: Auth<'_>, data: Option<Form<CsrfToken>>) -> Result<Either<Redirect, RawHtml<String>>, Forbidden<RawHtml<String>>> {
+    let Some(username) = auth.user() else { return Ok(Either::Right(RawHtml(auth.page(&ACCESS_DENIED)))) };
+    let trash_path = Path::new(TRASH_DIRECTORY).join(&username);
+    check_csrf(&auth, data.as_ref().map(|data| data.csrf_token.as_str()), "empty_trash", &trash_path)?;
+    remove_user_trash(&username);
+    audit::record(Some(&username), auth.ip, "empty_trash", &trash_path, "success");
+    Ok(Either::Left(Redirect::to(uri!(trash))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use crate::test_support::setup;
+
+    fn user_entries(user: &str) -> Vec<TrashEntry> {
+        get_trash().into_iter().filter(|entry| entry.user == user).collect()
+    }
+
+    #[test]
+    fn restore_keeps_existing_entries() {
+        setup();
+        let directory = STORAGE.join("trash_user/docs");
+        create_dir_all(&directory).expect("Cannot create test directory");
+        write(directory.join("report.txt"), "deleted").expect("Cannot write test file");
+
+        move_to_trash("trash_user", Path::new("trash_user/docs/report.txt")).expect("Cannot move file to the trash");
+        assert!(!directory.join("report.txt").exists());
+        let entries = user_entries("trash_user");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "trash_user/docs/report.txt");
+        assert!(stored_path(&entries[0]).is_file());
+
+        // A new file with the same name has been created since, so the restored one gets a number
+        write(directory.join("report.txt"), "new").expect("Cannot write test file");
+        let target = restore_entry(&entries[0]).expect("Cannot restore file");
+        assert_eq!(target, Path::new("trash_user/docs/report (1).txt"));
+        assert_eq!(read_to_string(directory.join("report.txt")).expect("Existing file has been removed"), "new");
+        assert_eq!(read_to_string(directory.join("report (1).txt")).expect("Restored file is missing"), "deleted");
+        assert!(user_entries("trash_user").is_empty());
+
+        // A missing stored entry fails without a panic and stays in the trash
+        move_to_trash("trash_user", Path::new("trash_user/docs/report.txt")).expect("Cannot move file to the trash");
+        let entries = user_entries("trash_user");
+        transfer::remove_path(&stored_path(&entries[0])).expect("Cannot remove stored entry");
+        let error = restore_entry(&entries[0]).expect_err("Restore of a missing entry succeeded");
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(user_entries("trash_user").len(), 1);
+        assert!(move_to_trash("trash_user", Path::new("trash_user/docs/missing.txt")).is_err());
+    }
+
+    #[test]
+    fn purge_removes_expired_entries() {
+        setup();
+        create_dir_all(STORAGE.join("purge_user")).expect("Cannot create test directory");
+        write(STORAGE.join("purge_user/old.txt"), "old").expect("Cannot write test file");
+        write(STORAGE.join("purge_user/new.txt"), "new").expect("Cannot write test file");
+        move_to_trash("purge_user", Path::new("purge_user/old.txt")).expect("Cannot move file to the trash");
+        move_to_trash("purge_user", Path::new("purge_user/new.txt")).expect("Cannot move file to the trash");
+        update_trash(|entries| {
+            for entry in entries.iter_mut().filter(|entry| entry.path == "purge_user/old.txt") { entry.deleted = 0; }
+        });
+        let old = user_entries("purge_user").into_iter().find(|entry| entry.path == "purge_user/old.txt").expect("Entry is missing");
+
+        purge();
+        let entries = user_entries("purge_user");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "purge_user/new.txt");
+        assert!(!stored_path(&old).exists() && stored_path(&entries[0]).is_file());
+    }
+}
@@ -0,0 +1,433 @@
+/*
+This file contains resumable uploads according to the tus protocol (version 1.0.0).
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs::{File, create_dir_all, metadata, read_dir, read_to_string, remove_file};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::macros::format_description;
+use rocket::{Data, Request, Response};
+use rocket::data::{Limits, ToByteUnit};
+use rocket::fs::NamedFile;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::tokio::fs::OpenOptions;
+use rocket::tokio::io::AsyncWriteExt;
+use crate::{CONFIG, STORAGE, ACCESS_DENIED, READ_ONLY, NO_DIRECTORY, IS_FILE, UPLOAD_ERROR, QUOTA_EXCEEDED, Auth, DotPathBuf, UploadConflict, audit,
+            session, check_conflict, check_login, check_path, create_upload_directories, exceeds_quota, path_components, place_upload, unix_time,
+            write_file_atomic};
+use crate::tokens::Scope;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+// Directory inside the tmp directory that contains the partial uploads, the tmp cleaning only removes files
+const TUS_DIRECTORY: &str = "tus";
+
+// An upload that has been created but not completed yet, its offset is the size of the staged file
+#[derive(Serialize, Deserialize)]
+struct TusUpload {
+    user: String,
+    // Target directory relative to the storage path and the (relative) file name from the metadata
+    directory: String,
+    name: String,
+    length: u64,
+    // The conflict policy and the modification time of the file (in milliseconds) from the metadata
+    #[serde(default)]
+    conflict: String,
+    #[serde(default)]
+    modified: Option<u64>,
+}
+
+impl TusUpload {
+    fn conflict(&self) -> UploadConflict {
+        UploadConflict::parse(&self.conflict)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        modified_time(self.modified)
+    }
+}
+
+fn modified_time(milliseconds: Option<u64>) -> Option<SystemTime> {
+    milliseconds.map(|milliseconds| UNIX_EPOCH + Duration::from_millis(milliseconds))
+}
+
+// The tus request headers, the CSRF token is sent as an additional header by the browser script
+pub struct TusHeaders {
+    resumable: Option<String>,
+    length: Option<u64>,
+    offset: Option<u64>,
+    metadata: Option<String>,
+    content_type: Option<String>,
+    csrf_token: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = |name: &str| request.headers().get_one(name).map(str::to_owned);
+        Outcome::Success(TusHeaders {
+            resumable: header("Tus-Resumable"),
+            length: header("Upload-Length").and_then(|value| value.parse().ok()),
+            offset: header("Upload-Offset").and_then(|value| value.parse().ok()),
+            metadata: header("Upload-Metadata"),
+            content_type: header("Content-Type"),
+            csrf_token: header("X-CSRF-Token"),
+        })
+    }
+}
+
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        TusResponse { status, headers: Vec::new() }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status).raw_header("Tus-Resumable", TUS_VERSION).raw_header("Cache-Control", "no-store");
+        for (name, value) in self.headers {
+            response.raw_header(name, value);
+        }
+        response.ok()
+    }
+}
+
+lazy_static! {
+    // Uploads that are currently written, a second request for the same upload has to wait
+    static ref ACTIVE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// Marks an upload as active until it is dropped
+struct ActiveUpload(String);
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        ACTIVE.lock().expect("Upload list lock is poisoned").remove(&self.0);
+    }
+}
+
+fn activate(id: &str) -> Option<ActiveUpload> {
+    ACTIVE.lock().expect("Upload list lock is poisoned").insert(id.to_owned()).then(|| ActiveUpload(id.to_owned()))
+}
+
+fn data_path(id: &str) -> PathBuf {
+    STORAGE.join("tmp").join(TUS_DIRECTORY).join(id)
+}
+
+fn info_path(id: &str) -> PathBuf {
+    STORAGE.join("tmp").join(TUS_DIRECTORY).join(format!("{}.toml", id))
+}
+
+fn remove_upload(id: &str) {
+    let _ = remove_file(data_path(id));
+    let _ = remove_file(info_path(id));
+}
+
+fn offset(id: &str) -> u64 {
+    metadata(data_path(id)).map_or(0, |metadata| metadata.len())
+}
+
+// Uploads expire after a period without any written data
+fn expires(id: &str) -> u64 {
+    let modified = metadata(data_path(id)).and_then(|metadata| metadata.modified()).ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs());
+    modified.saturating_add(CONFIG.upload_expiry)
+}
+
+fn http_date(timestamp: u64) -> String {
+    let format = format_description!("[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT");
+    OffsetDateTime::from_unix_timestamp(timestamp.try_into().unwrap_or(i64::MAX)).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        .format(format).expect("Cannot format timestamp")
+}
+
+// Returns the upload if it belongs to the user and has not expired yet
+fn load_upload(auth: &Auth<'_>, id: &str) -> Option<TusUpload> {
+    // The id is used as file name, so only the generated hexadecimal ids are accepted
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) { return None }
+    let upload: TusUpload = toml::from_str(&read_to_string(info_path(id)).ok()?).ok()?;
+    if auth.actor().as_deref() != Some(upload.user.as_str()) || expires(id) < unix_time() { return None }
+    Some(upload)
+}
+
+// A value from the metadata, every pair consists of a key and a base64 encoded value
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    let value = metadata.split(',').find_map(|pair| pair.trim().strip_prefix(key)?.strip_prefix(' '))?;
+    String::from_utf8(data_encoding::BASE64.decode(value.trim().as_bytes()).ok()?).ok()
+}
+
+// The status codes of the error pages of the normal upload
+fn page_status(page: &str) -> Status {
+    match audit::page_name(page) {
+        "access_denied" | "read_only" => Status::Forbidden,
+        "no_directory" => Status::NotFound,
+        "file_exists" | "directory_exists" => Status::Conflict,
+        "quota_exceeded" => Status::PayloadTooLarge,
+        _ => Status::BadRequest
+    }
+}
+
+// The same checks as for the normal upload, returns the path of the new file
+fn check_upload(auth: &Auth<'_>, directory: &Path, name: &str, length: u64, conflict: UploadConflict, modified: Option<SystemTime>) -> Result<PathBuf, &'static str> {
+    if check_login(auth, directory, Scope::Upload).is_none() { return Err(ACCESS_DENIED.as_str()) }
+    if auth.is_read_only() { return Err(READ_ONLY.as_str()) }
+    if !check_path(directory).1 { return Err(NO_DIRECTORY.as_str()) }
+    if exceeds_quota(directory, length) { return Err(QUOTA_EXCEEDED.as_str()) }
+    let mut components = path_components(name);
+    let Some(file_name) = components.pop() else { return Err(UPLOAD_ERROR.as_str()) };
+    // Files with the name of a subdirectory are skipped, an existing file name is handled by the conflict policy like in the normal upload
+    let mut target = directory.to_path_buf();
+    for component in components {
+        target.push(component);
+        if STORAGE.join(&target).is_file() { return Err(IS_FILE.as_str()) }
+    }
+    target.push(file_name);
+    check_conflict(&target, conflict, modified)?;
+    Ok(target)
+}
+
+// Move the completed upload to its target, the staged upload is removed in any case, returns the path of the stored file
+async fn finish_upload(auth: &Auth<'_>, id: &str, upload: &TusUpload) -> Result<PathBuf, &'static str> {
+    let (conflict, modified) = (upload.conflict(), upload.modified());
+    let result = async {
+        check_upload(auth, Path::new(&upload.directory), &upload.name, upload.length, conflict, modified)?;
+        let mut components = path_components(&upload.name);
+        let name = components.pop().ok_or(UPLOAD_ERROR.as_str())?;
+        let directory = create_upload_directories(Path::new(&upload.directory), &components)?;
+        place_upload(&data_path(id), &directory, &name, conflict, modified).await
+    }.await;
+    remove_upload(id);
+    result
+}
+
+// Remove uploads that have expired, called by the cleaning thread
+pub fn purge() {
+    let Ok(entries) = read_dir(STORAGE.join("tmp").join(TUS_DIRECTORY)) else { return };
+    for item in entries.flatten() {
+        let path = item.path();
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            let id = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_owned();
+            if expires(&id) >= unix_time() { continue }
+            // Uploads that are written right now are kept
+            if let Some(_active) = activate(&id) { remove_upload(&id); }
+        }
+    }
+}
+
+fn version_mismatch() -> TusResponse {
+    TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION)
+}
+
+#[get("/tus.js")]
+pub async fn script() -> Option<NamedFile> { NamedFile::open(Path::new("static/tus.js")).await.ok() }
+
+#[options("/tus/<_..>")]
+pub fn options(limits: &Limits) -> TusResponse {
+    let max_size = limits.get("file").unwrap_or(1.gibibytes()).as_u64();
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION).header("Tus-Extension", TUS_EXTENSIONS).header("Tus-Max-Size", max_size)
+}
+
+#[post("/tus/<path..>")]
+pub async fn create_upload(auth: Auth<'_>, path: DotPathBuf, headers: TusHeaders, limits: &Limits) -> TusResponse {
+    let directory = path.0;
+    if headers.resumable.as_deref() != Some(TUS_VERSION) { return version_mismatch() }
+    if !auth.verify_csrf(headers.csrf_token.as_deref().unwrap_or_default()) {
+        audit::record(auth.actor().as_deref(), auth.ip, "upload", &directory, "csrf_rejected");
+        return TusResponse::new(Status::Forbidden)
+    }
+    let metadata = headers.metadata.unwrap_or_default();
+    let (Some(length), Some(name)) = (headers.length, metadata_value(&metadata, "filename")) else {
+        return TusResponse::new(Status::BadRequest)
+    };
+    let conflict = metadata_value(&metadata, "conflict").unwrap_or_default();
+    let modified = metadata_value(&metadata, "modified").and_then(|modified| modified.parse().ok());
+    if length > limits.get("file").unwrap_or(1.gibibytes()).as_u64() { return TusResponse::new(Status::PayloadTooLarge) }
+    let target = match check_upload(&auth, &directory, &name, length, UploadConflict::parse(&conflict), modified_time(modified)) {
+        Ok(target) => target,
+        Err(page) => {
+            audit::record(auth.actor().as_deref(), auth.ip, "upload", &directory.join(&name), audit::page_name(page));
+            return TusResponse::new(page_status(page))
+        }
+    };
+    let Some(user) = auth.actor() else { return TusResponse::new(Status::Forbidden) };
+    let id = session::random_token();
+    let upload = TusUpload { user, directory: directory.to_str().expect("Invalid path encoding (expected UTF-8)").to_owned(), name, length, conflict, modified };
+    create_dir_all(STORAGE.join("tmp").join(TUS_DIRECTORY)).expect("Cannot create upload directory (permission error)");
+    File::create(data_path(&id)).expect("Cannot create temporary file (permission error)");
+    write_file_atomic(info_path(&id).to_str().expect("Invalid path encoding (expected UTF-8)"),
+                      &toml::to_string(&upload).expect("Cannot serialize upload"));
+    // An empty file is complete right away
+    if length == 0 {
+        let result = finish_upload(&auth, &id, &upload).await;
+        audit::record(auth.actor().as_deref(), auth.ip, "upload", result.as_ref().unwrap_or(&target), result.as_ref().map_or_else(|page| audit::page_name(page), |_| "success"));
+        return match result {
+            Ok(_) => TusResponse::new(Status::Created).header("Location", uri!(upload_offset(&id))),
+            Err(page) => TusResponse::new(page_status(page))
+        }
+    }
+    TusResponse::new(Status::Created)
+        .header("Location", uri!(upload_offset(&id))).header("Upload-Expires", http_date(expires(&id)))
+}
+
+#[head("/uploads/<id>")]
+pub fn upload_offset(auth: Auth<'_>, id: &str, headers: TusHeaders) -> TusResponse {
+    if headers.resumable.as_deref() != Some(TUS_VERSION) { return version_mismatch() }
+    let Some(upload) = load_upload(&auth, id) else { return TusResponse::new(Status::NotFound) };
+    TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset(id)).header("Upload-Length", upload.length).header("Upload-Expires", http_date(expires(id)))
+}
+
+#[patch("/uploads/<id>", data = "<data>")]
+pub async fn append_upload(auth: Auth<'_>, id: &str, headers: TusHeaders, data: Data<'_>) -> TusResponse {
+    if headers.resumable.as_deref() != Some(TUS_VERSION) { return version_mismatch() }
+    let Some(upload) = load_upload(&auth, id) else { return TusResponse::new(Status::NotFound) };
+    let target = Path::new(&upload.directory).join(path_components(&upload.name).iter().collect::<PathBuf>());
+    if !auth.verify_csrf(headers.csrf_token.as_deref().unwrap_or_default()) {
+        audit::record(auth.actor().as_deref(), auth.ip, "upload", &target, "csrf_rejected");
+        return TusResponse::new(Status::Forbidden)
+    }
+    if headers.content_type.as_deref() != Some("application/offset+octet-stream") { return TusResponse::new(Status::UnsupportedMediaType) }
+    let Some(_active) = activate(id) else { return TusResponse::new(Status::Conflict) };
+    // The client has to continue at the stored offset
+    let current = offset(id);
+    if headers.offset != Some(current) { return TusResponse::new(Status::Conflict) }
+    let Ok(mut file) = OpenOptions::new().append(true).open(data_path(id)).await else {
+        return TusResponse::new(Status::InternalServerError).header("Upload-Offset", current)
+    };
+    // Everything received until the connection is lost or the disk is full is kept, so the client can resume from there
+    let result = data.open(upload.length.saturating_sub(current).bytes()).stream_to(&mut file).await;
+    let flushed = file.flush().await;
+    let current = offset(id);
+    if result.is_err() || flushed.is_err() { return TusResponse::new(Status::InternalServerError).header("Upload-Offset", current) }
+    if current < upload.length {
+        return TusResponse::new(Status::NoContent).header("Upload-Offset", current).header("Upload-Expires", http_date(expires(id)))
+    }
+    // A completed upload has been moved to its target, so it does not expire anymore
+    let result = finish_upload(&auth, id, &upload).await;
+    audit::record(auth.actor().as_deref(), auth.ip, "upload", result.as_ref().unwrap_or(&target), result.as_ref().map_or_else(|page| audit::page_name(page), |_| "success"));
+    if let Err(page) = result { return TusResponse::new(page_status(page)) }
+    TusResponse::new(Status::NoContent).header("Upload-Offset", current)
+}
+
+#[delete("/uploads/<id>")]
+pub fn delete_upload(auth: Auth<'_>, id: &str, headers: TusHeaders) -> TusResponse {
+    if headers.resumable.as_deref() != Some(TUS_VERSION) { return version_mismatch() }
+    if load_upload(&auth, id).is_none() { return TusResponse::new(Status::NotFound) }
+    if !auth.verify_csrf(headers.csrf_token.as_deref().unwrap_or_default()) { return TusResponse::new(Status::Forbidden) }
+    let Some(_active) = activate(id) else { return TusResponse::new(Status::Conflict) };
+    remove_upload(id);
+    TusResponse::new(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::{Client, LocalRequest, LocalResponse};
+    use crate::users::Role;
+    use crate::test_support::{CSRF_TOKEN, client, login, test_user};
+
+    fn tus_request<'c>(request: LocalRequest<'c>) -> LocalRequest<'c> {
+        login(request, "tus_user").header(Header::new("Tus-Resumable", TUS_VERSION)).header(Header::new("X-CSRF-Token", CSRF_TOKEN))
+    }
+
+    // Create an upload of the given length and return its id
+    fn create(client: &Client, name: &str, length: u64) -> String {
+        let metadata = format!("filename {}", data_encoding::BASE64.encode(name.as_bytes()));
+        let response = tus_request(client.post("/tus/tus_user"))
+            .header(Header::new("Upload-Length", length.to_string())).header(Header::new("Upload-Metadata", metadata)).dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert!(response.headers().get_one("Upload-Expires").is_some());
+        let location = response.headers().get_one("Location").expect("Missing upload location");
+        location.strip_prefix("/uploads/").expect("Unexpected upload location").to_owned()
+    }
+
+    fn append<'c>(client: &'c Client, id: &str, offset: u64, data: &str) -> LocalResponse<'c> {
+        tus_request(client.patch(format!("/uploads/{}", id)))
+            .header(Header::new("Upload-Offset", offset.to_string())).header(Header::new("Content-Type", "application/offset+octet-stream"))
+            .body(data).dispatch()
+    }
+
+    fn tus_client() -> Client {
+        test_user("tus_user", Role::User);
+        client(routes![create_upload, upload_offset, append_upload])
+    }
+
+    #[test]
+    fn upload_is_completed_in_parts() {
+        let client = tus_client();
+        let id = create(&client, "parts.txt", 10);
+
+        // A part at a wrong offset is refused and nothing is written
+        assert_eq!(append(&client, &id, 3, "12345").status(), Status::Conflict);
+        assert_eq!(offset(&id), 0);
+
+        let response = append(&client, &id, 0, "12345");
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("5"));
+        assert!(response.headers().get_one("Upload-Expires").is_some());
+        let response = tus_request(client.head(format!("/uploads/{}", id))).dispatch();
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("5"));
+
+        // The last part moves the file to its target, the finished upload has no expiry anymore
+        let response = append(&client, &id, 5, "67890");
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("10"));
+        assert_eq!(response.headers().get_one("Upload-Expires"), None);
+        assert_eq!(read_to_string(STORAGE.join("tus_user/parts.txt")).expect("Upload is missing"), "1234567890");
+        assert!(!data_path(&id).exists() && !info_path(&id).exists());
+        assert_eq!(tus_request(client.head(format!("/uploads/{}", id))).dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn expired_uploads_are_purged() {
+        let client = tus_client();
+        let expired = create(&client, "expired.txt", 10);
+        let current = create(&client, "current.txt", 10);
+        let old = SystemTime::now() - Duration::from_secs(CONFIG.upload_expiry + 60);
+        File::options().write(true).open(data_path(&expired)).and_then(|file| file.set_modified(old)).expect("Cannot set modification time");
+
+        // An expired upload cannot be continued anymore and is removed by the cleaning thread
+        assert_eq!(tus_request(client.head(format!("/uploads/{}", expired))).dispatch().status(), Status::NotFound);
+        assert_eq!(append(&client, &expired, 0, "12345").status(), Status::NotFound);
+        purge();
+        assert!(!data_path(&expired).exists() && !info_path(&expired).exists());
+        assert!(data_path(&current).exists() && info_path(&current).exists());
+    }
+}
@@ -0,0 +1,438 @@
+/*
+This file contains the access to the users file and the user administration subcommands of the server binary.
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::process;
+use std::path::Path;
+use std::io::{stdin, Write};
+use std::fs::{File, OpenOptions, create_dir, read_to_string, remove_dir_all, rename};
+use sha2::{Sha384, Digest};
+use subtle::ConstantTimeEq;
+use serde::{Deserialize, Serialize};
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng};
+use crate::{STORAGE, session, sso, tokens, shares, internal, trash, sanitize_string, unix_time, write_file_atomic};
+
+// Directory names inside the storage path that cannot be used as user names
+const RESERVED_NAMES: [&str; 3] = ["tmp", "shared", "trash"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Admin,
+    User,
+    ReadOnly,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::User => "user",
+            Role::ReadOnly => "read-only",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        [Role::Admin, Role::User, Role::ReadOnly].into_iter().find(|role| role.name() == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub hash: String,
+    pub role: Role,
+    // Maximum size of the home directory in MiB (0 = unlimited)
+    pub quota: u64,
+    // Interface language and theme ("" = server default)
+    pub language: String,
+    pub theme: String,
+    pub enabled: bool,
+    pub created: u64,
+    pub totp_required: bool,
+    pub totp_secret: String,
+    pub recovery_codes: Vec<String>,
+}
+
+impl User {
+    fn new(name: &str, hash: String) -> Self {
+        User {
+            name: name.to_owned(),
+            hash,
+            role: Role::User,
+            quota: 0,
+            language: String::new(),
+            theme: String::new(),
+            enabled: true,
+            created: unix_time(),
+            totp_required: false,
+            totp_secret: String::new(),
+            recovery_codes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UsersFile {
+    #[serde(default)]
+    user: Vec<User>,
+}
+
+lazy_static! {
+    // Hash with a random password that is verified instead of a missing or legacy hash
+    static ref DUMMY_HASH: String = hash_password(&session::random_token());
+}
+
+pub fn get_users() -> Vec<User> {
+    let content = read_to_string("users.toml").expect("Cannot read users file");
+    toml::from_str::<UsersFile>(&content).expect("Users file formatting is invalid").user
+}
+
+// Serialize all write access to the users file, the exclusive lock on the lock file is held until the returned
+// file is dropped and also applies to the command line tool running next to the server
+fn lock_users() -> File {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open("users.toml.lock").expect("Cannot open users lock file");
+    file.lock().expect("Cannot lock users file");
+    file
+}
+
+fn write_users(users: &[User]) {
+    let content = toml::to_string(&UsersFile { user: users.to_vec() }).expect("Cannot serialize users file");
+    write_file_atomic("users.toml", &content);
+}
+
+// Convert the users.csv file of older versions, its lines have the format "hash;name" with the optional
+// two-factor fields ";required|optional;secret;codes"
+pub fn migrate_users() {
+    if Path::new("users.toml").exists() || !Path::new("users.csv").exists() { return }
+    let _lock = lock_users();
+    let content = read_to_string("users.csv").expect("Cannot read users file");
+    let users: Vec<User> = content.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() < 2 { return None }
+        let mut user = User::new(fields[1], fields[0].to_owned());
+        user.totp_required = fields.get(2) == Some(&"required");
+        user.totp_secret = fields.get(3).unwrap_or(&"").to_string();
+        user.recovery_codes = fields.get(4).unwrap_or(&"").split(',').filter(|code| !code.is_empty()).map(str::to_owned).collect();
+        Some(user)
+    }).collect();
+    write_users(&users);
+    rename("users.csv", "users.csv.bak").expect("Cannot rename old users file");
+    println!("Migrated {} user(s) from users.csv to users.toml (the old file was kept as users.csv.bak)", users.len());
+}
+
+pub fn get_user(name: &str) -> Option<User> {
+    get_users().into_iter().find(|user| user.name == name)
+}
+
+pub fn update_user(name: &str, change: impl FnOnce(&mut User)) {
+    let _lock = lock_users();
+    let mut users = get_users();
+    if let Some(user) = users.iter_mut().find(|user| user.name == name) {
+        change(user);
+        write_users(&users);
+    }
+}
+
+pub fn set_user_hash(name: &str, hash_value: &str) {
+    update_user(name, |user| user.hash = hash_value.to_owned());
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).expect("Cannot hash password").to_string()
+}
+
+pub fn verify_password(stored_hash: &str, name: &str, password: &str) -> bool {
+    match PasswordHash::new(stored_hash) {
+        Ok(parsed_hash) => Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+        // Entries that are not in the PHC string format are legacy unsalted SHA-384 hashes, they are compared in constant time
+        // and take as long as an Argon2id verification, so the response time does not reveal the kind of the stored hash
+        Err(_) => {
+            dummy_verify(password);
+            format!("{:x}", Sha384::digest(format!("{}{}", password, name))).as_bytes().ct_eq(stored_hash.as_bytes()).into()
+        }
+    }
+}
+
+// Spend the time of a password verification without a stored hash, so unknown user names cannot be told apart by timing
+pub fn dummy_verify(password: &str) {
+    let _ = Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(&DUMMY_HASH).expect("Cannot parse dummy hash"));
+}
+
+pub fn is_legacy_hash(stored_hash: &str) -> bool {
+    PasswordHash::new(stored_hash).is_err() && !is_external_hash(stored_hash)
+}
+
+// Users of an external authentication backend have a marker such as "!ldap" instead of a password hash
+pub fn is_external_hash(stored_hash: &str) -> bool {
+    stored_hash.starts_with('!')
+}
+
+// The name is used as directory name, so it has to survive the sanitation unchanged
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && sanitize_string(name) == name && !name.contains(';') && !RESERVED_NAMES.contains(&name)
+}
+
+// Create the entry and the home directory of a user that was authenticated by an external backend
+pub fn provision_user(name: &str, marker: &str) {
+    let _lock = lock_users();
+    let mut users = get_users();
+    if !users.iter().any(|user| user.name == name) {
+        users.push(User::new(name, marker.to_owned()));
+        write_users(&users);
+    }
+    let user_dir = STORAGE.join(name);
+    if !user_dir.is_dir() { create_dir(&user_dir).expect("Cannot create user directory (permission error)"); }
+}
+
+fn check_new_name(users: &[User], name: &str) -> Result<(), &'static str> {
+    if RESERVED_NAMES.contains(&name) { return Err("The user name is reserved for internal use") }
+    if !is_valid_name(name) { return Err("The user name contains invalid characters or is empty") }
+    if users.iter().any(|user| user.name == name) { return Err("The user already exists") }
+    Ok(())
+}
+
+fn insert_user(users: &mut Vec<User>, name: &str, hash_value: String, role: Role) -> Result<(), &'static str> {
+    check_new_name(users, name)?;
+    let mut user = User::new(name, hash_value);
+    user.role = role;
+    users.push(user);
+    write_users(users);
+    let user_dir = STORAGE.join(name);
+    if !user_dir.is_dir() { create_dir(&user_dir).expect("Cannot create user directory (permission error)"); }
+    Ok(())
+}
+
+fn delete_user(users: &mut Vec<User>, name: &str) {
+    users.retain(|user| user.name != name);
+    write_users(users);
+    tokens::remove_user_tokens(name);
+    shares::remove_user_shares(name);
+    internal::remove_user_internal_shares(name);
+    trash::remove_user_trash(name);
+    session::remove_user_sessions(name);
+    let user_dir = STORAGE.join(name);
+    if user_dir.is_dir() { remove_dir_all(&user_dir).expect("Cannot delete user directory (permission error)"); }
+}
+
+pub fn add_user(name: &str, password: &str, role: Role) -> Result<(), &'static str> {
+    let _lock = lock_users();
+    insert_user(&mut get_users(), name, hash_password(password), role)
+}
+
+pub fn remove_user(name: &str) {
+    let _lock = lock_users();
+    delete_user(&mut get_users(), name);
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1)
+}
+
+fn read_new_password() -> String {
+    let password = rpassword::prompt_password("Password: ").expect("Cannot read password");
+    let repeated = rpassword::prompt_password("Repeat password: ").expect("Cannot read password");
+    if password != repeated { exit_with("The passwords do not match"); }
+    if password.is_empty() { exit_with("The password must not be empty"); }
+    password
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} (y/n) ", question);
+    std::io::stdout().flush().expect("Cannot write to stdout");
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).expect("Cannot read from stdin");
+    answer.trim() == "y"
+}
+
+pub fn run_command(args: &[String]) {
+    migrate_users();
+    if !Path::new("users.toml").exists() {
+        let _lock = lock_users();
+        if !Path::new("users.toml").exists() { write_users(&[]); }
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // Passwords and confirmations are read before the users file is locked, so a waiting prompt never blocks the server
+    let exists = |name: &str| get_user(name).is_some();
+    let new_hash = match args.as_slice() {
+        ["add", name] => {
+            if let Err(error) = check_new_name(&get_users(), name) { exit_with(error); }
+            Some(hash_password(&read_new_password()))
+        }
+        ["passwd", name] => {
+            if !exists(name) { exit_with("The user does not exist"); }
+            Some(hash_password(&read_new_password()))
+        }
+        // Legacy hashes include the user name, so they cannot be verified after renaming
+        ["rename", old_name, _] if get_user(old_name).is_some_and(|user| is_legacy_hash(&user.hash)) => {
+            println!("The legacy password hash is invalidated, please set a new password:");
+            Some(hash_password(&read_new_password()))
+        }
+        ["remove", name] => {
+            if !exists(name) { exit_with("The user does not exist"); }
+            if !confirm(format!("Remove user {} and delete all of its files?", name).as_str()) { return }
+            None
+        }
+        ["sso", name] => {
+            if !exists(name) { exit_with("The user does not exist"); }
+            if !confirm(format!("Link {} to the SSO provider? The password is removed, so it can only log in with SSO.", name).as_str()) { return }
+            None
+        }
+        _ => None
+    };
+    let _lock = lock_users();
+    let mut users = get_users();
+    match args.as_slice() {
+        ["list"] => {
+            for user in &users {
+                let hash_type = if is_external_hash(&user.hash) {
+                    format!("external {}", &user.hash[1..])
+                } else if is_legacy_hash(&user.hash) {
+                    "legacy SHA-384".to_owned()
+                } else {
+                    "Argon2id".to_owned()
+                };
+                let two_factor = match (user.totp_secret.is_empty(), user.totp_required) {
+                    (false, _) => "2FA enabled",
+                    (true, true) => "2FA required, not set up",
+                    (true, false) => "2FA disabled",
+                };
+                let quota = if user.quota == 0 { "no quota".to_owned() } else { format!("quota {} MiB", user.quota) };
+                let state = if user.enabled { "" } else { ", disabled" };
+                println!("{} ({}, {}, {}, {}{})", user.name, user.role.name(), hash_type, two_factor, quota, state);
+            }
+        }
+        ["add", name] => {
+            let hash_value = new_hash.expect("The password has not been read");
+            if let Err(error) = insert_user(&mut users, name, hash_value, Role::User) { exit_with(error); }
+            println!("Added user {}", name);
+        }
+        ["remove", name] => {
+            if !users.iter().any(|user| user.name == *name) { exit_with("The user does not exist"); }
+            delete_user(&mut users, name);
+            println!("Removed user {}", name);
+        }
+        ["passwd", name] => {
+            if !users.iter().any(|user| user.name == *name) { exit_with("The user does not exist"); }
+            let hash_value = new_hash.expect("The password has not been read");
+            for user in users.iter_mut().filter(|user| user.name == *name) {
+                user.hash = hash_value.to_owned();
+            }
+            write_users(&users);
+            println!("Changed the password of {}", name);
+        }
+        ["rename", old_name, new_name] => {
+            if !users.iter().any(|user| user.name == *old_name) { exit_with("The user does not exist"); }
+            if let Err(error) = check_new_name(&users, new_name) { exit_with(error); }
+            if STORAGE.join(new_name).exists() { exit_with("A directory with the new user name already exists"); }
+            for user in users.iter_mut().filter(|user| user.name == *old_name) {
+                user.name = new_name.to_string();
+                if let Some(hash_value) = new_hash.as_ref().filter(|_| is_legacy_hash(&user.hash)) {
+                    user.hash = hash_value.to_owned();
+                }
+            }
+            write_users(&users);
+            tokens::rename_user_tokens(old_name, new_name);
+            shares::rename_user_shares(old_name, new_name);
+            internal::rename_user_internal_shares(old_name, new_name);
+            trash::rename_user_trash(old_name, new_name);
+            if STORAGE.join(old_name).is_dir() {
+                rename(STORAGE.join(old_name), STORAGE.join(new_name)).expect("Cannot rename user directory (permission error)");
+            }
+            println!("Renamed user {} to {}", old_name, new_name);
+        }
+        ["totp", name, setting @ ("require" | "optional" | "reset")] => {
+            let Some(user) = users.iter_mut().find(|user| user.name == *name) else { exit_with("The user does not exist") };
+            match *setting {
+                "require" => user.totp_required = true,
+                "optional" => user.totp_required = false,
+                _ => {
+                    user.totp_secret = String::new();
+                    user.recovery_codes = Vec::new();
+                }
+            }
+            write_users(&users);
+            println!("Changed the two-factor authentication of {}", name);
+        }
+        ["sso", name] => {
+            let Some(user) = users.iter_mut().find(|user| user.name == *name) else { exit_with("The user does not exist") };
+            user.hash = sso::OIDC_HASH.to_owned();
+            write_users(&users);
+            println!("Linked user {} to the SSO provider", name);
+        }
+        ["role", name, role] => {
+            let Some(role) = Role::from_name(role) else { exit_with("The role has to be admin, user or read-only") };
+            let Some(user) = users.iter_mut().find(|user| user.name == *name) else { exit_with("The user does not exist") };
+            user.role = role;
+            write_users(&users);
+            println!("Changed the role of {} to {}", name, role.name());
+        }
+        ["quota", name, quota] => {
+            let Ok(quota) = quota.parse() else { exit_with("The quota has to be a number of MiB (0 = unlimited)") };
+            let Some(user) = users.iter_mut().find(|user| user.name == *name) else { exit_with("The user does not exist") };
+            user.quota = quota;
+            write_users(&users);
+            println!("Changed the quota of {}", name);
+        }
+        [command @ ("enable" | "disable"), name] => {
+            let Some(user) = users.iter_mut().find(|user| user.name == *name) else { exit_with("The user does not exist") };
+            user.enabled = *command == "enable";
+            write_users(&users);
+            println!("{}d user {}", if *command == "enable" { "Enable" } else { "Disable" }, name);
+        }
+        _ => {
+            eprintln!("Usage: rnas user <command>\n");
+            eprintln!("Commands:");
+            eprintln!("  list                         List all users");
+            eprintln!("  add <name>                   Add a user and create its directory");
+            eprintln!("  remove <name>                Remove a user and delete its directory");
+            eprintln!("  passwd <name>                Set a new password");
+            eprintln!("  rename <old_name> <new_name> Rename a user and its directory");
+            eprintln!("  totp <name> require          Enforce the two-factor authentication (set up at the next login)");
+            eprintln!("  totp <name> optional         Let the user decide about the two-factor authentication");
+            eprintln!("  totp <name> reset            Remove the two-factor secret and recovery codes");
+            eprintln!("  sso <name>                   Let the user log in with SSO instead of a password");
+            eprintln!("  role <name> <role>           Set the role (admin, user or read-only)");
+            eprintln!("  quota <name> <MiB>           Limit the size of the home directory (0 = unlimited)");
+            eprintln!("  enable <name>                Allow the user to log in again");
+            eprintln!("  disable <name>               Lock the user out without deleting anything");
+            process::exit(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::TryLockError;
+    use crate::test_support::setup;
+
+    // The lock is taken on its own file description, so it excludes other threads and other processes alike
+    #[test]
+    fn users_lock_is_exclusive() {
+        setup();
+        let other = OpenOptions::new().write(true).create(true).truncate(false).open("users.toml.lock").expect("Cannot open users lock file");
+        let lock = lock_users();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(lock);
+        other.lock().expect("Users lock has not been released");
+    }
+}
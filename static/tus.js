@@ -0,0 +1,166 @@
+/*
+This file contains the resumable uploads of the directory view according to the tus protocol (version 1.0.0).
+Copyright (C) 2023  Nico Pieplow (nitrescov)
+Contact: nitrescov@protonmail.com
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+(function () {
+    "use strict";
+
+    const CHUNK_SIZE = 16 * 1024 * 1024;
+    // Waiting times after a lost connection before the upload is resumed (in milliseconds)
+    const RETRY_DELAYS = [1000, 3000, 5000, 10000, 20000, 30000];
+
+    const form = document.getElementById("upload");
+    // Without the required browser features the form is sent as a normal multipart upload
+    if (!form || !window.fetch || !window.localStorage || !window.Blob) return;
+    const status = document.getElementById("upload_status");
+    const endpoint = form.dataset.tus;
+    const csrfToken = form.elements["csrf_token"].value;
+
+    // Only these uploads send the modification times, so overwriting older files is offered here
+    const newer = form.querySelector("option[value=newer]");
+    if (newer) {
+        newer.disabled = false;
+        newer.hidden = false;
+        if ("default" in newer.dataset) newer.defaultSelected = newer.selected = true;
+    }
+
+    function sleep(milliseconds) {
+        return new Promise(resolve => setTimeout(resolve, milliseconds));
+    }
+
+    // The metadata values are base64 encoded UTF-8 strings
+    function encode(value) {
+        const bytes = new TextEncoder().encode(value);
+        let binary = "";
+        bytes.forEach(byte => binary += String.fromCharCode(byte));
+        return btoa(binary);
+    }
+
+    function request(method, url, headers, body) {
+        headers["Tus-Resumable"] = "1.0.0";
+        headers["X-CSRF-Token"] = csrfToken;
+        return fetch(url, { method: method, headers: headers, body: body, credentials: "same-origin", cache: "no-store" });
+    }
+
+    // Repeat a request after network errors with increasing waiting times
+    async function retry(send) {
+        for (let attempt = 0; ; attempt++) {
+            try {
+                return await send();
+            } catch (error) {
+                if (attempt >= RETRY_DELAYS.length) throw error;
+                await sleep(RETRY_DELAYS[attempt]);
+            }
+        }
+    }
+
+    // Ask the server for the stored offset, returns null if the upload does not exist anymore
+    async function storedOffset(url) {
+        const response = await retry(() => request("HEAD", url, {}));
+        return response.ok ? parseInt(response.headers.get("Upload-Offset"), 10) : null;
+    }
+
+    // Upload a file and return the final status code, the upload URL is kept to resume it after a reload of the page
+    async function upload(file, name, conflict, progress) {
+        const key = "tus " + endpoint + " " + name + " " + file.size + " " + file.lastModified;
+        let url = localStorage.getItem(key);
+        let offset = url ? await storedOffset(url) : null;
+        if (offset === null) {
+            const response = await retry(() => request("POST", endpoint, {
+                "Upload-Length": String(file.size),
+                // The modification time allows to overwrite only older files
+                "Upload-Metadata": "filename " + encode(name) + ",conflict " + encode(conflict) + ",modified " + encode(String(file.lastModified))
+            }));
+            if (response.status !== 201) return response.status;
+            url = response.headers.get("Location");
+            localStorage.setItem(key, url);
+            offset = 0;
+        }
+        let failures = 0;
+        while (offset < file.size) {
+            progress(offset);
+            try {
+                const response = await request("PATCH", url, {
+                    "Upload-Offset": String(offset),
+                    "Content-Type": "application/offset+octet-stream"
+                }, file.slice(offset, offset + CHUNK_SIZE));
+                // A different offset or a parallel request is answered with a conflict, in this case the offset is requested again
+                if (response.status === 409) throw new Error("conflict");
+                if (response.status !== 204) {
+                    localStorage.removeItem(key);
+                    return response.status;
+                }
+                offset = parseInt(response.headers.get("Upload-Offset"), 10);
+                failures = 0;
+            } catch (error) {
+                // The connection has been lost, resume at the offset stored by the server
+                if (failures >= RETRY_DELAYS.length) throw error;
+                await sleep(RETRY_DELAYS[failures++]);
+                offset = await storedOffset(url);
+                if (offset === null) {
+                    // The completed upload has been removed because the name already exists
+                    localStorage.removeItem(key);
+                    return 409;
+                }
+            }
+        }
+        localStorage.removeItem(key);
+        return 204;
+    }
+
+    form.addEventListener("submit", async event => {
+        event.preventDefault();
+        // Folder uploads keep the path inside the chosen folder
+        const files = [];
+        form.querySelectorAll("input[type=file]").forEach(input => {
+            Array.from(input.files).forEach(file => files.push([file, file.webkitRelativePath || file.name]));
+        });
+        if (files.length === 0) return;
+        const conflict = form.elements["conflict"].value;
+        form.querySelectorAll("input, select").forEach(input => input.disabled = true);
+        const results = [];
+        for (let index = 0; index < files.length; index++) {
+            const [file, name] = files[index];
+            const progress = offset => {
+                const percent = file.size > 0 ? Math.floor(offset * 100 / file.size) : 100;
+                status.textContent = form.dataset.uploading + " " + (index + 1) + " / " + files.length + ": " + name + " (" + percent + "%)";
+            };
+            let code;
+            try {
+                code = await upload(file, name, conflict, progress);
+            } catch (error) {
+                code = 0;
+            }
+            results.push([name, code]);
+        }
+        // Return to the directory if everything has been uploaded, otherwise show the result of every file
+        if (results.every(([, code]) => code === 201 || code === 204)) {
+            window.location.reload();
+            return;
+        }
+        status.textContent = "";
+        results.forEach(([name, code]) => {
+            const line = document.createElement("div");
+            const text = code === 201 || code === 204 ? form.dataset.uploaded : code === 409 ? form.dataset.skipped : form.dataset.failed;
+            line.textContent = name + ": " + text;
+            status.appendChild(line);
+        });
+        form.querySelectorAll("input, select").forEach(input => input.disabled = false);
+        form.reset();
+    });
+})();